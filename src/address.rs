@@ -0,0 +1,336 @@
+// what an output script pays to, and the address it's written as where it has one
+//
+// legacy addresses are base58check: version byte | payload | first 4 bytes of sha256d.
+// segwit addresses are bech32 (BIP173) for witness version 0 and bech32m (BIP350) for
+// version 1 and up, which only differ in the constant mixed into the checksum.
+
+use crate::network::Network;
+use crate::script::{
+    OP_0, OP_1, OP_16, OP_CHECKMULTISIG, OP_CHECKSIG, OP_DUP, OP_EQUAL, OP_EQUALVERIFY, OP_HASH160, OP_PUSHBYTES_20,
+    OP_PUSHBYTES_33, OP_PUSHBYTES_65, OP_RETURN,
+};
+use crate::serialisers::sha256d;
+
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_ALPHABET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    // bare m of n
+    Multisig { required: u8, keys: u8 },
+    OpReturn,
+    // a witness program with a version nothing uses yet
+    WitnessUnknown(u8),
+    NonStandard,
+}
+
+impl ScriptType {
+    pub fn name(self) -> String {
+        match self {
+            ScriptType::P2pk => "P2PK".to_string(),
+            ScriptType::P2pkh => "P2PKH".to_string(),
+            ScriptType::P2sh => "P2SH".to_string(),
+            ScriptType::P2wpkh => "P2WPKH".to_string(),
+            ScriptType::P2wsh => "P2WSH".to_string(),
+            ScriptType::P2tr => "P2TR".to_string(),
+            ScriptType::Multisig { required, keys } => format!("multisig {} of {}", required, keys),
+            ScriptType::OpReturn => "OP_RETURN".to_string(),
+            ScriptType::WitnessUnknown(version) => format!("witness v{}", version),
+            ScriptType::NonStandard => "non-standard".to_string(),
+        }
+    }
+}
+
+// matches the templates bitcoin core treats as standard
+pub fn classify(script: &[u8]) -> ScriptType {
+    match script {
+        [OP_DUP, OP_HASH160, OP_PUSHBYTES_20, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => {
+            ScriptType::P2pkh
+        }
+        [OP_HASH160, OP_PUSHBYTES_20, hash @ .., OP_EQUAL] if hash.len() == 20 => ScriptType::P2sh,
+        [OP_PUSHBYTES_33, key @ .., OP_CHECKSIG] if key.len() == 33 => ScriptType::P2pk,
+        [OP_PUSHBYTES_65, key @ .., OP_CHECKSIG] if key.len() == 65 => ScriptType::P2pk,
+        [OP_RETURN, ..] => ScriptType::OpReturn,
+        _ => match witness_program(script) {
+            Some((0, program)) if program.len() == 20 => ScriptType::P2wpkh,
+            Some((0, program)) if program.len() == 32 => ScriptType::P2wsh,
+            // any other length is invalid for version 0
+            Some((0, _)) => ScriptType::NonStandard,
+            Some((1, program)) if program.len() == 32 => ScriptType::P2tr,
+            Some((version, _)) => ScriptType::WitnessUnknown(version),
+            None => classify_multisig(script).unwrap_or(ScriptType::NonStandard),
+        },
+    }
+}
+
+// None for scripts that don't have an address, like OP_RETURN, bare multisig and pay to pubkey
+pub fn address(script: &[u8], network: Network) -> Option<String> {
+    let (pubkey_prefix, script_prefix) = network.base58_prefixes();
+
+    match classify(script) {
+        ScriptType::P2pkh => Some(base58check(pubkey_prefix, &script[3..23])),
+        ScriptType::P2sh => Some(base58check(script_prefix, &script[2..22])),
+        ScriptType::P2wpkh | ScriptType::P2wsh | ScriptType::P2tr | ScriptType::WitnessUnknown(_) => {
+            let (version, program) = witness_program(script)?;
+            Some(segwit_address(network.bech32_hrp(), version, program))
+        }
+        _ => None,
+    }
+}
+
+// whether the script pays the address the user typed. bech32 can be written all in lower case
+// or all in upper case but not a mix of the two, base58 only one way
+pub fn pays_to(script: &[u8], query: &str, network: Network) -> bool {
+    match address(script, network) {
+        Some(address) if address.starts_with(&format!("{}1", network.bech32_hrp())) => {
+            query == address || query == address.to_ascii_uppercase()
+        }
+        Some(address) => address == query,
+        None => false,
+    }
+}
+
+// BIP141: a version opcode followed by a single push of 2 to 40 bytes
+fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    let (&version_op, rest) = script.split_first()?;
+    let (&length, program) = rest.split_first()?;
+
+    let version = match version_op {
+        OP_0 => 0,
+        OP_1..=OP_16 => version_op - OP_1 + 1,
+        _ => return None,
+    };
+    if !(2..=40).contains(&length) || program.len() != length as usize {
+        return None;
+    }
+    Some((version, program))
+}
+
+// OP_m <pubkey>... OP_n OP_CHECKMULTISIG
+fn classify_multisig(script: &[u8]) -> Option<ScriptType> {
+    let (&required_op, rest) = script.split_first()?;
+    let (&checkmultisig, rest) = rest.split_last()?;
+    let (&keys_op, mut keys) = rest.split_last()?;
+
+    if checkmultisig != OP_CHECKMULTISIG
+        || !(OP_1..=OP_16).contains(&required_op)
+        || !(OP_1..=OP_16).contains(&keys_op)
+    {
+        return None;
+    }
+
+    let mut count = 0;
+    while let Some((&length, rest)) = keys.split_first() {
+        if !(length == OP_PUSHBYTES_33 || length == OP_PUSHBYTES_65) || rest.len() < length as usize {
+            return None;
+        }
+        keys = &rest[length as usize..];
+        count += 1;
+    }
+
+    let required = required_op - OP_1 + 1;
+    let total = keys_op - OP_1 + 1;
+    if count != total || required > total {
+        return None;
+    }
+    Some(ScriptType::Multisig { required, keys: total })
+}
+
+pub fn base58check(version: u8, payload: &[u8]) -> String {
+    let mut bytes = vec![version];
+    bytes.extend(payload);
+    let checksum = sha256d(&bytes);
+    bytes.extend(&checksum[..4]);
+    base58(&bytes)
+}
+
+fn base58(bytes: &[u8]) -> String {
+    // base 58 digits, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in bytes {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    // each leading zero byte is written as a leading '1'
+    let zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+    let mut encoded = "1".repeat(zeros);
+    encoded.extend(digits.iter().rev().map(|&digit| BASE58_ALPHABET[digit as usize] as char));
+    encoded
+}
+
+pub fn segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5));
+
+    let constant = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    let checksum = bech32_checksum(hrp, &data, constant);
+
+    let mut encoded = format!("{}1", hrp);
+    encoded.extend(data.iter().chain(&checksum).map(|&value| BECH32_ALPHABET[value as usize] as char));
+    encoded
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATORS: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+    let mut checksum = 1u32;
+    for &value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_checksum(hrp: &str, data: &[u8], constant: u32) -> [u8; 6] {
+    // the hrp goes in as its high bits, a zero, then its low bits
+    let mut values: Vec<u8> = hrp.bytes().map(|byte| byte >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|byte| byte & 31));
+    values.extend(data);
+    values.extend([0; 6]);
+
+    let polymod = bech32_polymod(&values) ^ constant;
+    let mut checksum = [0; 6];
+    for (i, value) in checksum.iter_mut().enumerate() {
+        *value = ((polymod >> (5 * (5 - i))) & 31) as u8;
+    }
+    checksum
+}
+
+// regroups bits, padding the last group with zeros
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Vec<u8> {
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+    let max = (1 << to) - 1;
+    let mut converted = Vec::new();
+
+    for &value in data {
+        accumulator = (accumulator << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((accumulator >> bits) & max) as u8);
+        }
+    }
+    if bits > 0 {
+        converted.push(((accumulator << (to - bits)) & max) as u8);
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    const P2PKH: &str = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac";
+    const P2SH: &str = "a914748284390f9e263a4b766a75d0633c50426eb87587";
+    const P2WPKH: &str = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    const P2WSH: &str = "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262";
+    const P2TR: &str = "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn classifies_standard_templates() {
+        assert_eq!(classify(&script(P2PKH)), ScriptType::P2pkh);
+        assert_eq!(classify(&script(P2SH)), ScriptType::P2sh);
+        assert_eq!(classify(&script(P2WPKH)), ScriptType::P2wpkh);
+        assert_eq!(classify(&script(P2WSH)), ScriptType::P2wsh);
+        assert_eq!(classify(&script(P2TR)), ScriptType::P2tr);
+        assert_eq!(classify(&script("6a0468656c6c6f")), ScriptType::OpReturn);
+    }
+
+    #[test]
+    fn base58_addresses() {
+        assert_eq!(address(&script(P2PKH), Network::Mainnet).unwrap(), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        assert_eq!(address(&script(P2SH), Network::Mainnet).unwrap(), "3CK4fEwbMP7heJarmU4eqA3sMbVJyEnU3V");
+        assert_eq!(address(&script(P2PKH), Network::Testnet).unwrap(), "mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt");
+        assert_eq!(address(&script(P2SH), Network::Testnet).unwrap(), "2N3sGiyscxqd3r6DQSbgXT738ZwhUpBqkej");
+    }
+
+    // test vectors from BIP173 and BIP350
+    #[test]
+    fn segwit_addresses() {
+        assert_eq!(
+            address(&script(P2WPKH), Network::Mainnet).unwrap(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert_eq!(
+            address(&script(P2WSH), Network::Testnet).unwrap(),
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+        );
+        assert_eq!(
+            address(&script(P2TR), Network::Mainnet).unwrap(),
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"
+        );
+    }
+
+    #[test]
+    fn hrp_follows_the_network() {
+        let hrps: Vec<String> = [Network::Mainnet, Network::Testnet, Network::Signet, Network::Regtest]
+            .into_iter()
+            .map(|network| address(&script(P2WPKH), network).unwrap())
+            .map(|address| address.split('1').next().unwrap().to_string())
+            .collect();
+        assert_eq!(hrps, vec!["bc", "tb", "tb", "bcrt"]);
+    }
+
+    #[test]
+    fn no_address_for_bare_scripts() {
+        assert_eq!(address(&script("6a0468656c6c6f"), Network::Mainnet), None);
+    }
+
+    #[test]
+    fn pays_to_the_addresses_it_encodes() {
+        for (hex, network) in [
+            (P2PKH, Network::Mainnet),
+            (P2SH, Network::Testnet),
+            (P2WPKH, Network::Mainnet),
+            (P2WSH, Network::Testnet),
+            (P2TR, Network::Regtest),
+        ] {
+            let script = script(hex);
+            let address = address(&script, network).unwrap();
+            assert!(pays_to(&script, &address, network), "{}", address);
+        }
+    }
+
+    #[test]
+    fn bech32_case() {
+        let witness = script(P2WPKH);
+        assert!(pays_to(&witness, "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", Network::Mainnet));
+        assert!(!pays_to(&witness, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3T4", Network::Mainnet));
+        // base58 is case sensitive throughout
+        assert!(!pays_to(&script(P2PKH), "1a1zp1ep5qgefi2dmptftl5slmv7divfna", Network::Mainnet));
+    }
+
+    #[test]
+    fn other_networks_addresses_dont_match() {
+        assert!(!pays_to(&script(P2PKH), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Network::Testnet));
+        assert!(!pays_to(&script(P2WPKH), "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Network::Regtest));
+    }
+}
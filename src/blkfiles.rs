@@ -0,0 +1,292 @@
+// bitcoin core's block files, read straight from a data directory
+//
+// blocks/blk00000.dat, blk00001.dat ... hold raw blocks back to back, each one framed as
+// magic (4) | length (4, le) | block
+// core preallocates the files, so the last record is followed by zeros up to the end.
+// since 28.0 the files are xored with the 8 byte key in blocks/xor.dat, byte n of a file
+// with key[n % 8]. older data directories have no key file, which is the same as a key of zeros.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::network::Network;
+use crate::parsers::parse_block;
+use crate::structures::BlockData;
+
+const KEY_FILE: &str = "xor.dat";
+const RECORD_HEADER_SIZE: usize = 8;
+
+// where a block sits in the block files, enough to read it again later
+#[derive(Clone, Copy)]
+pub struct BlockFilePosition {
+    pub file: usize,
+    pub offset: u64,
+    pub length: u32,
+}
+
+// a block as it came out of the file, not yet parsed
+pub struct RawBlock {
+    pub position: BlockFilePosition,
+    pub bytes: Vec<u8>,
+}
+
+pub struct BlockFiles {
+    dir: PathBuf,
+    network: Network,
+    key: [u8; 8],
+    files: Vec<PathBuf>,
+}
+
+impl BlockFiles {
+    // Takes either a core data directory or the blocks directory inside it. Checks the first
+    // file belongs to the network we're set up for, so a mixup is reported up front rather
+    // than turning into a pile of unreadable blocks.
+    pub fn open(path: &Path, network: Network) -> Result<BlockFiles, Box<dyn Error>> {
+        let dir = if path.join("blocks").is_dir() { path.join("blocks") } else { path.to_path_buf() };
+
+        let key = match fs::read(dir.join(KEY_FILE)) {
+            Ok(bytes) => <[u8; 8]>::try_from(bytes.as_slice())
+                .map_err(|_| format!("{} should be 8 bytes, it is {}", dir.join(KEY_FILE).display(), bytes.len()))?,
+            Err(_) => [0; 8],
+        };
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .map_err(|e| format!("Could not read {}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("blk") && name.ends_with(".dat"))
+            })
+            .collect();
+        files.sort();
+
+        if files.is_empty() {
+            return Err(format!("No blk*.dat files in {}", dir.display()).into());
+        }
+
+        let block_files = BlockFiles { dir, network, key, files };
+        block_files.check_magic()?;
+        Ok(block_files)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_obfuscated(&self) -> bool {
+        self.key != [0; 8]
+    }
+
+    fn check_magic(&self) -> Result<(), Box<dyn Error>> {
+        let mut header = [0; 4];
+        let mut file = File::open(&self.files[0])?;
+        if file.read_exact(&mut header).is_err() || header == [0; 4] {
+            // nothing written yet
+            return Ok(());
+        }
+        self.unxor(&mut header, 0);
+
+        if header != self.network.magic() {
+            return Err(format!(
+                "{} starts with magic {}, not {}'s {}. Pick its network with --network",
+                self.files[0].display(),
+                hex::encode(header),
+                self.network.name(),
+                hex::encode(self.network.magic())
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // offset is where bytes started in the file, the key lines up with file positions
+    fn unxor(&self, bytes: &mut [u8], offset: u64) {
+        if !self.is_obfuscated() {
+            return;
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte ^= self.key[((offset + i as u64) % 8) as usize];
+        }
+    }
+
+    // Every block in one file along with where it was found. Stops at the zeros after
+    // the last record, at a record that runs past the end of the file, which is what a
+    // crash in the middle of a write leaves behind, or at anything without the right magic.
+    pub fn read_file(&self, file: usize) -> Result<Vec<RawBlock>, Box<dyn Error>> {
+        let bytes = fs::read(&self.files[file])?;
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+
+        while offset + RECORD_HEADER_SIZE <= bytes.len() {
+            let mut header = [0; RECORD_HEADER_SIZE];
+            header.copy_from_slice(&bytes[offset..offset + RECORD_HEADER_SIZE]);
+            if header == [0; RECORD_HEADER_SIZE] {
+                break;
+            }
+            self.unxor(&mut header, offset as u64);
+
+            // there's no telling where the next record starts after garbage
+            if header[..4] != self.network.magic() {
+                break;
+            }
+            let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+            let start = offset + RECORD_HEADER_SIZE;
+            let end = start + length as usize;
+            if end > bytes.len() {
+                break;
+            }
+
+            let mut block = bytes[start..end].to_vec();
+            self.unxor(&mut block, start as u64);
+            blocks.push(RawBlock {
+                position: BlockFilePosition { file, offset: start as u64, length },
+                bytes: block,
+            });
+            offset = end;
+        }
+
+        Ok(blocks)
+    }
+
+    // reads back a block found by an earlier read_file
+    pub fn read_block(&self, position: &BlockFilePosition) -> Result<BlockData, Box<dyn Error>> {
+        let mut file = File::open(&self.files[position.file])?;
+        file.seek(SeekFrom::Start(position.offset))?;
+
+        let mut bytes = vec![0; position.length as usize];
+        file.read_exact(&mut bytes)?;
+        self.unxor(&mut bytes, position.offset);
+
+        parse_block(&bytes)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // a directory of its own under the system temp dir, removed again when dropped
+    pub(crate) struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("explorer-test-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const KEY: [u8; 8] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+    fn record(magic: [u8; 4], block: &[u8]) -> Vec<u8> {
+        let mut bytes = magic.to_vec();
+        bytes.extend((block.len() as u32).to_le_bytes());
+        bytes.extend(block);
+        bytes
+    }
+
+    // writes blk00000.dat under dir/blocks, xored with key when there is one
+    fn write_blocks(dir: &TempDir, contents: &[u8], key: Option<[u8; 8]>) -> BlockFiles {
+        let blocks = dir.0.join("blocks");
+        fs::create_dir_all(&blocks).unwrap();
+
+        let mut contents = contents.to_vec();
+        if let Some(key) = key {
+            fs::write(blocks.join(KEY_FILE), key).unwrap();
+            for (i, byte) in contents.iter_mut().enumerate() {
+                *byte ^= key[i % 8];
+            }
+        }
+        fs::write(blocks.join("blk00000.dat"), contents).unwrap();
+
+        BlockFiles::open(&dir.0, Network::Regtest).unwrap()
+    }
+
+    fn blocks_in(files: &BlockFiles) -> Vec<Vec<u8>> {
+        files.read_file(0).unwrap().into_iter().map(|raw| raw.bytes).collect()
+    }
+
+    fn two_records() -> Vec<u8> {
+        let magic = Network::Regtest.magic();
+        let mut contents = record(magic, &[1; 90]);
+        contents.extend(record(magic, &[2; 13]));
+        contents
+    }
+
+    #[test]
+    fn reads_records_without_a_key_file() {
+        let dir = TempDir::new("plain");
+        let files = write_blocks(&dir, &two_records(), None);
+
+        assert!(!files.is_obfuscated());
+        let raw = files.read_file(0).unwrap();
+        assert_eq!(raw.len(), 2);
+        assert_eq!(raw[1].bytes, vec![2; 13]);
+        assert_eq!(raw[1].position.offset, 8 + 90 + 8);
+        assert_eq!(raw[1].position.length, 13);
+    }
+
+    #[test]
+    fn unmasks_files_with_a_key() {
+        let dir = TempDir::new("xor");
+        let files = write_blocks(&dir, &two_records(), Some(KEY));
+
+        assert!(files.is_obfuscated());
+        assert_eq!(blocks_in(&files), vec![vec![1; 90], vec![2; 13]]);
+    }
+
+    #[test]
+    fn stops_at_the_zero_padding() {
+        let dir = TempDir::new("padding");
+        let mut contents = two_records();
+        contents.extend([0; 4096]);
+        // the padding is xored like everything else, so it's only zeros once unmasked
+        let files = write_blocks(&dir, &contents, Some(KEY));
+        assert_eq!(blocks_in(&files).len(), 2);
+    }
+
+    #[test]
+    fn stops_at_a_bad_magic() {
+        let dir = TempDir::new("bad-magic");
+        let magic = Network::Regtest.magic();
+        let mut contents = record(magic, &[1; 20]);
+        contents.extend(record(Network::Mainnet.magic(), &[2; 20]));
+        contents.extend(record(magic, &[3; 20]));
+
+        let files = write_blocks(&dir, &contents, None);
+        assert_eq!(blocks_in(&files), vec![vec![1; 20]]);
+    }
+
+    #[test]
+    fn stops_at_a_record_cut_short() {
+        let dir = TempDir::new("cut-short");
+        let mut contents = two_records();
+        contents.truncate(contents.len() - 1);
+
+        let files = write_blocks(&dir, &contents, None);
+        assert_eq!(blocks_in(&files), vec![vec![1; 90]]);
+    }
+
+    #[test]
+    fn refuses_another_networks_files() {
+        let dir = TempDir::new("wrong-network");
+        write_blocks(&dir, &two_records(), Some(KEY));
+        assert!(BlockFiles::open(&dir.0, Network::Mainnet).is_err());
+    }
+}
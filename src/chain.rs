@@ -0,0 +1,413 @@
+// every header we've heard about, indexed by hash, with the most-work chain picked out of them
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::network::Network;
+use crate::structures::{BlockData, BlockHash, ChainPosition, ChainUpdate, PeerId, Reorg};
+use crate::validation::{block_work, check_proof_of_work, permitted_difficulty_transition};
+
+// most headers a peer will send in one message, a full batch means there are more to come
+pub const MAX_HEADERS: usize = 2000;
+
+pub struct ChainEntry {
+    // transactions are never kept here
+    pub header: BlockData,
+    pub position: ChainPosition,
+}
+
+pub enum HeadersOutcome {
+    // empty when the headers were all known already or only extended a stale branch
+    Connected(ChainUpdate),
+    // the first header builds on something we've never seen, the peer needs to fill the gap
+    UnknownParent,
+}
+
+pub struct ChainIndex {
+    network: Network,
+    entries: HashMap<BlockHash, ChainEntry>,
+    // the chain with the most work, active[height] is the block at that height
+    active: Vec<BlockHash>,
+    // tips of branches that have less work than the active chain
+    stale_tips: HashSet<BlockHash>,
+    // the peer downloading headers for us, only one at a time so they aren't all fetched twice
+    sync_peer: Option<PeerId>,
+    // headers indexed since the last take_new_headers, waiting to be written to the store
+    new_headers: Vec<BlockData>,
+}
+
+impl ChainIndex {
+    pub fn new(network: Network) -> ChainIndex {
+        let genesis = network.genesis_header();
+        let hash = genesis.block_hash;
+        let position = ChainPosition {
+            height: 0,
+            chainwork: block_work(genesis.bits),
+        };
+
+        let mut entries = HashMap::new();
+        entries.insert(hash, ChainEntry { header: genesis, position });
+
+        ChainIndex {
+            network,
+            entries,
+            active: vec![hash],
+            stale_tips: HashSet::new(),
+            sync_peer: None,
+            new_headers: Vec::new(),
+        }
+    }
+
+    // Indexes headers loaded from the store, in the order they were first added. They're
+    // checked like any others, the number that didn't fit is returned.
+    pub fn restore(&mut self, headers: &[BlockData]) -> usize {
+        let mut rejected = 0;
+        for header in headers {
+            match self.add_headers(std::slice::from_ref(header)) {
+                Ok(HeadersOutcome::Connected(_)) => {}
+                _ => rejected += 1,
+            }
+        }
+        // these came from the store, there's no need to write them out again
+        self.new_headers.clear();
+        rejected
+    }
+
+    // the last count blocks of the active chain, oldest first
+    pub fn recent(&self, count: usize) -> Vec<(ChainPosition, BlockData)> {
+        let start = self.active.len().saturating_sub(count);
+        self.active[start..]
+            .iter()
+            .map(|hash| {
+                let entry = &self.entries[hash];
+                (entry.position, entry.header.clone())
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn stale_branches(&self) -> usize {
+        self.stale_tips.len()
+    }
+
+    pub fn take_new_headers(&mut self) -> Vec<BlockData> {
+        std::mem::take(&mut self.new_headers)
+    }
+
+    pub fn tip_height(&self) -> u32 {
+        (self.active.len() - 1) as u32
+    }
+
+    fn tip(&self) -> &ChainEntry {
+        &self.entries[&self.active[self.active.len() - 1]]
+    }
+
+    fn is_active(&self, hash: &BlockHash, height: u32) -> bool {
+        self.active.get(height as usize) == Some(hash)
+    }
+
+    // Checks each header links to the one before it and meets its own proof of work target,
+    // then indexes them. Whichever branch ends up with the most work becomes the active
+    // chain, on a tie the one we saw first stays. Where the network retargets normally the
+    // bits are held to the retarget schedule too, otherwise a branch off an early block could
+    // be built at minimum difficulty for next to nothing and fill up the index.
+    pub fn add_headers(&mut self, headers: &[BlockData]) -> Result<HeadersOutcome, Box<dyn Error>> {
+        if headers.len() > MAX_HEADERS {
+            return Err(format!("Peer sent {} headers, the limit is {}", headers.len(), MAX_HEADERS).into());
+        }
+
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(HeadersOutcome::Connected(ChainUpdate::default())),
+        };
+        if !self.entries.contains_key(&BlockHash(first.prev_block_hash)) {
+            return Ok(HeadersOutcome::UnknownParent);
+        }
+
+        for pair in headers.windows(2) {
+            if pair[1].prev_block_hash != pair[0].block_hash.0 {
+                return Err(format!("Header {} does not follow {}", pair[1].block_hash, pair[0].block_hash).into());
+            }
+        }
+        if let Some(header) = headers.iter().find(|header| !check_proof_of_work(header, self.network).valid) {
+            return Err(format!("Header {} does not meet its proof of work target", header.block_hash).into());
+        }
+        if self.network.retargets_strictly() {
+            let parent = &self.entries[&BlockHash(first.prev_block_hash)];
+            let (mut previous_bits, mut height) = (parent.header.bits, parent.position.height);
+            for header in headers {
+                height += 1;
+                if !permitted_difficulty_transition(self.network, height, previous_bits, header.bits) {
+                    return Err(format!(
+                        "Header {} at height {} changes bits from {:#010x} to {:#010x}",
+                        header.block_hash, height, previous_bits, header.bits
+                    )
+                    .into());
+                }
+                previous_bits = header.bits;
+            }
+        }
+
+        let mut update = ChainUpdate::default();
+        for header in headers {
+            if !self.entries.contains_key(&header.block_hash) {
+                self.add_header(header, &mut update);
+            }
+        }
+        update.stale_branches = self.stale_tips.len();
+
+        Ok(HeadersOutcome::Connected(update))
+    }
+
+    // the parent has to be indexed already
+    fn add_header(&mut self, header: &BlockData, update: &mut ChainUpdate) {
+        let parent_hash = BlockHash(header.prev_block_hash);
+        let parent = self.entries[&parent_hash].position;
+        let position = ChainPosition {
+            height: parent.height + 1,
+            chainwork: parent.chainwork.saturating_add(block_work(header.bits)),
+        };
+
+        let mut header = header.clone();
+        header.transactions.clear();
+        let hash = header.block_hash;
+        self.entries.insert(hash, ChainEntry { header: header.clone(), position });
+        self.new_headers.push(header.clone());
+        self.stale_tips.remove(&parent_hash);
+
+        if parent_hash == self.active[self.active.len() - 1] {
+            self.active.push(hash);
+            update.connected.push((position, header));
+        } else if position.chainwork > self.tip().position.chainwork {
+            self.reorganise(hash, update);
+        } else {
+            self.stale_tips.insert(hash);
+        }
+    }
+
+    // makes new_tip's branch the active chain
+    fn reorganise(&mut self, new_tip: BlockHash, update: &mut ChainUpdate) {
+        // walk back from the new tip until we meet the active chain
+        let mut branch = Vec::new();
+        let mut hash = new_tip;
+        loop {
+            let entry = &self.entries[&hash];
+            if self.is_active(&hash, entry.position.height) {
+                break;
+            }
+            branch.push(hash);
+            hash = BlockHash(entry.header.prev_block_hash);
+        }
+        let fork_height = self.entries[&hash].position.height;
+
+        let old_tip = self.active[self.active.len() - 1];
+        let disconnected = self.active.split_off(fork_height as usize + 1);
+        self.stale_tips.insert(old_tip);
+
+        // headers connected earlier in this batch may have just been disconnected again
+        update
+            .connected
+            .retain(|(_, header)| !disconnected.contains(&header.block_hash));
+
+        for hash in branch.into_iter().rev() {
+            let entry = &self.entries[&hash];
+            self.active.push(hash);
+            update.connected.push((entry.position, entry.header.clone()));
+        }
+
+        update.reorgs.push(Reorg {
+            fork_height,
+            old_tip,
+            new_tip,
+            new_height: self.tip_height(),
+            disconnected,
+        });
+    }
+
+    // Hashes for a getheaders request, newest first. The last ten blocks one by one, then
+    // doubling the gap each time, always ending with genesis so a peer on the same network
+    // can find some common ground.
+    pub fn locator(&self) -> Vec<[u8; 32]> {
+        let mut hashes = Vec::new();
+        let mut height = self.tip_height() as i64;
+        let mut step = 1;
+
+        while height > 0 {
+            hashes.push(self.active[height as usize].0);
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+
+        hashes.push(self.active[0].0);
+        hashes
+    }
+
+    // Answers a peer's getheaders from the active chain: everything after the first locator
+    // hash on it, up to and including hash_stop. Like core, a locator with nothing on the
+    // active chain gets everything after genesis.
+    pub fn headers_after(&self, locator: &[[u8; 32]], hash_stop: [u8; 32]) -> Vec<BlockData> {
+        let start = locator
+            .iter()
+            .map(|hash| BlockHash(*hash))
+            .find_map(|hash| {
+                let height = self.entries.get(&hash)?.position.height;
+                self.is_active(&hash, height).then_some(height)
+            })
+            .map_or(1, |height| height as usize + 1);
+
+        let mut headers = Vec::new();
+        for hash in self.active.iter().skip(start).take(MAX_HEADERS) {
+            headers.push(self.entries[hash].header.clone());
+            if hash.0 == hash_stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    // true if the peer is now the one syncing, including when it already was
+    pub fn claim_sync(&mut self, peer: PeerId) -> bool {
+        match self.sync_peer {
+            Some(current) => current == peer,
+            None => {
+                self.sync_peer = Some(peer);
+                true
+            }
+        }
+    }
+
+    pub fn release_sync(&mut self, peer: PeerId) {
+        if self.sync_peer == Some(peer) {
+            self.sync_peer = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a header on top of parent with the first nonce that meets bits, only quick at regtest's
+    // difficulty. branch keeps headers on different branches from coming out the same
+    fn mine(parent: &BlockData, bits: u32, network: Network, branch: u8) -> BlockData {
+        let mut header = BlockData {
+            version: 4,
+            prev_block_hash: parent.block_hash.0,
+            merkle_root: [branch; 32],
+            timestamp: parent.timestamp + 600,
+            bits,
+            nonce: 0,
+            block_hash: BlockHash::default(),
+            transactions: Vec::new(),
+        };
+        loop {
+            header.calculate_hash();
+            if check_proof_of_work(&header, network).valid {
+                return header;
+            }
+            header.nonce += 1;
+        }
+    }
+
+    fn mine_chain(parent: &BlockData, count: usize, branch: u8) -> Vec<BlockData> {
+        let mut headers: Vec<BlockData> = Vec::new();
+        for _ in 0..count {
+            let header = mine(headers.last().unwrap_or(parent), parent.bits, Network::Regtest, branch);
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn connected(outcome: HeadersOutcome) -> ChainUpdate {
+        match outcome {
+            HeadersOutcome::Connected(update) => update,
+            HeadersOutcome::UnknownParent => panic!("expected the headers to connect"),
+        }
+    }
+
+    #[test]
+    fn headers_extend_the_tip() {
+        let mut chain = ChainIndex::new(Network::Regtest);
+        let headers = mine_chain(&Network::Regtest.genesis_header(), 3, 0);
+
+        let update = connected(chain.add_headers(&headers).unwrap());
+        assert_eq!(update.connected.len(), 3);
+        assert_eq!(chain.tip_height(), 3);
+        assert_eq!(chain.take_new_headers().len(), 3);
+    }
+
+    #[test]
+    fn unknown_parent_is_reported() {
+        let mut chain = ChainIndex::new(Network::Regtest);
+        let headers = mine_chain(&Network::Regtest.genesis_header(), 2, 0);
+
+        assert!(matches!(chain.add_headers(&headers[1..]).unwrap(), HeadersOutcome::UnknownParent));
+        assert!(!chain.contains(&headers[1].block_hash));
+    }
+
+    #[test]
+    fn target_above_the_limit_is_rejected() {
+        let mut chain = ChainIndex::new(Network::Regtest);
+        let genesis = Network::Regtest.genesis_header();
+        let mut header = mine(&genesis, genesis.bits, Network::Regtest, 0);
+        // 2^256 - 2^240, past regtest's 2^255 - 1, and met by any hash below it
+        header.bits = 0x2100ffff;
+        header.calculate_hash();
+
+        assert!(chain.add_headers(&[header.clone()]).is_err());
+        assert!(!chain.contains(&header.block_hash));
+        assert_eq!(chain.stale_branches(), 0);
+    }
+
+    fn hashes(headers: &[BlockData]) -> Vec<BlockHash> {
+        headers.iter().map(|header| header.block_hash).collect()
+    }
+
+    #[test]
+    fn headers_after_the_first_locator_on_the_active_chain() {
+        let mut chain = ChainIndex::new(Network::Regtest);
+        let genesis = Network::Regtest.genesis_header();
+        let active = mine_chain(&genesis, 4, 0);
+        connected(chain.add_headers(&active).unwrap());
+        let stale = mine_chain(&active[1], 1, 1);
+        connected(chain.add_headers(&stale).unwrap());
+
+        // the stale header is known but not on the active chain, so height 1 is the fork
+        let locator = [stale[0].block_hash.0, active[0].block_hash.0];
+        assert_eq!(hashes(&chain.headers_after(&locator, [0; 32])), hashes(&active[1..]));
+        assert_eq!(hashes(&chain.headers_after(&locator, active[2].block_hash.0)), hashes(&active[1..3]));
+        assert!(chain.headers_after(&[active[3].block_hash.0], [0; 32]).is_empty());
+    }
+
+    #[test]
+    fn headers_after_an_unknown_locator_start_past_genesis() {
+        let mut chain = ChainIndex::new(Network::Regtest);
+        let active = mine_chain(&Network::Regtest.genesis_header(), 3, 0);
+        connected(chain.add_headers(&active).unwrap());
+
+        assert_eq!(hashes(&chain.headers_after(&[[7; 32]], [0; 32])), hashes(&active));
+        assert_eq!(hashes(&chain.headers_after(&[], [0; 32])), hashes(&active));
+    }
+
+    #[test]
+    fn longer_branch_takes_over() {
+        let mut chain = ChainIndex::new(Network::Regtest);
+        let genesis = Network::Regtest.genesis_header();
+        let active = mine_chain(&genesis, 2, 0);
+        connected(chain.add_headers(&active).unwrap());
+
+        let fork = mine_chain(&active[0], 2, 1);
+        let update = connected(chain.add_headers(&fork).unwrap());
+
+        assert_eq!(update.reorgs.len(), 1);
+        assert_eq!(update.reorgs[0].fork_height, 1);
+        assert_eq!(update.reorgs[0].disconnected, vec![active[1].block_hash]);
+        assert_eq!(chain.tip_height(), 3);
+        assert_eq!(chain.stale_branches(), 1);
+    }
+}
@@ -0,0 +1,366 @@
+// framing for the p2p wire format
+//
+// every message on the wire is a 24 byte header followed by the payload:
+// magic (4) | command (12, nul padded) | length (4, le) | checksum (4) | payload
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::serialisers::bitcoin_checksum;
+use crate::structures::BitcoinMessage;
+use crate::utils::ByteReader;
+
+pub const HEADER_SIZE: usize = 24;
+
+// same limit bitcoin core uses (MAX_PROTOCOL_MESSAGE_LENGTH)
+pub const MAX_PAYLOAD_SIZE: usize = 4 * 1000 * 1000;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    PayloadTooLarge { command: String, length: usize },
+    ChecksumMismatch { command: String, expected: [u8; 4], actual: [u8; 4] },
+    InvalidCommand([u8; 12]),
+    // an outgoing command that can't be written into the 12 byte field
+    UnencodableCommand(String),
+}
+
+impl CodecError {
+    // the reader has already skipped past the bad frame, so the stream is still usable
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, CodecError::Io(_))
+    }
+
+    // a read timeout ran out before a whole message arrived, what was read is still buffered
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            CodecError::Io(e) => matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "io error: {}", e),
+            CodecError::PayloadTooLarge { command, length } => write!(
+                f,
+                "{} payload of {} bytes exceeds the {} byte limit",
+                command, length, MAX_PAYLOAD_SIZE
+            ),
+            CodecError::ChecksumMismatch { command, expected, actual } => write!(
+                f,
+                "checksum mismatch for {}: header says {}, payload hashes to {}",
+                command,
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            CodecError::InvalidCommand(bytes) => {
+                write!(f, "invalid command bytes {}", hex::encode(bytes))
+            }
+            CodecError::UnencodableCommand(command) => {
+                write!(f, "command {:?} is not 1 to 12 printable ascii characters", command)
+            }
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+// commands are ascii, padded out to 12 bytes with nuls and nothing after the padding
+fn parse_command(bytes: &[u8; 12]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let (name, padding) = bytes.split_at(end);
+
+    if name.is_empty()
+        || !name.iter().all(|b| b.is_ascii_graphic())
+        || padding.iter().any(|&b| b != 0)
+    {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(name).to_string())
+}
+
+pub struct MessageReader<R: Read> {
+    inner: R,
+    buffer: Vec<u8>,
+    magic: [u8; 4],
+    bytes_read: u64,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(inner: R, magic: [u8; 4]) -> MessageReader<R> {
+        MessageReader {
+            inner,
+            buffer: Vec::new(),
+            magic,
+            bytes_read: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    // everything taken off the wire, including frames that were later thrown away
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    // Reads the next complete message. Anything before the magic bytes is thrown away, and
+    // partially received frames stay buffered between calls (including calls that fail
+    // with a timeout), so the reader never loses its place in the stream.
+    pub fn read_message(&mut self) -> Result<BitcoinMessage, CodecError> {
+        loop {
+            if !self.sync_to_magic() || self.buffer.len() < HEADER_SIZE {
+                self.fill_buffer()?;
+                continue;
+            }
+
+            let mut header = ByteReader::new(&self.buffer[self.magic.len()..HEADER_SIZE]);
+            let command_bytes: [u8; 12] = header.read_array()?;
+            let length = header.read_u32()? as usize;
+            let checksum: [u8; 4] = header.read_array()?;
+
+            let command = match parse_command(&command_bytes) {
+                Some(command) => command,
+                None => {
+                    // probably not a real header, look for the next magic instead
+                    self.buffer.drain(..self.magic.len());
+                    return Err(CodecError::InvalidCommand(command_bytes));
+                }
+            };
+
+            if length > MAX_PAYLOAD_SIZE {
+                self.buffer.drain(..self.magic.len());
+                return Err(CodecError::PayloadTooLarge { command, length });
+            }
+
+            if self.buffer.len() < HEADER_SIZE + length {
+                self.fill_buffer()?;
+                continue;
+            }
+
+            let frame: Vec<u8> = self.buffer.drain(..HEADER_SIZE + length).collect();
+            let payload = frame[HEADER_SIZE..].to_vec();
+
+            let actual = bitcoin_checksum(&payload);
+            if actual != checksum {
+                return Err(CodecError::ChecksumMismatch {
+                    command,
+                    expected: checksum,
+                    actual,
+                });
+            }
+
+            return Ok(BitcoinMessage {
+                magic: u32::from_le_bytes(self.magic),
+                command,
+                payload,
+            });
+        }
+    }
+
+    // Drops everything in front of the next magic. Returns false if no magic is buffered,
+    // keeping the tail in case it holds the start of one split across reads.
+    fn sync_to_magic(&mut self) -> bool {
+        match self.buffer.windows(self.magic.len()).position(|w| w == self.magic) {
+            Some(position) => {
+                self.buffer.drain(..position);
+                true
+            }
+            None => {
+                let keep = self.buffer.len().min(self.magic.len() - 1);
+                self.buffer.drain(..self.buffer.len() - keep);
+                false
+            }
+        }
+    }
+
+    fn fill_buffer(&mut self) -> Result<(), CodecError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(CodecError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed by peer",
+                    )))
+                }
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                    self.bytes_read += n as u64;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+// the whole frame, header and payload, ready to write
+pub fn serialise_message(message: &BitcoinMessage) -> Result<Vec<u8>, CodecError> {
+    if message.command.is_empty()
+        || message.command.len() > 12
+        || !message.command.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(CodecError::UnencodableCommand(message.command.clone()));
+    }
+
+    let mut complete_message = vec![];
+    let mut command = [0u8; 12];
+    command[..message.command.len()].copy_from_slice(message.command.as_bytes());
+    let checksum = bitcoin_checksum(&message.payload);
+
+    complete_message.extend(&message.magic.to_le_bytes());
+    complete_message.extend(&command);
+    complete_message.extend(&(message.payload.len() as u32).to_le_bytes());
+    complete_message.extend(&checksum);
+    complete_message.extend(&message.payload);
+
+    Ok(complete_message)
+}
+
+pub struct MessageWriter<W: Write> {
+    inner: W,
+    magic: [u8; 4],
+    bytes_written: u64,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(inner: W, magic: [u8; 4]) -> MessageWriter<W> {
+        MessageWriter { inner, magic, bytes_written: 0 }
+    }
+
+    pub fn magic(&self) -> [u8; 4] {
+        self.magic
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn write_message(&mut self, message: &BitcoinMessage) -> Result<(), CodecError> {
+        if message.payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(CodecError::PayloadTooLarge {
+                command: message.command.clone(),
+                length: message.payload.len(),
+            });
+        }
+
+        let bytes = serialise_message(message)?;
+        self.inner.write_all(&bytes)?;
+        self.inner.flush()?;
+        self.bytes_written += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::utils::BITCOIN_MAGIC;
+
+    fn frame(command: &str, payload: &[u8]) -> Vec<u8> {
+        serialise_message(&BitcoinMessage::new(BITCOIN_MAGIC, command, payload.to_vec())).unwrap()
+    }
+
+    // hands out at most chunk bytes per read, like a socket that's slow to fill
+    struct Trickle {
+        data: Cursor<Vec<u8>>,
+        chunk: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.chunk);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn skips_junk_before_the_magic() {
+        let mut bytes = vec![0x00, 0xf9, 0xbe, 0x13, 0x37];
+        bytes.extend(frame("ping", &[1, 2, 3, 4, 5, 6, 7, 8]));
+
+        let mut reader = MessageReader::new(Cursor::new(bytes), BITCOIN_MAGIC);
+        let message = reader.read_message().unwrap();
+        assert_eq!(message.command, "ping");
+        assert_eq!(message.payload, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn bad_checksum_is_recoverable() {
+        let mut bytes = frame("ping", &[1; 8]);
+        bytes[20] ^= 0x01;
+        bytes.extend(frame("verack", &[]));
+
+        let mut reader = MessageReader::new(Cursor::new(bytes), BITCOIN_MAGIC);
+        let error = reader.read_message().unwrap_err();
+        assert!(matches!(error, CodecError::ChecksumMismatch { ref command, .. } if command == "ping"));
+        assert!(error.is_recoverable());
+
+        assert_eq!(reader.read_message().unwrap().command, "verack");
+    }
+
+    #[test]
+    fn oversized_length_is_rejected_from_the_header() {
+        // only the header arrives, the reader mustn't wait or make room for the payload
+        let mut bytes = frame("block", &[]);
+        bytes[16..20].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+        bytes.extend(frame("verack", &[]));
+
+        let mut reader = MessageReader::new(Cursor::new(bytes), BITCOIN_MAGIC);
+        let error = reader.read_message().unwrap_err();
+        assert!(matches!(error, CodecError::PayloadTooLarge { length, .. } if length == MAX_PAYLOAD_SIZE + 1));
+        assert!(error.is_recoverable());
+        assert!(reader.buffer.capacity() < MAX_PAYLOAD_SIZE);
+
+        assert_eq!(reader.read_message().unwrap().command, "verack");
+    }
+
+    #[test]
+    fn message_split_across_reads() {
+        let payload: Vec<u8> = (0..=255).collect();
+        let mut bytes = frame("tx", &payload);
+        bytes.extend(frame("verack", &[]));
+
+        let trickle = Trickle { data: Cursor::new(bytes), chunk: 3 };
+        let mut reader = MessageReader::new(trickle, BITCOIN_MAGIC);
+        let message = reader.read_message().unwrap();
+        assert_eq!(message.command, "tx");
+        assert_eq!(message.payload, payload);
+        assert_eq!(reader.read_message().unwrap().command, "verack");
+    }
+
+    #[test]
+    fn eof_is_not_recoverable() {
+        let bytes = frame("ping", &[1; 8]);
+        let mut reader = MessageReader::new(Cursor::new(bytes[..30].to_vec()), BITCOIN_MAGIC);
+        assert!(!reader.read_message().unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn long_commands_are_refused() {
+        let message = BitcoinMessage::new(BITCOIN_MAGIC, "sendaddrv2xyz", Vec::new());
+        assert!(matches!(serialise_message(&message), Err(CodecError::UnencodableCommand(_))));
+
+        let mut writer = MessageWriter::new(Vec::new(), BITCOIN_MAGIC);
+        assert!(writer.write_message(&message).is_err());
+        assert!(writer.inner.is_empty());
+    }
+}
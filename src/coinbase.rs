@@ -0,0 +1,313 @@
+// what a block's coinbase, its first transaction, says about who mined it and what they got
+//
+// the coinbase's one input spends nothing and its scriptSig is free for the miner to fill.
+// since BIP34 it has to start with a push of the block height, and pools usually sign it
+// with their name after that. the outputs pay out the subsidy plus the fees of the block,
+// and since segwit one of them commits to the block's witness data (BIP141).
+
+use crate::merkle::compute_merkle_root;
+use crate::network::Network;
+use crate::script::{disassemble, Instruction, OP_1, OP_16};
+use crate::serialisers::sha256d;
+use crate::structures::BlockData;
+
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+
+// OP_RETURN, a push of 36 bytes, then the commitment header
+const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// shorter runs of printable bytes are usually just extranonce that happens to be printable
+const MIN_TAG_LENGTH: usize = 4;
+
+// what pools write in their coinbases, matched ignoring case. a pool can have a few
+const POOL_TAGS: &[(&str, &str)] = &[
+    ("Foundry USA", "Foundry USA"),
+    ("AntPool", "AntPool"),
+    ("F2Pool", "F2Pool"),
+    ("七彩神仙鱼", "F2Pool"),
+    ("ViaBTC", "ViaBTC"),
+    ("/Binance/", "Binance Pool"),
+    ("MARA Pool", "MARA Pool"),
+    ("MARA Made in USA", "MARA Pool"),
+    ("SpiderPool", "SpiderPool"),
+    ("/slush/", "Braiins Pool"),
+    ("Luxor", "Luxor"),
+    ("/BTC.COM/", "BTC.com"),
+    ("poolin", "Poolin"),
+    ("SBICrypto", "SBI Crypto"),
+    ("SecPool", "SECPOOL"),
+    ("ultimus", "ULTIMUSPOOL"),
+    ("ocean.xyz", "OCEAN"),
+    ("WhitePool", "WhitePool"),
+    ("ckpool", "Solo CK"),
+    ("/Bitfury/", "BitFury"),
+    ("BTC Guild", "BTC Guild"),
+    ("Eligius", "Eligius"),
+    ("/BitClub Network/", "BitClub"),
+    ("KanoPool", "KanoPool"),
+];
+
+pub struct WitnessCommitment {
+    pub commitment: [u8; 32],
+    // None when the coinbase has no 32 byte reserved value to check it with
+    pub valid: Option<bool>,
+}
+
+pub struct Coinbase {
+    pub bip34_height: Option<u32>,
+    // the printable parts of the scriptSig after the height
+    pub tag: String,
+    pub pool: Option<&'static str>,
+    // everything the coinbase pays out
+    pub reward: u64,
+    // None when the block's height isn't known
+    pub subsidy: Option<u64>,
+    pub witness_commitment: Option<WitnessCommitment>,
+}
+
+impl Coinbase {
+    // what the miner claimed on top of the subsidy. it can't be more than the fees the
+    // block's transactions paid, but a miner is free to take less
+    pub fn fees(&self) -> Option<u64> {
+        self.subsidy.map(|subsidy| self.reward.saturating_sub(subsidy))
+    }
+}
+
+// None when the block hasn't been downloaded in full. height is where the block sits on the
+// chain if that's known, otherwise the height in the coinbase is used for the subsidy.
+pub fn decode_coinbase(block: &BlockData, height: Option<u32>, network: Network) -> Option<Coinbase> {
+    let coinbase = block.transactions.first().filter(|transaction| transaction.is_coinbase())?;
+    let script_sig = &coinbase.inputs[0].script_sig;
+    // genesis has nothing before it, even when it isn't on a synced chain
+    let height = if block.prev_block_hash == [0; 32] { Some(0) } else { height };
+
+    // before BIP34 the scriptSig can start with anything, genesis starts with the target
+    let bip34 = match height {
+        Some(height) if height < network.bip34_height() => None,
+        _ => bip34_height(script_sig),
+    };
+    let after_height = bip34.map_or(0, |(_, length)| length);
+    let bip34_height = bip34.map(|(height, _)| height);
+
+    Some(Coinbase {
+        bip34_height,
+        tag: miner_tag(&script_sig[after_height..]),
+        pool: identify_pool(script_sig),
+        reward: coinbase.outputs.iter().map(|output| output.value).sum(),
+        subsidy: height.or(bip34_height).map(|height| block_subsidy(height, network)),
+        witness_commitment: witness_commitment(block),
+    })
+}
+
+pub fn block_subsidy(height: u32, network: Network) -> u64 {
+    let halvings = height / network.halving_interval();
+    if halvings >= 64 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+// the height and how many bytes of the scriptSig it took up. heights up to 16 are pushed
+// with OP_1 to OP_16, anything higher as a little endian number
+fn bip34_height(script_sig: &[u8]) -> Option<(u32, usize)> {
+    match disassemble(script_sig).instructions.first()? {
+        Instruction::Op(opcode @ OP_1..=OP_16) => Some(((opcode - OP_1 + 1) as u32, 1)),
+        Instruction::Push(data) if (1..=4).contains(&data.len()) => {
+            // the top bit of the last byte is the sign, heights can't be negative
+            if data[data.len() - 1] & 0x80 != 0 {
+                return None;
+            }
+            let height = data.iter().rev().fold(0u32, |height, &byte| (height << 8) | byte as u32);
+            Some((height, 1 + data.len()))
+        }
+        _ => None,
+    }
+}
+
+// runs of printable text in the scriptSig. each push is searched on its own so the length
+// bytes in front of it don't run into the text, unless the scriptSig doesn't decode
+fn miner_tag(script_sig: &[u8]) -> String {
+    let disassembly = disassemble(script_sig);
+    let pieces: Vec<&[u8]> = match disassembly.malformed {
+        Some(_) => vec![script_sig],
+        None => disassembly
+            .instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Push(data) => Some(*data),
+                Instruction::Op(_) => None,
+            })
+            .collect(),
+    };
+
+    pieces
+        .iter()
+        .flat_map(|piece| piece.split(|byte| !(0x20..=0x7e).contains(byte)))
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|run| run.len() >= MIN_TAG_LENGTH)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn identify_pool(script_sig: &[u8]) -> Option<&'static str> {
+    let script_sig = script_sig.to_ascii_lowercase();
+    POOL_TAGS
+        .iter()
+        .find(|(tag, _)| {
+            let tag = tag.as_bytes().to_ascii_lowercase();
+            script_sig.windows(tag.len()).any(|window| window == tag)
+        })
+        .map(|(_, pool)| *pool)
+}
+
+// BIP141: the last output starting with the commitment header holds
+// sha256d(witness merkle root | reserved value), where the reserved value is the coinbase's
+// witness and the root is taken over wtxids with the coinbase's counted as zeros
+fn witness_commitment(block: &BlockData) -> Option<WitnessCommitment> {
+    let coinbase = block.transactions.first()?;
+    let output = coinbase
+        .outputs
+        .iter()
+        .rev()
+        .find(|output| output.script_pub_key.len() >= 38 && output.script_pub_key.starts_with(&WITNESS_COMMITMENT_PREFIX))?;
+
+    let mut commitment = [0; 32];
+    commitment.copy_from_slice(&output.script_pub_key[6..38]);
+
+    let valid = match coinbase.inputs[0].witness.as_slice() {
+        [reserved] if reserved.len() == 32 => {
+            let mut wtxids: Vec<[u8; 32]> = block.transactions.iter().map(|transaction| transaction.wtxid).collect();
+            wtxids[0] = [0; 32];
+            compute_merkle_root(&wtxids).map(|root| sha256d(&[&root[..], reserved].concat()) == commitment)
+        }
+        _ => None,
+    };
+
+    Some(WitnessCommitment { commitment, valid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::parse_block;
+    use crate::serialisers::serialise_block_header;
+
+    // a block holding only a coinbase with the given scriptSig and outputs, with a 32 byte
+    // witness reserved value of zeros when witness is set. it has a parent so it isn't
+    // taken for genesis
+    fn coinbase_block(script_sig: &[u8], outputs: &[(u64, Vec<u8>)], witness: bool) -> BlockData {
+        let mut header = Network::Regtest.genesis_header();
+        header.prev_block_hash = [1; 32];
+        let mut bytes = serialise_block_header(&header);
+        bytes.push(1);
+
+        bytes.extend([2, 0, 0, 0]);
+        if witness {
+            bytes.extend([0, 1]);
+        }
+        bytes.push(1);
+        bytes.extend([0; 32]);
+        bytes.extend([0xff; 4]);
+        bytes.push(script_sig.len() as u8);
+        bytes.extend(script_sig);
+        bytes.extend([0xff; 4]);
+
+        bytes.push(outputs.len() as u8);
+        for (value, script) in outputs {
+            bytes.extend(value.to_le_bytes());
+            bytes.push(script.len() as u8);
+            bytes.extend(script);
+        }
+        if witness {
+            bytes.extend([1, 32]);
+            bytes.extend([0; 32]);
+        }
+        bytes.extend([0; 4]);
+
+        parse_block(&bytes).unwrap()
+    }
+
+    fn height_of(script_sig: &[u8]) -> Option<u32> {
+        let block = coinbase_block(script_sig, &[(0, vec![0x51])], false);
+        decode_coinbase(&block, None, Network::Mainnet).unwrap().bip34_height
+    }
+
+    fn commitment_output(commitment: &str) -> (u64, Vec<u8>) {
+        let mut script = WITNESS_COMMITMENT_PREFIX.to_vec();
+        script.extend(hex::decode(commitment).unwrap());
+        (0, script)
+    }
+
+    // sha256d of a witness root of zeros and a reserved value of zeros, what every block with
+    // nothing but its coinbase in it commits to
+    const EMPTY_BLOCK_COMMITMENT: &str = "e2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9";
+
+    #[test]
+    fn small_heights_are_opcodes() {
+        assert_eq!(height_of(&[OP_1]), Some(1));
+        assert_eq!(height_of(&[OP_16, 0x04, b'p', b'o', b'o', b'l']), Some(16));
+    }
+
+    #[test]
+    fn heights_are_little_endian() {
+        assert_eq!(height_of(&[0x03, 0x40, 0x0d, 0x03]), Some(200_000));
+        // 0x800000 needs a fourth byte to keep the sign bit clear
+        assert_eq!(height_of(&[0x04, 0x00, 0x00, 0x80, 0x00]), Some(0x80_0000));
+        assert_eq!(height_of(&[0x03, 0x00, 0x00, 0x80]), None);
+    }
+
+    #[test]
+    fn height_comes_off_the_tag() {
+        let mut script_sig = vec![0x03, 0x40, 0x0d, 0x03, 0x08];
+        script_sig.extend(b"/F2Pool/");
+        script_sig.extend([0x03, 1, 2, 3]);
+        let block = coinbase_block(&script_sig, &[], false);
+        let coinbase = decode_coinbase(&block, None, Network::Mainnet).unwrap();
+        assert_eq!(coinbase.tag, "/F2Pool/");
+        assert_eq!(coinbase.pool, Some("F2Pool"));
+    }
+
+    #[test]
+    fn no_height_before_bip34() {
+        let block = coinbase_block(&[0x03, 0x40, 0x0d, 0x03], &[], false);
+        assert_eq!(decode_coinbase(&block, Some(100), Network::Mainnet).unwrap().bip34_height, None);
+    }
+
+    #[test]
+    fn subsidy_halves_on_schedule() {
+        assert_eq!(block_subsidy(0, Network::Mainnet), 50 * 100_000_000);
+        assert_eq!(block_subsidy(209_999, Network::Mainnet), 50 * 100_000_000);
+        assert_eq!(block_subsidy(210_000, Network::Mainnet), 25 * 100_000_000);
+        assert_eq!(block_subsidy(420_000, Network::Mainnet), 1_250_000_000);
+        assert_eq!(block_subsidy(149, Network::Regtest), 50 * 100_000_000);
+        assert_eq!(block_subsidy(150, Network::Regtest), 25 * 100_000_000);
+        assert_eq!(block_subsidy(64 * 150, Network::Regtest), 0);
+    }
+
+    #[test]
+    fn fees_are_the_reward_over_the_subsidy() {
+        let block = coinbase_block(&[OP_1], &[(block_subsidy(1, Network::Regtest) + 1234, vec![0x51])], false);
+        assert_eq!(decode_coinbase(&block, None, Network::Regtest).unwrap().fees(), Some(1234));
+    }
+
+    #[test]
+    fn empty_block_witness_commitment() {
+        let block = coinbase_block(&[OP_1], &[(0, vec![0x51]), commitment_output(EMPTY_BLOCK_COMMITMENT)], true);
+        let commitment = witness_commitment(&block).unwrap();
+        assert_eq!(hex::encode(commitment.commitment), EMPTY_BLOCK_COMMITMENT);
+        assert_eq!(commitment.valid, Some(true));
+    }
+
+    #[test]
+    fn wrong_witness_commitment() {
+        let block = coinbase_block(&[OP_1], &[commitment_output(&"00".repeat(32))], true);
+        assert_eq!(witness_commitment(&block).unwrap().valid, Some(false));
+    }
+
+    #[test]
+    fn commitment_without_a_reserved_value() {
+        let block = coinbase_block(&[OP_1], &[commitment_output(EMPTY_BLOCK_COMMITMENT)], false);
+        assert_eq!(witness_commitment(&block).unwrap().valid, None);
+        assert!(witness_commitment(&coinbase_block(&[OP_1], &[(0, vec![0x51])], true)).is_none());
+    }
+}
@@ -0,0 +1,285 @@
+// runtime settings, taken from (highest priority first) command line arguments, environment
+// variables and a config file of key=value lines
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::network::Network;
+
+pub const DEFAULT_CONFIG_FILE: &str = "explorer.conf";
+
+pub const PEER_ENV_VAR: &str = "EXPLORER_PEER";
+pub const NETWORK_ENV_VAR: &str = "EXPLORER_NETWORK";
+pub const CONFIG_ENV_VAR: &str = "EXPLORER_CONFIG";
+pub const BACKFILL_ENV_VAR: &str = "EXPLORER_BACKFILL";
+pub const DATA_DIR_ENV_VAR: &str = "EXPLORER_DATADIR";
+pub const BLOCKS_DIR_ENV_VAR: &str = "EXPLORER_BLOCKSDIR";
+
+// blocks and headers are kept under here, one directory per network
+pub const DEFAULT_DATA_DIR: &str = "explorer-data";
+
+// how many older blocks each "load more" fetches unless told otherwise
+pub const DEFAULT_BACKFILL_DEPTH: u32 = 10;
+
+pub struct Config {
+    pub network: Network,
+    // host:port, where host can be a name, an ipv4 address or a bracketed ipv6 address.
+    // None means find one through the network's dns seeds
+    pub peer: Option<String>,
+    // blocks fetched per "load more" at the bottom of the block list
+    pub backfill_depth: u32,
+    pub data_dir: PathBuf,
+    // a bitcoin core data or blocks directory to read instead of going to the network
+    pub blocks_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let cli = parse_args(&args)?;
+        let env = |name: &str| std::env::var(name).ok();
+
+        // an explicitly named config file has to exist, the default one is optional
+        let config_path = cli.config.clone().or_else(|| env(CONFIG_ENV_VAR));
+        let file = match &config_path {
+            Some(path) => parse_config_file(&fs::read_to_string(path)
+                .map_err(|e| format!("Could not read config file {}: {}", path, e))?)?,
+            None => match fs::read_to_string(DEFAULT_CONFIG_FILE) {
+                Ok(contents) => parse_config_file(&contents)?,
+                Err(_) => FileSettings::default(),
+            },
+        };
+
+        resolve(cli, env, file)
+    }
+}
+
+// each setting from the command line if it's there, then the environment, then the file
+fn resolve(cli: CliSettings, env: impl Fn(&str) -> Option<String>, file: FileSettings) -> Result<Config, Box<dyn Error>> {
+    let network_name = cli.network.or_else(|| env(NETWORK_ENV_VAR)).or(file.network);
+    let network = match network_name {
+        Some(name) => Network::from_name(&name)
+            .ok_or_else(|| format!("Unknown network {}, expected mainnet, testnet, signet or regtest", name))?,
+        None => Network::Mainnet,
+    };
+
+    let peer = cli
+        .peer
+        .or_else(|| env(PEER_ENV_VAR))
+        .or(file.peer)
+        .map(|peer| with_default_port(&peer, network.default_port()));
+
+    let backfill_depth = match cli.backfill.or_else(|| env(BACKFILL_ENV_VAR)).or(file.backfill) {
+        Some(depth) => parse_backfill_depth(&depth)?,
+        None => DEFAULT_BACKFILL_DEPTH,
+    };
+
+    let data_dir = cli
+        .data_dir
+        .or_else(|| env(DATA_DIR_ENV_VAR))
+        .or(file.data_dir)
+        .unwrap_or_else(|| DEFAULT_DATA_DIR.to_string());
+
+    let blocks_dir = cli
+        .blocks_dir
+        .or_else(|| env(BLOCKS_DIR_ENV_VAR))
+        .or(file.blocks_dir)
+        .map(PathBuf::from);
+
+    Ok(Config {
+        network,
+        peer,
+        backfill_depth,
+        data_dir: PathBuf::from(data_dir),
+        blocks_dir,
+    })
+}
+
+#[derive(Default)]
+struct CliSettings {
+    peer: Option<String>,
+    network: Option<String>,
+    config: Option<String>,
+    backfill: Option<String>,
+    data_dir: Option<String>,
+    blocks_dir: Option<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<CliSettings, Box<dyn Error>> {
+    let mut settings = CliSettings::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", name))
+        };
+
+        match arg.as_str() {
+            "--peer" | "-p" => settings.peer = Some(value("--peer")?),
+            "--network" | "-n" => settings.network = Some(value("--network")?),
+            "--config" | "-c" => settings.config = Some(value("--config")?),
+            "--backfill" | "-b" => settings.backfill = Some(value("--backfill")?),
+            "--datadir" | "-d" => settings.data_dir = Some(value("--datadir")?),
+            "--blocksdir" | "-B" => settings.blocks_dir = Some(value("--blocksdir")?),
+            other => return Err(format!("Unknown argument {}", other).into()),
+        }
+    }
+
+    Ok(settings)
+}
+
+#[derive(Default)]
+struct FileSettings {
+    peer: Option<String>,
+    network: Option<String>,
+    backfill: Option<String>,
+    data_dir: Option<String>,
+    blocks_dir: Option<String>,
+}
+
+fn parse_config_file(contents: &str) -> Result<FileSettings, Box<dyn Error>> {
+    let mut settings = FileSettings::default();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Config line {} is not key=value: {}", number + 1, line))?;
+
+        match key.trim() {
+            "peer" => settings.peer = Some(value.trim().to_string()),
+            "network" => settings.network = Some(value.trim().to_string()),
+            "backfill" => settings.backfill = Some(value.trim().to_string()),
+            "datadir" => settings.data_dir = Some(value.trim().to_string()),
+            "blocksdir" => settings.blocks_dir = Some(value.trim().to_string()),
+            other => return Err(format!("Unknown config key {} on line {}", other, number + 1).into()),
+        }
+    }
+
+    Ok(settings)
+}
+
+fn parse_backfill_depth(depth: &str) -> Result<u32, Box<dyn Error>> {
+    match depth.trim().parse() {
+        Ok(depth) if depth > 0 => Ok(depth),
+        _ => Err(format!("Backfill depth must be a whole number of blocks above 0, got {}", depth).into()),
+    }
+}
+
+// "host" -> "host:8333", "::1" -> "[::1]:8333", anything that already has a port is left alone
+fn with_default_port(peer: &str, port: u16) -> String {
+    if peer.starts_with('[') {
+        if peer.contains("]:") {
+            peer.to_string()
+        } else {
+            format!("{}:{}", peer, port)
+        }
+    } else {
+        match peer.matches(':').count() {
+            0 => format!("{}:{}", peer, port),
+            1 => peer.to_string(),
+            // a bare ipv6 address
+            _ => format!("[{}]:{}", peer, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn parses_long_and_short_flags() {
+        let cli = parse_args(&args("--peer example.org -n testnet -b 25 --datadir data -B /core/blocks -c my.conf")).unwrap();
+        assert_eq!(cli.peer.as_deref(), Some("example.org"));
+        assert_eq!(cli.network.as_deref(), Some("testnet"));
+        assert_eq!(cli.backfill.as_deref(), Some("25"));
+        assert_eq!(cli.data_dir.as_deref(), Some("data"));
+        assert_eq!(cli.blocks_dir.as_deref(), Some("/core/blocks"));
+        assert_eq!(cli.config.as_deref(), Some("my.conf"));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        assert_eq!(parse_args(&args("--peer")).err().unwrap().to_string(), "--peer needs a value");
+        assert_eq!(parse_args(&args("--verbose")).err().unwrap().to_string(), "Unknown argument --verbose");
+    }
+
+    #[test]
+    fn parses_config_files() {
+        let file = parse_config_file("# a comment\n\n peer = 10.0.0.1:8333 \nnetwork=signet\nbackfill=5\n").unwrap();
+        assert_eq!(file.peer.as_deref(), Some("10.0.0.1:8333"));
+        assert_eq!(file.network.as_deref(), Some("signet"));
+        assert_eq!(file.backfill.as_deref(), Some("5"));
+        assert!(file.data_dir.is_none());
+
+        let error = parse_config_file("peer=a\nnonsense").err().unwrap();
+        assert_eq!(error.to_string(), "Config line 2 is not key=value: nonsense");
+        let error = parse_config_file("colour=blue").err().unwrap();
+        assert_eq!(error.to_string(), "Unknown config key colour on line 1");
+    }
+
+    #[test]
+    fn command_line_beats_environment_beats_file() {
+        let file = || parse_config_file("peer=file.example\nnetwork=regtest\nbackfill=3\ndatadir=file-data").unwrap();
+        let env: HashMap<&str, &str> = [(PEER_ENV_VAR, "env.example"), (BACKFILL_ENV_VAR, "7")].into();
+        let env = |name: &str| env.get(name).map(|value| value.to_string());
+
+        let config = resolve(parse_args(&args("--backfill 9")).unwrap(), env, file()).unwrap();
+        assert_eq!(config.backfill_depth, 9);
+        assert_eq!(config.peer.as_deref(), Some("env.example:18444"));
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.data_dir, PathBuf::from("file-data"));
+
+        let config = resolve(CliSettings::default(), env, file()).unwrap();
+        assert_eq!(config.backfill_depth, 7);
+
+        let config = resolve(CliSettings::default(), no_env, file()).unwrap();
+        assert_eq!(config.peer.as_deref(), Some("file.example:18444"));
+        assert_eq!(config.backfill_depth, 3);
+    }
+
+    #[test]
+    fn defaults_without_any_settings() {
+        let config = resolve(CliSettings::default(), no_env, FileSettings::default()).unwrap();
+        assert_eq!(config.network, Network::Mainnet);
+        assert_eq!(config.peer, None);
+        assert_eq!(config.backfill_depth, DEFAULT_BACKFILL_DEPTH);
+        assert_eq!(config.data_dir, PathBuf::from(DEFAULT_DATA_DIR));
+        assert_eq!(config.blocks_dir, None);
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        let cli = parse_args(&args("--network moon")).unwrap();
+        assert!(resolve(cli, no_env, FileSettings::default()).is_err());
+        let cli = parse_args(&args("--backfill 0")).unwrap();
+        assert!(resolve(cli, no_env, FileSettings::default()).is_err());
+    }
+
+    #[test]
+    fn default_port_is_added_where_missing() {
+        assert_eq!(with_default_port("seed.example", 8333), "seed.example:8333");
+        assert_eq!(with_default_port("seed.example:18333", 8333), "seed.example:18333");
+        assert_eq!(with_default_port("10.0.0.1", 8333), "10.0.0.1:8333");
+        assert_eq!(with_default_port("2001:db8::1", 8333), "[2001:db8::1]:8333");
+        assert_eq!(with_default_port("[2001:db8::1]", 8333), "[2001:db8::1]:8333");
+        assert_eq!(with_default_port("[2001:db8::1]:18444", 8333), "[2001:db8::1]:18444");
+    }
+}
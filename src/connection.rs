@@ -1,49 +1,130 @@
 use std::error::Error;
-use std::io::{Cursor, Write};
+use std::io::{self, Read, Write};
 use std::sync::mpsc::Sender;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{io::Read, net::TcpStream};
-
-use crate::serialisers::{bitcoin_checksum, construct_complete_message, serialise_version_message};
-use crate::structures::{BitcoinMessage, Command, NetAddr, VersionPayload, BlockData, Transaction, TransactionInput, TransactionOutput};
-use crate::utils::{read_bytes, read_u32, read_u64, read_var_bytes, read_var_int, BITCOIN_MAGIC};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::chain::{ChainIndex, HeadersOutcome, MAX_HEADERS};
+use crate::codec::{MessageReader, MessageWriter};
+use crate::merkle::verify_merkle_root;
+use crate::network::Network;
+use crate::parsers::parse_network_message;
+use crate::peer_manager::{InventoryTracker, PeerContext};
+use crate::serialisers::build_message;
+use crate::storage::BlockStore;
+use crate::structures::{BlockData, BlockHash, BlockLocatorPayload, ChainUpdate, HeadersPayload, InvPayload, InvType, Inventory, NetAddr, NetworkCommand, NetworkEvent, NetworkMessage, PeerId, PeerInfo, PeerStats, PingPayload, VersionPayload};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const PROTOCOL_VERSION: i32 = 70015;
+// bitcoin core won't talk to anything older than this either
+const MIN_PEER_VERSION: i32 = 31800;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// peers ping every couple of minutes, one that stays silent longer than this has gone away
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// a peer syncing us headers gets this long to answer each getheaders
+const HEADERS_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+// a backfill block that hasn't turned up by now isn't coming, the ui can ask again
+const BACKFILL_TIMEOUT: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// how often byte counts go to the ui while traffic is flowing
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Connection {
-    stream: Option<TcpStream>,
+    network: Network,
+    reader: Option<MessageReader<TcpStream>>,
+    writer: Option<MessageWriter<TcpStream>>,
+    peer_info: Option<PeerInfo>,
+}
+
+struct HeaderSync {
+    // when our outstanding getheaders went out
+    requested: Option<Instant>,
+    caught_up: bool,
+}
+
+// a walk back through prev_block_hash, one getdata at a time
+struct Backfill {
+    waiting_for: BlockHash,
+    requested: Instant,
+    // blocks still to fetch after the one we're waiting for
+    remaining: u32,
+}
+
+// Each side sends a version and answers the other's with a verack. We send ours straight
+// away, so what's left is waiting on theirs and then on their verack. Peers may slip in
+// feature messages like wtxidrelay and sendaddrv2 between the two.
+enum HandshakeState {
+    AwaitingVersion,
+    AwaitingVerack(PeerInfo),
+}
+
+impl HandshakeState {
+    fn waiting_for(&self) -> &'static str {
+        match self {
+            HandshakeState::AwaitingVersion => "waiting for the peer's version",
+            HandshakeState::AwaitingVerack(_) => "waiting for the peer's verack",
+        }
+    }
+}
+
+// The handshake sets a deadline on each read, anything it runs over has to support that
+trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
 }
 
 impl Connection {
-    pub fn new() -> Connection {
-        Connection { stream: None }
+    pub fn new(network: Network) -> Connection {
+        Connection { network, reader: None, writer: None, peer_info: None }
     }
 
-    pub fn connect(&mut self) -> Result<(), Box<dyn Error>> {
-        let stream = TcpStream::connect("52.57.53.177:8333")?;
-        self.stream = Some(stream);
-        Ok(())
+    // peer is host:port, the host can be a name or an ipv4/ipv6 address
+    pub fn connect(&mut self, peer: &str) -> Result<(), Box<dyn Error>> {
+        let addresses: Vec<SocketAddr> = peer
+            .to_socket_addrs()
+            .map_err(|e| format!("Could not resolve {}: {}", peer, e))?
+            .collect();
+
+        // a name can resolve to several addresses, use the first one that answers
+        let mut last_error = None;
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    self.writer = Some(MessageWriter::new(stream.try_clone()?, self.network.magic()));
+                    self.reader = Some(MessageReader::new(stream, self.network.magic()));
+                    return Ok(());
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(format!("Could not connect to {}: {}", peer, e).into()),
+            None => Err(format!("{} did not resolve to any addresses", peer).into()),
+        }
     }
 
     pub fn handshake(&mut self) -> Result<(), Box<dyn Error>> {
-        let stream = match &mut self.stream {
-            Some(stream) => stream,
-            None => return Err("Not connected to a node".into()),
+        let (reader, writer) = match (&mut self.reader, &mut self.writer) {
+            (Some(reader), Some(writer)) => (reader, writer),
+            _ => return Err("Not connected to a node".into()),
         };
 
-        //prep payload
-        let addr_recv = NetAddr {
-            services: 0,
-            ip_v6_4: "::ffff:2d90:70d0".parse().unwrap(),
-            port: 8333,
-        };
-
-        let addr_from = NetAddr {
-            services: 0,
-            ip_v6_4: "::ffff:54cb:4f70".parse().unwrap(),
-            port: 8333,
-        };
+        //prep payload, the addresses come from the socket itself
+        let stream = reader.get_ref();
+        let peer_address = stream.peer_addr()?;
+        let addr_recv = net_addr(peer_address, 0);
+        let addr_from = net_addr(stream.local_addr()?, 0);
 
         let version_message = VersionPayload {
-            version: 70015, // current protocol version
+            version: PROTOCOL_VERSION, // current protocol version
             services: 1,    // node is a full node
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -56,349 +137,582 @@ impl Connection {
             start_height: 0,                         // block height
             relay: true,                             // node will relay transactions
         };
+        let peer_info = exchange_versions(reader, writer, version_message, peer_address.to_string())?;
+        self.peer_info = Some(peer_info);
+        Ok(())
+    }
+
+    // None until the handshake has finished
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref()
+    }
 
-        let verack_message = BitcoinMessage {
-            magic: 0xd9b4bef9,
-            command: "verack".to_string(),
-            length: 0,
-            payload: vec![],
+    pub fn handle_stream(&mut self, context: PeerContext) -> Result<(), Box<dyn Error>> {
+        let PeerContext { id, events: sender, commands, inventory, chain, store } = context;
+        let (reader, writer) = match (&mut self.reader, &mut self.writer) {
+            (Some(reader), Some(writer)) => (reader, writer),
+            _ => return Err("Not connected to a node".into()),
         };
 
-        // Serialize the struct into bytes
-        let serialized_version_message = match serialise_version_message(&version_message) {
-            Ok(message) => message,
-            Err(e) => {
-                //println!("Failed to serialize version message: {}", e);
-                return Err("Failed to serialize version message".into());
+        if let Some(info) = &self.peer_info {
+            sender.send(NetworkEvent::PeerConnected(id, info.clone()))?;
+        }
+
+        // reads wake up every tick so pings and stats go out even when the peer is quiet
+        reader.get_ref().set_read_timeout(Some(STATS_INTERVAL))?;
+
+        let mut last_received = Instant::now();
+        let mut last_ping: Option<Instant> = None;
+        let mut pending_ping: Option<(u64, Instant)> = None;
+        let mut stats = PeerStats::default();
+        let mut reported_stats = None;
+
+        // header sync with this peer. once it has told us it has nothing past our tip we only
+        // go back for more when it announces something we can't connect
+        let start_height = self.peer_info.as_ref().map_or(0, |info| info.start_height.max(0) as u32);
+        let mut sync = HeaderSync { requested: None, caught_up: false };
+        let mut backfill: Option<Backfill> = None;
+
+        loop {
+            if last_received.elapsed() > IDLE_TIMEOUT {
+                return Err("Peer went quiet".into());
             }
-        };
 
-        // Construct the complete message
-        let complete_message =
-            construct_complete_message(Command::Version, serialized_version_message);
-
-        stream.write_all(&complete_message)?;
-
-        // Listen for a response
-        let mut header_buffer = [0; 24];
-        match stream.read_exact(&mut header_buffer) {
-            Ok(_) => {
-                let length =
-                    u32::from_le_bytes((&header_buffer[16..20]).try_into().unwrap()) as usize;
-
-                // Read the payload
-                let mut payload_buffer = vec![0; length];
-                match stream.read_exact(&mut payload_buffer) {
-                    Ok(_) => {
-                        let message = BitcoinMessage {
-                            magic: u32::from_le_bytes((&header_buffer[0..4]).try_into().unwrap()),
-                            command: String::from_utf8((&header_buffer[4..16]).try_into().unwrap())
-                                .unwrap()
-                                .trim_end_matches('\0')
-                                .to_string(),
-                            length: length as u32,
-                            payload: payload_buffer,
-                        };
-
-                        //println!("BitcoinMessage: {:?}", message);
-                    }
-                    Err(e) => {
-                        //println!("Failed to read payload: {}", e);
-                        return Err("Failed to read payload".into());
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    NetworkCommand::Backfill { start, depth } => {
+                        backfill = request_backfill(start, depth, &store, writer, &sender)?;
                     }
                 }
             }
-            Err(e) => {
-                //println!("Failed to read header: {}", e);
-                return Err("Failed to read header".into());
+            if backfill.as_ref().is_some_and(|walk| walk.requested.elapsed() > BACKFILL_TIMEOUT) {
+                backfill = None;
+                sender.send(NetworkEvent::BackfillFinished)?;
             }
-        }
 
-        let verack_bytes = construct_complete_message(Command::Verack, vec![]);
+            match sync.requested {
+                Some(requested) if requested.elapsed() > HEADERS_TIMEOUT => {
+                    return Err("Peer stalled during header sync".into())
+                }
+                Some(_) => {}
+                None if !sync.caught_up => {
+                    let mut chain = chain.lock().unwrap();
+                    if chain.tip_height() < start_height && chain.claim_sync(id) {
+                        request_headers(&chain, writer, &mut sync)?;
+                    }
+                }
+                None => {}
+            }
 
-        match stream.write_all(&verack_bytes) {
-            Ok(_) => {}, //println!("Verack message sent!"),
-            Err(e) => {
-                //println!("Failed to send verack message: {}", e);
-                return Err("Failed to send verack message".into());
+            let ping_due = last_ping.is_none_or(|sent| sent.elapsed() >= PING_INTERVAL);
+            if pending_ping.is_none() && ping_due {
+                let nonce = rand::random::<u64>();
+                send(writer, &NetworkMessage::Ping(PingPayload { nonce }))?;
+                pending_ping = Some((nonce, Instant::now()));
+                last_ping = Some(Instant::now());
             }
-        }
-        Ok(())
-    }
 
-    pub fn handle_stream(&mut self, sender: Sender<BlockData>) -> Result<(), Box<dyn Error>> {
-        let stream = match &mut self.stream {
-            Some(stream) => stream,
-            None => return Err("Not connected to a node".into()),
-        };
+            stats.bytes_in = reader.bytes_read();
+            stats.bytes_out = writer.bytes_written();
+            if reported_stats != Some(stats) {
+                sender.send(NetworkEvent::PeerUpdated(id, stats))?;
+                reported_stats = Some(stats);
+            }
 
-        loop {
-            // create buffer
-            let mut buffer: Vec<u8> = vec![0; 24];
-
-            let num_bytes = stream.read(&mut buffer).unwrap();
-
-            if num_bytes >= 24 {
-                let magic_bytes = &buffer[0..4];
-                let command_bytes = &buffer[4..16];
-                let length_bytes = &buffer[16..20];
-                let checksum_bytes = &buffer[20..24];
-
-                if magic_bytes == BITCOIN_MAGIC {
-                    //println!("Magic number matched");
-
-                    let command = String::from_utf8_lossy(command_bytes)
-                        .trim_end_matches('\0')
-                        .to_string();
-                    let length = u32::from_le_bytes(length_bytes.try_into().unwrap());
-                    let checksum = u32::from_le_bytes(checksum_bytes.try_into().unwrap());
-
-                    //println!("Command: {}", command);
-                    //println!("Length: {}", length);
-                    //println!("Checksum: {}\n\n", checksum);
-
-                    let mut payload: Vec<u8> = vec![0; length as usize];
-                    match stream.read_exact(&mut payload) {
-                        Ok(_) => {
-                            // Continue processing the payload
-                            if command == "ping" {
-                                let nonce = u64::from_le_bytes(payload[0..8].try_into().unwrap());
-                                handle_ping(nonce, stream).unwrap();
-                            } else if command == "inv" {
-                                handle_inv(payload, stream).unwrap();
-                            } else if command == "block" {
-                                handle_block(payload, &sender).unwrap();
-                            }
-                            else if command == "getheaders" {
-                                parse_getheaders(payload, stream).unwrap();
-                            }
-                        },
-                        Err(e) => {
-                            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                                //println!("Reached end of file before expected");
-                                // Handle the error, e.g., by breaking out of the loop or returning an error
-                            } else {
-                                // Propagate the error
-                                return Err(e.into());
-                            }
+            let message = match reader.read_message() {
+                Ok(message) => message,
+                // a bad frame has already been skipped, carry on with the next one
+                Err(e) if e.is_recoverable() => continue,
+                Err(e) if e.is_timeout() => continue,
+                Err(e) => return Err(e.into()),
+            };
+            last_received = Instant::now();
+
+            // a payload we can't make sense of is dropped rather than ending the session
+            let message = match parse_network_message(&message.command, &message.payload) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+
+            match message {
+                NetworkMessage::Ping(ping) => handle_ping(ping, writer)?,
+                NetworkMessage::Pong(pong) => {
+                    if let Some((nonce, sent)) = pending_ping {
+                        if pong.nonce == nonce {
+                            stats.ping = Some(sent.elapsed());
+                            pending_ping = None;
                         }
                     }
-                    
                 }
+                NetworkMessage::Inv(inv) => handle_inv(inv, id, &inventory, writer)?,
+                NetworkMessage::Headers(headers) => {
+                    handle_headers(headers, id, &chain, &store, &sender, writer, &mut sync)?
+                }
+                NetworkMessage::Block(block) => match backfill.take() {
+                    Some(walk) if walk.waiting_for == block.block_hash => {
+                        backfill = handle_backfilled_block(block, walk, &inventory, &store, writer, &sender)?;
+                    }
+                    walk => {
+                        backfill = walk;
+                        handle_block(block, &inventory, &chain, &store, &sender)?;
+                    }
+                },
+                NetworkMessage::NotFound(notfound) => {
+                    let missing = backfill.as_ref().is_some_and(|walk| {
+                        notfound.inventory.iter().any(|item| item.hash == walk.waiting_for.0)
+                    });
+                    if missing {
+                        backfill = None;
+                        sender.send(NetworkEvent::BackfillFinished)?;
+                    }
+                }
+                NetworkMessage::GetHeaders(getheaders) => handle_getheaders(getheaders, &chain, writer)?,
+                _ => {}
             }
         }
-
-        Ok(())
     }
 }
 
-fn handle_ping(nonce: u64, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-    let pong_command = Command::Pong.as_bytes();
-    let pong_length = 8u32.to_le_bytes();
-    let pong_checksum = bitcoin_checksum(&nonce.to_le_bytes());
-
-    let mut pong_message = Vec::new();
-    pong_message.extend_from_slice(&BITCOIN_MAGIC);
-    pong_message.extend_from_slice(&pong_command);
-    pong_message.extend_from_slice(&pong_length);
-    pong_message.extend_from_slice(&pong_checksum);
-    pong_message.extend_from_slice(&nonce.to_le_bytes());
+// The version and verack exchange, from sending our version to the peer's verack. Returns
+// what the peer told us about itself.
+fn exchange_versions<R: Read + ReadTimeout, W: Write>(
+    reader: &mut MessageReader<R>,
+    writer: &mut MessageWriter<W>,
+    version_message: VersionPayload,
+    peer_address: String,
+) -> Result<PeerInfo, Box<dyn Error>> {
+    let our_nonce = version_message.nonce;
+
+    send(writer, &NetworkMessage::Version(version_message))?;
+
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut state = HandshakeState::AwaitingVersion;
+
+    let peer_info = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(format!("Timed out {}", state.waiting_for()).into());
+        }
+        reader.get_ref().set_read_timeout(Some(remaining))?;
 
-    stream.write_all(&pong_message).unwrap();
-    //println!("Sent pong message with nonce {}", nonce);
+        let message = match reader.read_message() {
+            Ok(message) => message,
+            Err(e) if e.is_recoverable() => continue,
+            Err(e) if e.is_timeout() => return Err(format!("Timed out {}", state.waiting_for()).into()),
+            Err(e) => return Err(format!("Handshake failed {}: {}", state.waiting_for(), e).into()),
+        };
 
-    Ok(())
-}
+        // the version has to make sense, anything else we can't read is skipped like it is later on
+        let message = match parse_network_message(&message.command, &message.payload) {
+            Ok(message) => message,
+            Err(e) if message.command == "version" => return Err(format!("Could not read peer's version: {}", e).into()),
+            Err(_) => continue,
+        };
 
-fn handle_inv(payload: Vec<u8>, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-    let count = payload[0] as usize;
-    let mut start = 1;
+        state = match (state, message) {
+            (HandshakeState::AwaitingVersion, NetworkMessage::Version(version)) => {
+                if version.nonce == our_nonce {
+                    return Err("Connected to ourselves".into());
+                }
+                if version.version < MIN_PEER_VERSION {
+                    return Err(format!("Peer's protocol version {} is too old", version.version).into());
+                }
 
-    let mut hashes = Vec::new();
-    let mut inv_types = Vec::new();
+                send(writer, &NetworkMessage::Verack)?;
+                HandshakeState::AwaitingVerack(PeerInfo {
+                    address: peer_address.clone(),
+                    version: version.version,
+                    negotiated_version: version.version.min(PROTOCOL_VERSION),
+                    services: version.services,
+                    user_agent: String::from_utf8_lossy(&version.user_agent).into_owned(),
+                    start_height: version.start_height,
+                    relay: version.relay,
+                    wtxid_relay: false,
+                    addr_v2: false,
+                })
+            }
+            (HandshakeState::AwaitingVerack(_), NetworkMessage::Version(_)) => {
+                return Err("Peer sent a second version message".into())
+            }
+            (HandshakeState::AwaitingVersion, NetworkMessage::Verack) => {
+                return Err("Peer sent verack before its version".into())
+            }
+            (HandshakeState::AwaitingVerack(info), NetworkMessage::Verack) => break info,
+            // feature negotiation, only allowed between version and verack
+            (HandshakeState::AwaitingVerack(mut info), NetworkMessage::WtxidRelay) => {
+                info.wtxid_relay = true;
+                HandshakeState::AwaitingVerack(info)
+            }
+            (HandshakeState::AwaitingVerack(mut info), NetworkMessage::SendAddrV2) => {
+                info.addr_v2 = true;
+                HandshakeState::AwaitingVerack(info)
+            }
+            (state, NetworkMessage::Ping(ping)) => {
+                send(writer, &NetworkMessage::Pong(ping))?;
+                state
+            }
+            (state, _) => state,
+        };
+    };
 
-    for _ in 0..count {
-        let inv_type = u32::from_le_bytes(payload[start..start+4].try_into()?);
-        let hash = &payload[start+4..start+36];
+    reader.get_ref().set_read_timeout(None)?;
+    Ok(peer_info)
+}
 
-        if inv_type == 2 {
-            inv_types.push(inv_type);
-            hashes.push(hash);
-        }
-    
-        start += 36;
+fn net_addr(address: SocketAddr, services: u64) -> NetAddr {
+    NetAddr {
+        services,
+        ip_v6_4: address.ip().to_string(),
+        port: address.port(),
     }
+}
 
+fn send<W: Write>(writer: &mut MessageWriter<W>, message: &NetworkMessage) -> Result<(), Box<dyn Error>> {
+    let magic = writer.magic();
+    writer.write_message(&build_message(message, magic)?)?;
+    Ok(())
+}
 
-    // Only call send_getdata if there are blocks to request
-    if !hashes.is_empty() && !inv_types.is_empty() {
-        send_getdata(hashes, inv_types, stream)?;
-    }
-
+fn handle_ping(ping: PingPayload, writer: &mut MessageWriter<TcpStream>) -> Result<(), Box<dyn Error>> {
+    send(writer, &NetworkMessage::Pong(ping))
+}
 
-        // Check if the type is a transaction
-        // if inv_type == 1 {
-        //     let hash = &inv_vector[4..];
-        //     //println!("Transaction hash: {:?}", hash);
-        // }
-    
+fn handle_inv(
+    inv: InvPayload,
+    id: PeerId,
+    tracker: &Mutex<InventoryTracker>,
+    writer: &mut MessageWriter<TcpStream>,
+) -> Result<(), Box<dyn Error>> {
+    // only blocks are interesting for now, transactions get ignored. blocks another peer
+    // has already fetched or is fetching are left to them
+    let mut tracker = tracker.lock().unwrap();
+    let inventory: Vec<Inventory> = inv
+        .inventory
+        .into_iter()
+        .filter(|item| item.inv_type == InvType::Block && tracker.claim(item.hash, id))
+        .collect();
+    drop(tracker);
+
+    // Only send getdata if there are blocks to request
+    if !inventory.is_empty() {
+        send(writer, &NetworkMessage::GetData(InvPayload { inventory }))?;
+    }
 
     Ok(())
 }
 
-fn send_getdata(
-    hashes: Vec<&[u8]>,
-    inv_types: Vec<u32>,
-    stream: &mut TcpStream,
+fn handle_block(
+    block: BlockData,
+    tracker: &Mutex<InventoryTracker>,
+    chain: &Mutex<ChainIndex>,
+    store: &Mutex<BlockStore>,
+    sender: &Sender<NetworkEvent>,
 ) -> Result<(), Box<dyn Error>> {
-    let getdata_command = Command::GetData.as_bytes();
-
-    let mut getdata_payload = Vec::new();
+    // transactions that don't hash to the header's root were tampered with or mangled on the
+    // way, and a peer that sends those is no more use than one sending bad headers
+    if verify_merkle_root(&block) != Some(true) {
+        return Err(format!("Sent block {} whose transactions don't match its merkle root", block.block_hash).into());
+    }
 
-    // Add the count of inventory vectors to the payload
-    getdata_payload.push(hashes.len() as u8);
+    // a new block usually extends the tip, send its header on first so the ui knows its height
+    let mut chain = chain.lock().unwrap();
+    let update = match chain.add_headers(std::slice::from_ref(&block)) {
+        Ok(HeadersOutcome::Connected(update)) => update,
+        // nowhere to put it yet, the header sync will bring it in and it can be asked for again
+        Ok(HeadersOutcome::UnknownParent) => return Ok(()),
+        Err(e) => return Err(format!("Sent an invalid block: {}", e).into()),
+    };
+    save_new_headers(&mut chain, store, &update, sender)?;
+    drop(chain);
+    if !update.connected.is_empty() || !update.reorgs.is_empty() {
+        sender.send(NetworkEvent::Chain(update))?;
+    }
 
-    // Add each inventory vector to the payload
-    for (hash, inv_type) in hashes.iter().zip(inv_types.iter()) {
-        getdata_payload.extend(&inv_type.to_le_bytes());
-        getdata_payload.extend(*hash);
+    if tracker.lock().unwrap().deliver(block.block_hash.0) {
+        report_storage_error(store.lock().unwrap().save_block(&block), sender)?;
+        sender.send(NetworkEvent::Block(block))?;
     }
 
-    let getdata_length = (getdata_payload.len() as u32).to_le_bytes();
-    let getdata_checksum = bitcoin_checksum(&getdata_payload);
+    Ok(())
+}
 
-    let mut getdata_message = Vec::new();
-    getdata_message.extend_from_slice(&BITCOIN_MAGIC);
-    getdata_message.extend_from_slice(&getdata_command);
-    getdata_message.extend_from_slice(&getdata_length);
-    getdata_message.extend_from_slice(&getdata_checksum);
-    getdata_message.extend_from_slice(&getdata_payload);
+// Asks for the block at start, or reports the backfill finished straight away when there's
+// nothing to fetch because the last walk already reached genesis. Blocks already in the store
+// are read from there instead of being downloaded again.
+fn request_backfill(
+    mut start: BlockHash,
+    mut depth: u32,
+    store: &Mutex<BlockStore>,
+    writer: &mut MessageWriter<TcpStream>,
+    sender: &Sender<NetworkEvent>,
+) -> Result<Option<Backfill>, Box<dyn Error>> {
+    while depth > 0 && start != BlockHash::default() {
+        let block = match store.lock().unwrap().read_block(&start) {
+            Ok(Some(block)) => block,
+            // not stored, or stored but corrupt, either way the peer has to send it
+            _ => break,
+        };
+        start = BlockHash(block.prev_block_hash);
+        depth -= 1;
+        sender.send(NetworkEvent::BackfilledBlock(block))?;
+    }
 
-    stream.write_all(&getdata_message).unwrap();
-    //println!("Sent getdata message for hashes {:?}", hashes);
+    if depth == 0 || start == BlockHash::default() {
+        sender.send(NetworkEvent::BackfillFinished)?;
+        return Ok(None);
+    }
 
-    Ok(())
-}
+    let inventory = vec![Inventory { inv_type: InvType::Block, hash: start.0 }];
+    send(writer, &NetworkMessage::GetData(InvPayload { inventory }))?;
 
-fn handle_block(block: Vec<u8>, sender: &Sender<BlockData>) -> Result<(), Box<dyn Error>> {
-    // The block header is the first 80 bytes of the payload
-    let header = &block[0..80];
-
-    // The rest of the payload is the transactions
-    let transactions = &block[80..];
-
-    // Parse the header
-    let version = u32::from_le_bytes(header[0..4].try_into().unwrap());
-    let prev_block_hash: [u8; 32] = header[4..36].try_into().unwrap();
-    let merkle_root: [u8; 32] = header[36..68].try_into().unwrap();
-    let timestamp = u32::from_le_bytes(header[68..72].try_into().unwrap());
-    let bits = u32::from_le_bytes(header[72..76].try_into().unwrap());
-    let nonce = u32::from_le_bytes(header[76..80].try_into().unwrap());
-
-    //println!("Block header:");
-    //println!("Version: {}", version);
-    //println!("Previous block hash: {:?}", prev_block_hash);
-    //println!("Merkle root: {:?}", merkle_root);
-    //println!("Timestamp: {}", timestamp);
-    //println!("Bits: {}", bits);
-    //println!("Nonce: {}", nonce);
-
-    let parsed_transactions = parse_transaction(transactions)?;
-
-
-    let mut block = BlockData {
-        version,
-        prev_block_hash,
-        merkle_root,
-        timestamp,
-        bits,
-        nonce,
-        block_hash: vec![],
-        transactions: parsed_transactions,
-    };
+    Ok(Some(Backfill {
+        waiting_for: start,
+        requested: Instant::now(),
+        remaining: depth - 1,
+    }))
+}
 
-    sender.send(block).unwrap();
+fn handle_backfilled_block(
+    block: BlockData,
+    walk: Backfill,
+    tracker: &Mutex<InventoryTracker>,
+    store: &Mutex<BlockStore>,
+    writer: &mut MessageWriter<TcpStream>,
+    sender: &Sender<NetworkEvent>,
+) -> Result<Option<Backfill>, Box<dyn Error>> {
+    if verify_merkle_root(&block) != Some(true) {
+        return Err(format!("Sent block {} whose transactions don't match its merkle root", block.block_hash).into());
+    }
 
+    // counts as delivered so a peer announcing it late doesn't get it listed again as new
+    tracker.lock().unwrap().deliver(block.block_hash.0);
+    report_storage_error(store.lock().unwrap().save_block(&block), sender)?;
 
+    let previous = BlockHash(block.prev_block_hash);
+    sender.send(NetworkEvent::BackfilledBlock(block))?;
 
+    request_backfill(previous, walk.remaining, store, writer, sender)
+}
 
+// writes out the headers just indexed and where the active chain now runs
+fn save_new_headers(
+    chain: &mut ChainIndex,
+    store: &Mutex<BlockStore>,
+    update: &ChainUpdate,
+    sender: &Sender<NetworkEvent>,
+) -> Result<(), Box<dyn Error>> {
+    let headers = chain.take_new_headers();
+    let mut store = store.lock().unwrap();
+    if !headers.is_empty() {
+        report_storage_error(store.save_headers(&headers), sender)?;
+    }
+    if !update.connected.is_empty() {
+        report_storage_error(store.save_heights(&update.connected), sender)?;
+    }
     Ok(())
 }
 
-fn parse_getheaders(payload: Vec<u8>, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-    let version = u32::from_le_bytes(payload[0..4].try_into()?);
+// a failed write only means something gets downloaded again next run, so it's passed on to
+// the ui rather than dropping the peer over it
+fn report_storage_error(result: Result<(), Box<dyn Error>>, sender: &Sender<NetworkEvent>) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = result {
+        sender.send(NetworkEvent::StorageFailed(e.to_string()))?;
+    }
+    Ok(())
+}
 
-    let (count, offset) = read_var_int(payload[4..].to_vec())?;
-    let mut start = 4 + offset;
+fn handle_headers(
+    headers: HeadersPayload,
+    id: PeerId,
+    chain: &Mutex<ChainIndex>,
+    store: &Mutex<BlockStore>,
+    sender: &Sender<NetworkEvent>,
+    writer: &mut MessageWriter<TcpStream>,
+    sync: &mut HeaderSync,
+) -> Result<(), Box<dyn Error>> {
+    let mut chain = chain.lock().unwrap();
+
+    let outcome = match chain.add_headers(&headers.headers) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            // Headers that don't link up or don't meet their targets aren't a slow or flaky
+            // peer, they're a different chain or a bad one, so there's nothing to gain by
+            // staying connected. Let another peer carry on the sync.
+            sync.requested = None;
+            chain.release_sync(id);
+            drop(chain);
+            sender.send(NetworkEvent::HeadersRejected(id, e.to_string()))?;
+            return Err(format!("Sent invalid headers: {}", e).into());
+        }
+    };
 
-    let mut hashes = Vec::new();
-    let mut inv_types = Vec::new();
+    match outcome {
+        HeadersOutcome::Connected(update) => {
+            save_new_headers(&mut chain, store, &update, sender)?;
+            if sync.requested.is_some() {
+                if headers.headers.len() == MAX_HEADERS {
+                    request_headers(&chain, writer, sync)?;
+                } else {
+                    // a short batch means the peer has nothing more past it
+                    sync.requested = None;
+                    sync.caught_up = true;
+                    chain.release_sync(id);
+                }
+            }
 
-    for _ in 0..count {
-        let hash = &payload[start..start + 32];
-        hashes.push(hash);
-        inv_types.push(2);  // 2 represents a block
-        start += 32;
+            if !update.connected.is_empty() || !update.reorgs.is_empty() {
+                sender.send(NetworkEvent::Chain(update))?;
+            }
+        }
+        // an announcement we can't connect yet, fill in the gap unless another peer is already on it
+        HeadersOutcome::UnknownParent => {
+            if sync.requested.is_none() && chain.claim_sync(id) {
+                request_headers(&chain, writer, sync)?;
+            }
+        }
     }
 
-    let hash_stop: [u8; 32] = payload[start..start + 32].try_into()?;
+    Ok(())
+}
 
-    send_getdata(hashes, inv_types, stream)?;
+fn request_headers(chain: &ChainIndex, writer: &mut MessageWriter<TcpStream>, sync: &mut HeaderSync) -> Result<(), Box<dyn Error>> {
+    let getheaders = BlockLocatorPayload {
+        version: PROTOCOL_VERSION as u32,
+        locator_hashes: chain.locator(),
+        hash_stop: [0; 32],
+    };
+    send(writer, &NetworkMessage::GetHeaders(getheaders))?;
+    sync.requested = Some(Instant::now());
+    Ok(())
+}
 
-    // Now you have the version, block_locator_hashes, and hash_stop
-    // You can process them as needed
+fn handle_getheaders(
+    getheaders: BlockLocatorPayload,
+    chain: &Mutex<ChainIndex>,
+    writer: &mut MessageWriter<TcpStream>,
+) -> Result<(), Box<dyn Error>> {
+    let headers = chain
+        .lock()
+        .unwrap()
+        .headers_after(&getheaders.locator_hashes, getheaders.hash_stop);
 
-    Ok(())
+    send(writer, &NetworkMessage::Headers(HeadersPayload { headers }))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::serialise_message;
+    use std::io::Cursor;
 
-fn parse_transaction(data: &[u8]) -> Result<Transaction, Box<dyn Error>> {
-    let mut start = 0;
+    const PEER: &str = "127.0.0.1:8333";
 
-    let version = u32::from_le_bytes(data[start..start + 4].try_into()?);
-    start += 4;
+    // whatever the peer sent, then a read timeout once it's all been read
+    struct Scripted(Cursor<Vec<u8>>);
 
-    let input_count = data[start] as usize;
-    start += 1;
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::TimedOut.into()),
+                n => Ok(n),
+            }
+        }
+    }
 
-    let mut inputs = Vec::new();
-    for _ in 0..input_count {
-        let prev_tx_hash = data[start..start + 32].try_into()?;
-        start += 32;
+    impl ReadTimeout for Scripted {
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
-        let prev_output_index = u32::from_le_bytes(data[start..start + 4].try_into()?);
-        start += 4;
+    fn version(nonce: u64) -> VersionPayload {
+        let address: SocketAddr = PEER.parse().unwrap();
+        VersionPayload {
+            version: PROTOCOL_VERSION,
+            services: 1033,
+            timestamp: 1_700_000_000,
+            addr_recv: net_addr(address, 0),
+            addr_from: net_addr(address, 0),
+            nonce,
+            user_agent: b"/Satoshi:26.0.0/".to_vec(),
+            start_height: 820_000,
+            relay: true,
+        }
+    }
 
-        let script_length = data[start] as usize;
-        start += 1;
+    // runs our side of the handshake against a peer that sends these messages, and hands
+    // back what we sent along with the outcome
+    fn handshake_with(peer: &[NetworkMessage]) -> (Result<PeerInfo, Box<dyn Error>>, Vec<String>) {
+        let magic = Network::Mainnet.magic();
+        let mut bytes = Vec::new();
+        for message in peer {
+            bytes.extend(serialise_message(&build_message(message, magic).unwrap()).unwrap());
+        }
 
-        let script_sig = data[start..start + script_length].to_vec();
-        start += script_length;
+        let mut reader = MessageReader::new(Scripted(Cursor::new(bytes)), magic);
+        let mut written = Vec::new();
+        let mut writer = MessageWriter::new(&mut written, magic);
+        let result = exchange_versions(&mut reader, &mut writer, version(1), PEER.to_string());
 
-        let sequence = u32::from_le_bytes(data[start..start + 4].try_into()?);
-        start += 4;
+        let mut sent = MessageReader::new(Cursor::new(written), magic);
+        let mut commands = Vec::new();
+        while let Ok(message) = sent.read_message() {
+            commands.push(message.command);
+        }
+        (result, commands)
+    }
 
-        inputs.push(TransactionInput { prev_tx_hash, prev_output_index, script_sig, sequence });
+    #[test]
+    fn version_then_verack_completes() {
+        let (result, sent) = handshake_with(&[
+            NetworkMessage::Version(version(2)),
+            NetworkMessage::WtxidRelay,
+            NetworkMessage::SendAddrV2,
+            NetworkMessage::Verack,
+        ]);
+
+        let info = result.unwrap();
+        assert_eq!(info.address, PEER);
+        assert_eq!(info.user_agent, "/Satoshi:26.0.0/");
+        assert_eq!(info.start_height, 820_000);
+        assert!(info.wtxid_relay);
+        assert!(info.addr_v2);
+        assert_eq!(sent, ["version", "verack"]);
     }
 
-    let output_count = data[start] as usize;
-    start += 1;
+    #[test]
+    fn verack_before_version_is_refused() {
+        let (result, sent) = handshake_with(&[NetworkMessage::Verack, NetworkMessage::Version(version(2))]);
+
+        assert_eq!(result.unwrap_err().to_string(), "Peer sent verack before its version");
+        assert_eq!(sent, ["version"]);
+    }
 
-    let mut outputs = Vec::new();
-    for _ in 0..output_count {
-        let value = u64::from_le_bytes(data[start..start + 8].try_into()?);
-        start += 8;
+    #[test]
+    fn second_version_is_refused() {
+        let (result, sent) = handshake_with(&[
+            NetworkMessage::Version(version(2)),
+            NetworkMessage::Version(version(3)),
+            NetworkMessage::Verack,
+        ]);
 
-        let script_length = data[start] as usize;
-        start += 1;
+        assert_eq!(result.unwrap_err().to_string(), "Peer sent a second version message");
+        assert_eq!(sent, ["version", "verack"]);
+    }
 
-        let script_pub_key = data[start..start + script_length].to_vec();
-        start += script_length;
+    #[test]
+    fn our_own_version_is_refused() {
+        let (result, _) = handshake_with(&[NetworkMessage::Version(version(1))]);
 
-        outputs.push(TransactionOutput { value, script_pub_key });
+        assert_eq!(result.unwrap_err().to_string(), "Connected to ourselves");
     }
 
-    let locktime = u32::from_le_bytes(data[start..start + 4].try_into()?);
+    #[test]
+    fn silent_peer_times_out() {
+        let (result, _) = handshake_with(&[]);
+        assert_eq!(result.unwrap_err().to_string(), "Timed out waiting for the peer's version");
 
-    Ok(Transaction { version, inputs, outputs, locktime })
-}
\ No newline at end of file
+        let (result, _) = handshake_with(&[NetworkMessage::Version(version(2))]);
+        assert_eq!(result.unwrap_err().to_string(), "Timed out waiting for the peer's verack");
+    }
+}
@@ -0,0 +1,246 @@
+// finding a peer to talk to when one hasn't been configured
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use rand::seq::SliceRandom;
+
+use crate::network::Network;
+
+// lets the dns lookups be swapped out, e.g. for a canned list when testing offline
+pub trait Resolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+pub struct FailedAttempt {
+    pub target: String,
+    pub reason: String,
+}
+
+impl fmt::Display for FailedAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.target, self.reason)
+    }
+}
+
+#[derive(Debug)]
+pub struct DiscoveryError {
+    pub network: Network,
+    pub attempts: usize,
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.attempts == 0 {
+            write!(
+                f,
+                "Found no {} peers to connect to. Check your DNS settings, or pass one with --peer",
+                self.network.name()
+            )
+        } else {
+            write!(
+                f,
+                "None of the {} {} peers tried would complete a handshake. Check your DNS settings, or pass one with --peer",
+                self.attempts,
+                self.network.name()
+            )
+        }
+    }
+}
+
+impl Error for DiscoveryError {}
+
+// Resolves every dns seed for the network and adds the fixed seeds, shuffled so we don't
+// always pile onto the same node. Seeds that fail to resolve are reported through
+// on_failure rather than stopping the search.
+pub fn discover_candidates(
+    network: Network,
+    resolver: &dyn Resolver,
+    on_failure: &mut dyn FnMut(&FailedAttempt),
+) -> Vec<SocketAddr> {
+    let mut candidates = Vec::new();
+
+    for seed in network.dns_seeds() {
+        match resolver.resolve(seed, network.default_port()) {
+            Ok(addresses) => candidates.extend(addresses),
+            Err(e) => on_failure(&FailedAttempt {
+                target: seed.to_string(),
+                reason: format!("DNS lookup failed ({})", e),
+            }),
+        }
+    }
+
+    for seed in network.fixed_seeds() {
+        if let Ok(address) = seed.parse() {
+            candidates.push(address);
+        }
+    }
+
+    candidates.sort();
+    candidates.dedup();
+    candidates.shuffle(&mut rand::thread_rng());
+    candidates
+}
+
+// Calls attempt on each candidate in turn until one succeeds, at most max_attempts times.
+// attempt is expected to connect and handshake, so a peer that accepts the tcp connection
+// but speaks the wrong protocol still counts as a failure.
+pub fn try_candidates<T>(
+    network: Network,
+    candidates: &[SocketAddr],
+    max_attempts: usize,
+    mut attempt: impl FnMut(SocketAddr) -> Result<T, Box<dyn Error>>,
+    on_failure: &mut dyn FnMut(&FailedAttempt),
+) -> Result<T, DiscoveryError> {
+    let mut attempts = 0;
+
+    for address in candidates.iter().take(max_attempts) {
+        attempts += 1;
+        match attempt(*address) {
+            Ok(connected) => return Ok(connected),
+            Err(e) => on_failure(&FailedAttempt {
+                target: address.to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    Err(DiscoveryError { network, attempts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    // answers from a table, any host not in it fails to resolve. remembers what was asked
+    struct FakeResolver {
+        answers: HashMap<&'static str, Vec<&'static str>>,
+        asked: RefCell<Vec<String>>,
+    }
+
+    impl FakeResolver {
+        fn new(answers: &[(&'static str, Vec<&'static str>)]) -> FakeResolver {
+            FakeResolver {
+                answers: answers.iter().cloned().collect(),
+                asked: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Resolver for FakeResolver {
+        fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.asked.borrow_mut().push(format!("{}:{}", host, port));
+            match self.answers.get(host) {
+                Some(addresses) => Ok(addresses.iter().map(|address| address.parse().unwrap()).collect()),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            }
+        }
+    }
+
+    fn address(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn failing_seeds_are_reported_and_the_rest_still_asked() {
+        let seeds = Network::Mainnet.dns_seeds();
+        let resolver = FakeResolver::new(&[(seeds[1], vec!["1.2.3.4:8333"])]);
+
+        let mut failures = Vec::new();
+        let candidates = discover_candidates(Network::Mainnet, &resolver, &mut |failure| {
+            failures.push(failure.target.clone())
+        });
+
+        assert_eq!(resolver.asked.borrow().len(), seeds.len());
+        assert!(resolver.asked.borrow().iter().all(|asked| asked.ends_with(":8333")));
+        assert_eq!(failures.len(), seeds.len() - 1);
+        assert_eq!(failures[0], seeds[0]);
+        assert!(!failures.contains(&seeds[1].to_string()));
+        assert!(candidates.contains(&address("1.2.3.4:8333")));
+    }
+
+    #[test]
+    fn fixed_seeds_are_merged_without_duplicates() {
+        let seeds = Network::Mainnet.dns_seeds();
+        let fixed = Network::Mainnet.fixed_seeds()[0];
+        let resolver = FakeResolver::new(&[
+            (seeds[0], vec!["1.2.3.4:8333", fixed]),
+            (seeds[2], vec!["5.6.7.8:8333", "1.2.3.4:8333"]),
+        ]);
+
+        let mut candidates = discover_candidates(Network::Mainnet, &resolver, &mut |_| {});
+        candidates.sort();
+
+        let mut expected = vec![address("1.2.3.4:8333"), address("5.6.7.8:8333")];
+        expected.extend(Network::Mainnet.fixed_seeds().iter().map(|seed| address(seed)));
+        expected.sort();
+        assert_eq!(candidates, expected);
+    }
+
+    #[test]
+    fn fixed_seeds_alone_when_no_seed_resolves() {
+        let resolver = FakeResolver::new(&[]);
+        let candidates = discover_candidates(Network::Regtest, &resolver, &mut |_| {});
+        assert_eq!(candidates, vec![address("127.0.0.1:18444")]);
+    }
+
+    #[test]
+    fn stops_at_the_first_success() {
+        let candidates = [address("1.1.1.1:8333"), address("2.2.2.2:8333"), address("3.3.3.3:8333")];
+        let mut tried = Vec::new();
+        let mut failures = 0;
+
+        let connected = try_candidates(
+            Network::Mainnet,
+            &candidates,
+            10,
+            |address| {
+                tried.push(address);
+                if address == candidates[1] {
+                    Ok(address)
+                } else {
+                    Err("refused".into())
+                }
+            },
+            &mut |_| failures += 1,
+        );
+
+        assert_eq!(connected.unwrap(), candidates[1]);
+        assert_eq!(tried, &candidates[..2]);
+        assert_eq!(failures, 1);
+    }
+
+    #[test]
+    fn counts_attempts_when_every_candidate_fails() {
+        let candidates = [address("1.1.1.1:8333"), address("2.2.2.2:8333"), address("3.3.3.3:8333")];
+        let mut failures = Vec::new();
+
+        let error = try_candidates::<()>(
+            Network::Testnet,
+            &candidates,
+            2,
+            |_| Err("handshake timed out".into()),
+            &mut |failure| failures.push(failure.to_string()),
+        )
+        .unwrap_err();
+
+        assert_eq!(error.attempts, 2);
+        assert_eq!(error.network, Network::Testnet);
+        assert_eq!(failures, ["1.1.1.1:8333: handshake timed out", "2.2.2.2:8333: handshake timed out"]);
+
+        let error = try_candidates::<()>(Network::Testnet, &[], 2, |_| Ok(()), &mut |_| {}).unwrap_err();
+        assert_eq!(error.attempts, 0);
+    }
+}
@@ -1,3 +0,0 @@
-mod serialisers;
-mod structures;
-mod utils;
\ No newline at end of file
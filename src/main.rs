@@ -1,14 +1,37 @@
+use blkfiles::BlockFiles;
+use chain::ChainIndex;
+use config::Config;
 use connection::Connection;
+use discovery::{discover_candidates, try_candidates, FailedAttempt, SystemResolver};
+use offline::OfflineSource;
+use peer_manager::PeerManager;
 use ratatui::backend::{Backend, CrosstermBackend};
 use ratatui::Terminal;
-use structures::BlockData;
-use tui::{reduce, Action, AppState, CurrentScreen, Direction, TableType};
+use storage::{BlockStore, Corruption};
+use structures::{ChainUpdate, NetworkCommand, NetworkEvent};
+use tui::{reduce, Action, AppState, CurrentScreen, Direction, TableType, BLOCK_INFO_PAGES, MAX_LISTED_BLOCKS};
+mod address;
+mod blkfiles;
+mod chain;
+mod coinbase;
+mod codec;
+mod config;
 mod connection;
+mod discovery;
+mod merkle;
+mod network;
+mod offline;
+mod parsers;
+mod peer_manager;
+mod script;
 mod serialisers;
+mod stats;
+mod storage;
 mod structures;
 mod tui;
 mod ui;
 mod utils;
+mod validation;
 mod widgets;
 
 use crossterm::event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode};
@@ -16,112 +39,323 @@ use crossterm::execute;
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::{io, thread};
-use std::sync::mpsc::{self, Receiver};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::time::Duration;
 use ui::ui;
 
+const MAX_CONNECTION_ATTEMPTS: usize = 10;
+// how many peers to keep connected when they come from discovery
+const OUTBOUND_PEERS: usize = 8;
+const MAX_EVENTS_PER_FRAME: usize = 1000;
+// lines page up and page down move the transaction detail by
+const DETAIL_PAGE: u16 = 10;
+
 fn main() -> io::Result<()> {
 
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut app_state = AppState::new();
+    app_state.backfill_depth = config.backfill_depth;
+    app_state.network = config.network;
+
     let (sender, reciever) = mpsc::channel();
-    let mut connection = Connection::new();
-    let _ = connection.connect();
-    let _ = connection.handshake();
+    let (command_sender, command_receiver) = mpsc::channel();
+
+    // with a blocks directory there's no network at all, blocks come from core's files instead
+    let started = match &config.blocks_dir {
+        Some(dir) => start_offline(&config, dir, &mut app_state, sender, command_receiver),
+        None => start_network(&config, &mut app_state, sender, command_receiver),
+    };
+    if let Err(e) = started {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout(); // This is a special case. Normally using stdout is fine
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    // create app and run it
+    let res = run_app(&mut terminal, &mut app_state, reciever, command_sender);
+
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+
+    if let Err(e) = res {
+        println!("{}", e);
+    }
 
-    let _ = connection.handle_stream(sender);
-    // let handle = thread::spawn(move || {
-       
-    // });
-   
+    Ok(())
+}
 
-    // enable_raw_mode()?;
-    // let mut stdout = io::stdout(); // This is a special case. Normally using stdout is fine
-    // execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+// picks up from the store, connects to a first peer and starts the peer manager
+fn start_network(
+    config: &Config,
+    app_state: &mut AppState,
+    events: Sender<NetworkEvent>,
+    commands: Receiver<NetworkCommand>,
+) -> Result<(), Box<dyn Error>> {
+    // whatever the last run downloaded, so the explorer only has to catch up from there
+    let (mut store, headers) = BlockStore::open(&config.data_dir, config.network, &mut report_corruption)?;
+    let mut chain = ChainIndex::new(config.network);
+    let rejected = chain.restore(&headers);
+    if rejected > 0 {
+        eprintln!("Ignoring {} stored headers that don't connect to the chain", rejected);
+    }
+    // the height index only lags the headers if the last run stopped between writing the two
+    let tip_height = chain.tip_height();
+    let tip = chain.recent(1)[0].1.block_hash;
+    if store.block_hash_at(tip_height) != Some(tip) || store.block_hash_at(tip_height + 1).is_some() {
+        if let Err(e) = store.replace_heights(&chain.recent(usize::MAX)) {
+            eprintln!("Could not rebuild the height index: {}", e);
+        }
+    }
+    if !headers.is_empty() {
+        println!(
+            "Loaded {} headers and {} blocks from {}",
+            headers.len() - rejected,
+            store.block_count(),
+            store.dir().display()
+        );
+    }
 
-    // let backend = CrosstermBackend::new(stdout);
-    // let mut terminal = Terminal::new(backend)?;
+    let candidates = match config.peer {
+        Some(_) => Vec::new(),
+        None => {
+            println!("Looking for {} peers...", config.network.name());
+            discover_candidates(config.network, &SystemResolver, &mut report_failure)
+        }
+    };
 
-    // // create app and run it
-    // let mut app_state = AppState::new();
-    // let res = run_app(&mut terminal, &mut app_state, reciever);
+    // get one peer going before taking over the terminal so any errors are readable
+    let (address, connection) = open_connection(config, &candidates)?;
 
-    // handle.join().unwrap();
+    if let Some(info) = connection.peer_info() {
+        println!(
+            "Connected to {} ({}, protocol {} using {}, services {:#x}, height {}{})",
+            info.address,
+            info.user_agent,
+            info.version,
+            info.negotiated_version,
+            info.services,
+            info.start_height,
+            if info.relay { "" } else { ", not relaying transactions" }
+        );
+    }
+
+    // start the ui off with the stored tip, filling in the blocks we have in full
+    let recent = chain.recent(MAX_LISTED_BLOCKS);
+    let mut stored_blocks = Vec::new();
+    for (_, header) in &recent {
+        match store.read_block(&header.block_hash) {
+            Ok(Some(block)) => stored_blocks.push(block),
+            Ok(None) => {}
+            // it'll be stored again if it's downloaded again
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    reduce(app_state, Action::UpdateChain(ChainUpdate {
+        connected: recent,
+        reorgs: Vec::new(),
+        stale_branches: chain.stale_branches(),
+    }));
+    for block in stored_blocks {
+        reduce(app_state, Action::AddBlock(block));
+    }
 
-    // disable_raw_mode()?;
-    // execute!(
-    //     terminal.backend_mut(),
-    //     LeaveAlternateScreen,
-    //     DisableMouseCapture
-    // )?;
-    // terminal.show_cursor()?;
+    // the network side runs on its own threads and hands blocks to the ui over the channel,
+    // the manager fills out the rest of the peers in the background
+    let mut peer_manager = PeerManager::new(
+        config.network,
+        config.peer.clone(),
+        OUTBOUND_PEERS,
+        chain,
+        store,
+        events,
+        commands,
+    );
+    peer_manager.add_candidates(&candidates);
+    peer_manager.add_peer(address, connection);
+    thread::spawn(move || peer_manager.run());
+    Ok(())
+}
 
+fn start_offline(
+    config: &Config,
+    dir: &Path,
+    app_state: &mut AppState,
+    events: Sender<NetworkEvent>,
+    commands: Receiver<NetworkCommand>,
+) -> Result<(), Box<dyn Error>> {
+    let files = BlockFiles::open(dir, config.network)?;
+    println!(
+        "Reading {} block files from {}{}",
+        files.file_count(),
+        files.dir().display(),
+        if files.is_obfuscated() { " (xor obfuscated)" } else { "" }
+    );
+    app_state.offline_source = Some(files.dir().display().to_string());
 
+    let chain = ChainIndex::new(config.network);
+    let source = OfflineSource::new(files, chain, events, commands);
+    thread::spawn(move || source.run());
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, app_state: &mut AppState, receiver: Receiver<BlockData>) -> io::Result<bool> {
+fn report_failure(failure: &FailedAttempt) {
+    eprintln!("Skipping {}", failure);
+}
+
+fn report_corruption(corruption: &Corruption) {
+    eprintln!("Corrupt data in {}", corruption);
+}
+
+// uses the configured peer, or failing that tries the discovered candidates in turn
+fn open_connection(config: &Config, candidates: &[SocketAddr]) -> Result<(String, Connection), Box<dyn Error>> {
+    let connect = |peer: &str| -> Result<(String, Connection), Box<dyn Error>> {
+        let mut connection = Connection::new(config.network);
+        connection.connect(peer)?;
+        connection.handshake()?;
+        Ok((peer.to_string(), connection))
+    };
+
+    if let Some(peer) = &config.peer {
+        return connect(peer);
+    }
+
+    let connected = try_candidates(
+        config.network,
+        candidates,
+        MAX_CONNECTION_ATTEMPTS,
+        |address| {
+            println!("Trying {}", address);
+            connect(&address.to_string())
+        },
+        &mut report_failure,
+    )?;
+
+    Ok(connected)
+}
+
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    app_state: &mut AppState,
+    receiver: Receiver<NetworkEvent>,
+    commands: Sender<NetworkCommand>,
+) -> io::Result<bool> {
     loop {
         terminal.draw(|f| ui(f, app_state))?;
 
-        if let Ok(block) = receiver.try_recv() {
-            // Dispatch an action to add the block to the block list
-            let action = Action::AddBlock(block);
+        // peers report stats every second, so take everything that's queued up. reading core's
+        // block files can queue blocks faster than that, capped so the screen still redraws
+        for event in receiver.try_iter().take(MAX_EVENTS_PER_FRAME) {
+            let action = match event {
+                NetworkEvent::Block(block) => Action::AddBlock(block),
+                NetworkEvent::Chain(update) => Action::UpdateChain(update),
+                NetworkEvent::PeerConnected(id, info) => Action::AddPeer(id, info),
+                NetworkEvent::PeerUpdated(id, stats) => Action::UpdatePeer(id, stats),
+                NetworkEvent::PeerDisconnected(id) => Action::RemovePeer(id),
+                NetworkEvent::BackfilledBlock(block) => Action::AddHistoricalBlock(block),
+                NetworkEvent::BackfillFinished => Action::BackfillFinished,
+                NetworkEvent::HeadersRejected(id, reason) => Action::RejectHeaders(id, reason),
+                NetworkEvent::StorageFailed(reason) => Action::StorageFailed(reason),
+            };
             reduce(app_state, action);
         }
 
         // Key event handling
-        match app_state.current_screen {
-            CurrentScreen::Home => {
-                if event::poll(Duration::from_millis(100)).unwrap() {
-                    if let event::Event::Key(key) = event::read().unwrap() {
-                        let action = match key.code {
-                            KeyCode::Down | KeyCode::Up => {
-                                let direction = if key.code == KeyCode::Down {
-                                    Direction::Down
-                                } else {
-                                    Direction::Up
-                                };
-                                match app_state.active_table {
-                                    TableType::Block => Action::NavigateTable(direction, TableType::Block),
-                                    TableType::Transaction => Action::NavigateTable(direction, TableType::Transaction),
-                                }
-                            }
-                            KeyCode::Tab => Action::SwitchTable,
-                            KeyCode::BackTab => {
-                                // Switch to the previous panel
-                                let prev_panel = (app_state.selected_panel + 1) % 2; // Assuming you have 2 panels
-                                Action::SetPanel(prev_panel)
-                            }
-                            KeyCode::Char('q') => Action::SetScreen(CurrentScreen::Exit),
-                            _ => Action::DoNothing,
-                        };
-        
-                        reduce(app_state, action);
+        if let CurrentScreen::Exiting = app_state.current_screen {
+            break;
+        }
+        if !event::poll(Duration::from_millis(100)).unwrap() {
+            continue;
+        }
+        let key = match event::read().unwrap() {
+            Event::Key(key) => key,
+            _ => continue,
+        };
+
+        let action = match app_state.current_screen {
+            CurrentScreen::Home => match key.code {
+                // scrolling past the oldest block loads the ones before it
+                KeyCode::Down if app_state.at_bottom_of_blocks() => match app_state.backfill_command() {
+                    Some(command) if commands.send(command).is_ok() => Action::LoadMore(command),
+                    _ => Action::NavigateTable(Direction::Down, TableType::Block),
+                },
+                KeyCode::Down | KeyCode::Up => {
+                    let direction = if key.code == KeyCode::Down {
+                        Direction::Down
+                    } else {
+                        Direction::Up
+                    };
+                    match app_state.active_table {
+                        TableType::Block => Action::NavigateTable(direction, TableType::Block),
+                        TableType::Transaction => Action::NavigateTable(direction, TableType::Transaction),
                     }
                 }
-        
-
-            }
-            CurrentScreen::Exit => {
-
-                if event::poll(Duration::from_millis(100)).unwrap() {
-                    if let event::Event::Key(key) = event::read().unwrap() {
-                        let action = match key.code {
-                            KeyCode::Char('y') => Action::SetScreen(CurrentScreen::Exit),
-                            KeyCode::Char('n') => Action::SetScreen(CurrentScreen::Home),
-                            _ => Action::DoNothing,
-                        };
-    
-                        reduce(app_state, action);
-                    }
+                KeyCode::Enter => match app_state.active_table {
+                    TableType::Block => match app_state.block_table_state.selected() {
+                        Some(index) => Action::SelectBlock(index),
+                        None => Action::DoNothing,
+                    },
+                    TableType::Transaction => Action::OpenTransaction,
+                },
+                KeyCode::Tab => Action::SwitchTable,
+                KeyCode::BackTab => {
+                    // next page of the block info panel
+                    let next_page = (app_state.selected_panel + 1) % BLOCK_INFO_PAGES.len();
+                    Action::SetPanel(next_page)
                 }
-            }
-            CurrentScreen::Exiting => break,
-            _ => {}
-        }
-
-
-
+                KeyCode::Char('p') => Action::NextPeer,
+                KeyCode::Char('/') => Action::StartSearch,
+                KeyCode::Char('q') => Action::SetScreen(CurrentScreen::Exit),
+                _ => Action::DoNothing,
+            },
+            CurrentScreen::TransactionDetail => match key.code {
+                KeyCode::Up => Action::ScrollDetail(Direction::Up, 1),
+                KeyCode::Down => Action::ScrollDetail(Direction::Down, 1),
+                KeyCode::PageUp => Action::ScrollDetail(Direction::Up, DETAIL_PAGE),
+                KeyCode::PageDown => Action::ScrollDetail(Direction::Down, DETAIL_PAGE),
+                KeyCode::Esc | KeyCode::Backspace => Action::SetScreen(CurrentScreen::Home),
+                KeyCode::Char('q') => Action::SetScreen(CurrentScreen::Exit),
+                _ => Action::DoNothing,
+            },
+            // every character goes into the query, q included
+            CurrentScreen::AddressSearch => match key.code {
+                KeyCode::Char(character) => Action::SearchInput(character),
+                KeyCode::Backspace => Action::SearchBackspace,
+                KeyCode::Enter => Action::SubmitSearch,
+                KeyCode::Up => Action::NavigateResults(Direction::Up),
+                KeyCode::Down => Action::NavigateResults(Direction::Down),
+                KeyCode::Esc => Action::SetScreen(CurrentScreen::Home),
+                _ => Action::DoNothing,
+            },
+            CurrentScreen::Exit => match key.code {
+                KeyCode::Char('y') => Action::SetScreen(CurrentScreen::Exiting),
+                KeyCode::Char('n') => Action::SetScreen(CurrentScreen::Home),
+                _ => Action::DoNothing,
+            },
+            CurrentScreen::Exiting => Action::DoNothing,
+        };
+        reduce(app_state, action);
     }
 
     Ok(true)
@@ -0,0 +1,165 @@
+// merkle root calculation and inclusion proofs over a block's txids
+
+use crate::serialisers::sha256d;
+use crate::structures::BlockData;
+
+pub struct MerkleProof {
+    // position of the transaction in the block, its bits say which side each sibling is on
+    pub index: usize,
+    // sibling hashes from the leaf level up to just below the root
+    pub branch: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    // walks the branch back up from the txid, the result should equal the header's merkle root
+    pub fn root(&self, txid: [u8; 32]) -> [u8; 32] {
+        let mut hash = txid;
+        let mut index = self.index;
+
+        for sibling in &self.branch {
+            hash = if index & 1 == 0 {
+                hash_pair(&hash, sibling)
+            } else {
+                hash_pair(sibling, &hash)
+            };
+            index /= 2;
+        }
+
+        hash
+    }
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut concatenated = [0u8; 64];
+    concatenated[..32].copy_from_slice(left);
+    concatenated[32..].copy_from_slice(right);
+    sha256d(&concatenated)
+}
+
+// one level up the tree, an odd node out is paired with itself
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+pub fn compute_merkle_root(txids: &[[u8; 32]]) -> Option<[u8; 32]> {
+    if txids.is_empty() {
+        return None;
+    }
+
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    Some(level[0])
+}
+
+pub fn block_txids(block: &BlockData) -> Vec<[u8; 32]> {
+    block.transactions.iter().map(|transaction| transaction.txid).collect()
+}
+
+// None when there are no transactions to check, e.g. a block we only have the header for
+pub fn verify_merkle_root(block: &BlockData) -> Option<bool> {
+    compute_merkle_root(&block_txids(block)).map(|root| root == block.merkle_root)
+}
+
+pub fn merkle_proof(txids: &[[u8; 32]], index: usize) -> Option<MerkleProof> {
+    if index >= txids.len() {
+        return None;
+    }
+
+    let mut branch = Vec::new();
+    let mut level = txids.to_vec();
+    let mut position = index;
+
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        branch.push(*level.get(sibling).unwrap_or(&level[position]));
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof { index, branch })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // txids and roots are written byte reversed, the way explorers show them
+    fn hash(hex: &str) -> [u8; 32] {
+        let mut bytes: [u8; 32] = hex::decode(hex).unwrap().try_into().unwrap();
+        bytes.reverse();
+        bytes
+    }
+
+    // block 100000
+    fn block_100000_txids() -> Vec<[u8; 32]> {
+        [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]
+        .iter()
+        .map(|txid| hash(txid))
+        .collect()
+    }
+
+    #[test]
+    fn no_transactions_no_root() {
+        assert_eq!(compute_merkle_root(&[]), None);
+        assert!(merkle_proof(&[], 0).is_none());
+    }
+
+    #[test]
+    fn single_transaction_is_its_own_root() {
+        let txid = hash("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        assert_eq!(compute_merkle_root(&[txid]), Some(txid));
+
+        let proof = merkle_proof(&[txid], 0).unwrap();
+        assert!(proof.branch.is_empty());
+        assert_eq!(proof.root(txid), txid);
+    }
+
+    #[test]
+    fn matches_a_mainnet_block() {
+        let root = hash("f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766");
+        assert_eq!(compute_merkle_root(&block_100000_txids()), Some(root));
+    }
+
+    #[test]
+    fn odd_count_duplicates_the_last() {
+        let txids = &block_100000_txids()[..3];
+        let mut padded = txids.to_vec();
+        padded.push(txids[2]);
+        assert_eq!(compute_merkle_root(txids), compute_merkle_root(&padded));
+
+        let expected = hash_pair(&hash_pair(&txids[0], &txids[1]), &hash_pair(&txids[2], &txids[2]));
+        assert_eq!(compute_merkle_root(txids), Some(expected));
+    }
+
+    #[test]
+    fn every_proof_leads_back_to_the_root() {
+        for count in 1..=9 {
+            let txids: Vec<[u8; 32]> = (0..count).map(|i| sha256d(&[i as u8])).collect();
+            let root = compute_merkle_root(&txids).unwrap();
+
+            for (index, txid) in txids.iter().enumerate() {
+                let proof = merkle_proof(&txids, index).unwrap();
+                assert_eq!(proof.root(*txid), root, "{} of {}", index, count);
+            }
+            assert!(merkle_proof(&txids, count).is_none());
+        }
+    }
+
+    #[test]
+    fn proof_for_the_wrong_txid_misses() {
+        let txids = block_100000_txids();
+        let proof = merkle_proof(&txids, 1).unwrap();
+        assert_ne!(Some(proof.root(txids[2])), compute_merkle_root(&txids));
+    }
+}
@@ -0,0 +1,272 @@
+// per network constants: message magic, default port and where to find peers
+
+use crate::structures::{BlockData, BlockHash};
+use crate::utils::BITCOIN_MAGIC;
+
+// every network's genesis block has the same single coinbase, so the same merkle root
+const GENESIS_MERKLE_ROOT: [u8; 32] = [
+    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    pub fn from_name(name: &str) -> Option<Network> {
+        match name {
+            "mainnet" | "main" | "bitcoin" => Some(Network::Mainnet),
+            "testnet" | "test" | "testnet3" => Some(Network::Testnet),
+            "signet" => Some(Network::Signet),
+            "regtest" => Some(Network::Regtest),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => BITCOIN_MAGIC,
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Signet => [0x0a, 0x03, 0xcf, 0x40],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    pub fn default_port(self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet => 18333,
+            Network::Signet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
+
+    // the same seeds bitcoin core ships with
+    pub fn dns_seeds(self) -> &'static [&'static str] {
+        match self {
+            Network::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+            ],
+            Network::Testnet => &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+            ],
+            Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+            Network::Regtest => &[],
+        }
+    }
+
+    // version bytes for base58 addresses: (pay to pubkey hash, pay to script hash)
+    pub fn base58_prefixes(self) -> (u8, u8) {
+        match self {
+            Network::Mainnet => (0x00, 0x05),
+            Network::Testnet | Network::Signet | Network::Regtest => (0x6f, 0xc4),
+        }
+    }
+
+    // human readable part of segwit addresses
+    pub fn bech32_hrp(self) -> &'static str {
+        match self {
+            Network::Mainnet => "bc",
+            Network::Testnet | Network::Signet => "tb",
+            Network::Regtest => "bcrt",
+        }
+    }
+
+    // the easiest target a block can have, big endian. headers whose bits decode to anything
+    // higher are invalid whatever their hash
+    pub fn pow_limit(self) -> [u8; 32] {
+        let mut limit = [0xff; 32];
+        match self {
+            // 2^224 - 1
+            Network::Mainnet | Network::Testnet => limit[..4].fill(0),
+            Network::Signet => {
+                limit = [0; 32];
+                limit[2..5].copy_from_slice(&[0x03, 0x77, 0xae]);
+            }
+            // 2^255 - 1
+            Network::Regtest => limit[0] = 0x7f,
+        }
+        limit
+    }
+
+    // Whether difficulty only changes at retargets. Testnet lets a block drop to the minimum
+    // once 20 minutes pass without one, and regtest blocks are all at the minimum anyway.
+    pub fn retargets_strictly(self) -> bool {
+        matches!(self, Network::Mainnet | Network::Signet)
+    }
+
+    // first block whose coinbase has to start with its height
+    pub fn bip34_height(self) -> u32 {
+        match self {
+            Network::Mainnet => 227_931,
+            Network::Testnet => 21_111,
+            Network::Signet | Network::Regtest => 1,
+        }
+    }
+
+    // blocks between each halving of the block subsidy
+    pub fn halving_interval(self) -> u32 {
+        match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        }
+    }
+
+    // the header every chain starts from, the only one we take on trust
+    pub fn genesis_header(self) -> BlockData {
+        let (timestamp, bits, nonce) = match self {
+            Network::Mainnet => (1231006505, 0x1d00ffff, 2083236893),
+            Network::Testnet => (1296688602, 0x1d00ffff, 414098458),
+            Network::Signet => (1598918400, 0x1e0377ae, 52613770),
+            Network::Regtest => (1296688602, 0x207fffff, 2),
+        };
+
+        let mut header = BlockData {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: GENESIS_MERKLE_ROOT,
+            timestamp,
+            bits,
+            nonce,
+            block_hash: BlockHash::default(),
+            transactions: Vec::new(),
+        };
+        header.calculate_hash();
+        header
+    }
+
+    // fallback for when none of the dns seeds answer. long running nodes out of bitcoin core's
+    // contrib/seeds lists, which get regenerated every release or so as nodes come and go
+    pub fn fixed_seeds(self) -> &'static [&'static str] {
+        match self {
+            Network::Mainnet => &MAINNET_FIXED_SEEDS,
+            Network::Testnet => &TESTNET_FIXED_SEEDS,
+            Network::Signet => &SIGNET_FIXED_SEEDS,
+            Network::Regtest => &["127.0.0.1:18444"],
+        }
+    }
+}
+
+const MAINNET_FIXED_SEEDS: [&str; 18] = [
+    "1.65.195.98:8333",
+    "2.59.236.56:8333",
+    "2.83.114.20:8333",
+    "2.248.194.16:8333",
+    "5.2.154.6:8333",
+    "5.101.140.30:8333",
+    "5.128.87.126:8333",
+    "5.144.21.49:8333",
+    "5.172.132.104:8333",
+    "5.188.62.18:8333",
+    "8.129.184.255:8333",
+    "8.209.105.138:8333",
+    "12.34.98.148:8333",
+    "14.199.102.151:8333",
+    "18.27.79.17:8333",
+    "18.216.249.151:8333",
+    "23.88.155.58:8333",
+    "23.93.101.158:8333",
+];
+
+const TESTNET_FIXED_SEEDS: [&str; 6] = [
+    "5.19.5.127:18333",
+    "5.189.158.246:18333",
+    "13.231.20.249:18333",
+    "18.191.253.246:18333",
+    "34.209.237.242:18333",
+    "54.39.203.141:18333",
+];
+
+// the default signet's own seed node, core lists it alongside the dns seeds
+const SIGNET_FIXED_SEEDS: [&str; 1] = ["178.128.221.177:38333"];
+
+// service bits from the version message, by the names bitcoin core gives them
+const SERVICE_NAMES: [(u64, &str); 7] = [
+    (1 << 0, "NODE_NETWORK"),
+    (1 << 1, "NODE_GETUTXO"),
+    (1 << 2, "NODE_BLOOM"),
+    (1 << 3, "NODE_WITNESS"),
+    (1 << 6, "NODE_COMPACT_FILTERS"),
+    (1 << 10, "NODE_NETWORK_LIMITED"),
+    (1 << 11, "NODE_P2P_V2"),
+];
+
+// bits we don't have a name for come out as "bit N"
+pub fn service_names(services: u64) -> Vec<String> {
+    let mut names = Vec::new();
+
+    for bit in 0..64 {
+        let flag = 1u64 << bit;
+        if services & flag == 0 {
+            continue;
+        }
+        match SERVICE_NAMES.iter().find(|(known, _)| *known == flag) {
+            Some((_, name)) => names.push(name.to_string()),
+            None => names.push(format!("bit {}", bit)),
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const NETWORKS: [Network; 4] = [Network::Mainnet, Network::Testnet, Network::Signet, Network::Regtest];
+
+    #[test]
+    fn every_network_has_fixed_seeds_on_its_port() {
+        for network in NETWORKS {
+            assert!(!network.fixed_seeds().is_empty(), "{:?}", network);
+            for seed in network.fixed_seeds() {
+                let address: SocketAddr = seed.parse().unwrap();
+                assert_eq!(address.port(), network.default_port(), "{}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn service_bits_are_named() {
+        // what a current bitcoin core node advertises
+        assert_eq!(
+            service_names(0x0c09),
+            vec!["NODE_NETWORK", "NODE_WITNESS", "NODE_NETWORK_LIMITED", "NODE_P2P_V2"]
+        );
+        assert_eq!(service_names(0x44), vec!["NODE_BLOOM", "NODE_COMPACT_FILTERS"]);
+        assert!(service_names(0).is_empty());
+    }
+
+    #[test]
+    fn unknown_service_bits_are_numbered() {
+        assert_eq!(service_names(1 << 24 | 1), vec!["NODE_NETWORK", "bit 24"]);
+        assert_eq!(service_names(1 << 63), vec!["bit 63"]);
+        assert_eq!(service_names(u64::MAX).len(), 64);
+    }
+}
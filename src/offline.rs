@@ -0,0 +1,223 @@
+// stands in for the peers when exploring a bitcoin core data directory offline. blocks come
+// out of the blk files and go to the ui as the same events a live connection sends
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::blkfiles::{BlockFilePosition, BlockFiles};
+use crate::chain::{ChainIndex, HeadersOutcome};
+use crate::parsers::parse_block;
+use crate::structures::{BlockData, BlockHash, ChainUpdate, NetworkCommand, NetworkEvent};
+
+// far more than core ever writes out of order. past it the parents aren't in the files at all,
+// a pruned data directory for one, and the oldest headers are the least likely to connect
+const MAX_ORPHANS: usize = 10_000;
+
+pub struct OfflineSource {
+    files: BlockFiles,
+    chain: ChainIndex,
+    // every block read so far, so backfills can go straight to it
+    positions: HashMap<BlockHash, BlockFilePosition>,
+    // core writes blocks in the order they arrive, which isn't always parent first. headers
+    // wait here, keyed by the parent they need, until it turns up
+    orphans: HashMap<[u8; 32], Vec<BlockData>>,
+    orphan_count: usize,
+    // (parent, hash) of each orphan oldest first, including some that have since connected
+    orphan_order: VecDeque<([u8; 32], BlockHash)>,
+    events: Sender<NetworkEvent>,
+    commands: Receiver<NetworkCommand>,
+}
+
+impl OfflineSource {
+    pub fn new(
+        files: BlockFiles,
+        chain: ChainIndex,
+        events: Sender<NetworkEvent>,
+        commands: Receiver<NetworkCommand>,
+    ) -> OfflineSource {
+        OfflineSource {
+            files,
+            chain,
+            positions: HashMap::new(),
+            orphans: HashMap::new(),
+            orphan_count: 0,
+            orphan_order: VecDeque::new(),
+            events,
+            commands,
+        }
+    }
+
+    // Reads every file in order, then carries on answering backfills until the ui goes away.
+    // Blocks that won't parse are skipped, like a bad message from a peer.
+    pub fn run(mut self) {
+        for file in 0..self.files.file_count() {
+            let blocks = match self.files.read_file(file) {
+                Ok(blocks) => blocks,
+                Err(_) => continue,
+            };
+
+            for raw in blocks {
+                while let Ok(command) = self.commands.try_recv() {
+                    self.handle_command(command);
+                }
+
+                let block = match parse_block(&raw.bytes) {
+                    Ok(block) => block,
+                    Err(_) => continue,
+                };
+                self.positions.insert(block.block_hash, raw.position);
+
+                let update = self.connect(&block);
+                if !update.connected.is_empty() || !update.reorgs.is_empty() {
+                    let _ = self.events.send(NetworkEvent::Chain(update));
+                }
+                if self.events.send(NetworkEvent::Block(block)).is_err() {
+                    return;
+                }
+            }
+        }
+
+        // every file has been read, so whatever is still waiting will never connect
+        self.orphans = HashMap::new();
+        self.orphan_count = 0;
+        self.orphan_order = VecDeque::new();
+
+        while let Ok(command) = self.commands.recv() {
+            self.handle_command(command);
+        }
+    }
+
+    // adds the block's header to the chain, along with any orphans that were waiting on it
+    fn connect(&mut self, block: &BlockData) -> ChainUpdate {
+        let mut update = ChainUpdate::default();
+        let mut pending = vec![BlockData { transactions: Vec::new(), ..*block }];
+
+        while let Some(header) = pending.pop() {
+            if self.chain.contains(&header.block_hash) {
+                continue;
+            }
+            match self.chain.add_headers(std::slice::from_ref(&header)) {
+                Ok(HeadersOutcome::Connected(added)) => {
+                    update.append(added);
+                    if let Some(children) = self.orphans.remove(&header.block_hash.0) {
+                        self.orphan_count -= children.len();
+                        pending.extend(children);
+                    }
+                }
+                Ok(HeadersOutcome::UnknownParent) => self.add_orphan(header),
+                // fails proof of work, nothing can build on it either
+                Err(_) => {}
+            }
+        }
+
+        // there's no store to write them to offline
+        self.chain.take_new_headers();
+        update
+    }
+
+    fn add_orphan(&mut self, header: BlockData) {
+        if self.orphan_count >= MAX_ORPHANS {
+            self.evict_oldest_orphan();
+        }
+        // drop the ones that connected before the queue gets much longer than the orphans
+        if self.orphan_order.len() >= 2 * MAX_ORPHANS {
+            let orphans = &self.orphans;
+            self.orphan_order.retain(|(parent, hash)| is_orphan(orphans, parent, hash));
+        }
+
+        self.orphan_count += 1;
+        self.orphan_order.push_back((header.prev_block_hash, header.block_hash));
+        self.orphans.entry(header.prev_block_hash).or_default().push(header);
+    }
+
+    fn evict_oldest_orphan(&mut self) {
+        while let Some((parent, hash)) = self.orphan_order.pop_front() {
+            let children = match self.orphans.get_mut(&parent) {
+                Some(children) => children,
+                None => continue,
+            };
+            if let Some(index) = children.iter().position(|child| child.block_hash == hash) {
+                children.remove(index);
+                if children.is_empty() {
+                    self.orphans.remove(&parent);
+                }
+                self.orphan_count -= 1;
+                return;
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: NetworkCommand) {
+        match command {
+            NetworkCommand::Backfill { mut start, depth } => {
+                for _ in 0..depth {
+                    // blocks further back than that either haven't been read yet or aren't in the files
+                    let block = match self.positions.get(&start).map(|position| self.files.read_block(position)) {
+                        Some(Ok(block)) => block,
+                        _ => break,
+                    };
+                    start = BlockHash(block.prev_block_hash);
+                    let _ = self.events.send(NetworkEvent::BackfilledBlock(block));
+                }
+                let _ = self.events.send(NetworkEvent::BackfillFinished);
+            }
+        }
+    }
+}
+
+fn is_orphan(orphans: &HashMap<[u8; 32], Vec<BlockData>>, parent: &[u8; 32], hash: &BlockHash) -> bool {
+    orphans.get(parent).is_some_and(|children| children.iter().any(|child| child.block_hash == *hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blkfiles::tests::TempDir;
+    use crate::network::Network;
+    use std::sync::mpsc::channel;
+
+    fn source(dir: &TempDir) -> OfflineSource {
+        let blocks = dir.0.join("blocks");
+        std::fs::create_dir_all(&blocks).unwrap();
+        std::fs::write(blocks.join("blk00000.dat"), []).unwrap();
+        let files = BlockFiles::open(&dir.0, Network::Regtest).unwrap();
+
+        let (events, _) = channel();
+        let (_, commands) = channel();
+        OfflineSource::new(files, ChainIndex::new(Network::Regtest), events, commands)
+    }
+
+    #[test]
+    fn orphans_are_bounded() {
+        let dir = TempDir::new("orphans");
+        let mut source = source(&dir);
+        let genesis = Network::Regtest.genesis_header();
+
+        // pairs of siblings, so evicting one leaves the other waiting on the same parent
+        let orphan = |n: u32| {
+            let mut orphan = genesis.clone();
+            orphan.prev_block_hash = [0xab; 32];
+            orphan.prev_block_hash[..4].copy_from_slice(&(n / 2).to_le_bytes());
+            orphan.block_hash.0[..4].copy_from_slice(&n.to_le_bytes());
+            orphan
+        };
+        let waiting = |source: &OfflineSource, n: u32| {
+            let orphan = orphan(n);
+            is_orphan(&source.orphans, &orphan.prev_block_hash, &orphan.block_hash)
+        };
+
+        for n in 0..MAX_ORPHANS as u32 + 11 {
+            source.connect(&orphan(n));
+        }
+
+        assert_eq!(source.orphan_count, MAX_ORPHANS);
+        assert_eq!(source.orphans.values().map(Vec::len).sum::<usize>(), MAX_ORPHANS);
+        // the oldest went first
+        assert!(!waiting(&source, 0));
+        assert!(!waiting(&source, 10));
+        assert!(waiting(&source, 11));
+        assert!(waiting(&source, MAX_ORPHANS as u32 + 10));
+        assert!(!source.orphans.contains_key(&orphan(8).prev_block_hash));
+        assert_eq!(source.orphans[&orphan(10).prev_block_hash].len(), 1);
+    }
+}
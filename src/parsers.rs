@@ -0,0 +1,766 @@
+// the other half of serialisers.rs, turns payload bytes back into structures
+
+use std::error::Error;
+use std::net::Ipv6Addr;
+
+use crate::serialisers::sha256d;
+use crate::structures::{
+    AddrEntry, AddrPayload, AddrV2Entry, AddrV2Payload, BlockData, BlockHash, BlockLocatorPayload,
+    FeeFilterPayload, HeadersPayload, InvPayload, InvType, Inventory, NetAddr, NetworkMessage,
+    PingPayload, RejectPayload, SendCmpctPayload, Transaction, TransactionInput,
+    TransactionOutput, VersionPayload,
+};
+use crate::utils::ByteReader;
+
+pub fn parse_network_message(command: &str, payload: &[u8]) -> Result<NetworkMessage, Box<dyn Error>> {
+    let message = match command {
+        "version" => NetworkMessage::Version(parse_version(payload)?),
+        "verack" => NetworkMessage::Verack,
+        "ping" => NetworkMessage::Ping(parse_ping(payload)?),
+        "pong" => NetworkMessage::Pong(parse_ping(payload)?),
+        "inv" => NetworkMessage::Inv(parse_inv(payload)?),
+        "getdata" => NetworkMessage::GetData(parse_inv(payload)?),
+        "notfound" => NetworkMessage::NotFound(parse_inv(payload)?),
+        "getblocks" => NetworkMessage::GetBlocks(parse_block_locator(payload)?),
+        "getheaders" => NetworkMessage::GetHeaders(parse_block_locator(payload)?),
+        "headers" => NetworkMessage::Headers(parse_headers(payload)?),
+        "block" => NetworkMessage::Block(parse_block(payload)?),
+        "tx" => NetworkMessage::Tx(parse_tx(payload)?),
+        "addr" => NetworkMessage::Addr(parse_addr(payload)?),
+        "addrv2" => NetworkMessage::AddrV2(parse_addrv2(payload)?),
+        "sendheaders" => NetworkMessage::SendHeaders,
+        "sendcmpct" => NetworkMessage::SendCmpct(parse_sendcmpct(payload)?),
+        "feefilter" => NetworkMessage::FeeFilter(FeeFilterPayload { feerate: ByteReader::new(payload).read_u64()? }),
+        "wtxidrelay" => NetworkMessage::WtxidRelay,
+        "sendaddrv2" => NetworkMessage::SendAddrV2,
+        "mempool" => NetworkMessage::MemPool,
+        "reject" => NetworkMessage::Reject(parse_reject(payload)?),
+        _ => NetworkMessage::Unknown {
+            command: command.to_string(),
+            payload: payload.to_vec(),
+        },
+    };
+
+    Ok(message)
+}
+
+fn parse_network_address(reader: &mut ByteReader) -> Result<NetAddr, Box<dyn Error>> {
+    let services = reader.read_u64()?;
+    let ip = Ipv6Addr::from(reader.read_array::<16>()?);
+    let port = reader.read_u16_be()?;
+
+    // ipv4 addresses come back out in their usual dotted form
+    let ip_v6_4 = match ip.to_ipv4_mapped() {
+        Some(ipv4) => ipv4.to_string(),
+        None => ip.to_string(),
+    };
+
+    Ok(NetAddr {
+        services,
+        ip_v6_4,
+        port,
+    })
+}
+
+pub fn parse_version(payload: &[u8]) -> Result<VersionPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+
+    let version = reader.read_i32()?;
+    let services = reader.read_u64()?;
+    let timestamp = reader.read_i64()?;
+    let addr_recv = parse_network_address(&mut reader)?;
+    let addr_from = parse_network_address(&mut reader)?;
+    let nonce = reader.read_u64()?;
+    let user_agent = reader.read_var_bytes()?;
+    let start_height = reader.read_i32()?;
+
+    // the relay flag was added in 70001, older peers leave it off and always relay
+    let relay = if reader.is_empty() { true } else { reader.read_u8()? != 0 };
+
+    Ok(VersionPayload {
+        version,
+        services,
+        timestamp,
+        addr_recv,
+        addr_from,
+        nonce,
+        user_agent,
+        start_height,
+        relay,
+    })
+}
+
+fn parse_ping(payload: &[u8]) -> Result<PingPayload, Box<dyn Error>> {
+    Ok(PingPayload { nonce: ByteReader::new(payload).read_u64()? })
+}
+
+pub fn parse_inv(payload: &[u8]) -> Result<InvPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let count = reader.read_compact_size()?;
+
+    let mut inventory = Vec::new();
+    for _ in 0..count {
+        let inv_type = InvType::from_u32(reader.read_u32()?);
+        let hash = reader.read_hash()?;
+        inventory.push(Inventory { inv_type, hash });
+    }
+
+    Ok(InvPayload { inventory })
+}
+
+pub fn parse_block_locator(payload: &[u8]) -> Result<BlockLocatorPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let version = reader.read_u32()?;
+    let count = reader.read_compact_size()?;
+
+    let mut locator_hashes = Vec::new();
+    for _ in 0..count {
+        locator_hashes.push(reader.read_hash()?);
+    }
+
+    let hash_stop = reader.read_hash()?;
+
+    Ok(BlockLocatorPayload { version, locator_hashes, hash_stop })
+}
+
+pub fn parse_block_header(reader: &mut ByteReader) -> Result<BlockData, Box<dyn Error>> {
+    let mut block = BlockData {
+        version: reader.read_u32()?,
+        prev_block_hash: reader.read_hash()?,
+        merkle_root: reader.read_hash()?,
+        timestamp: reader.read_u32()?,
+        bits: reader.read_u32()?,
+        nonce: reader.read_u32()?,
+        block_hash: BlockHash::default(),
+        transactions: vec![],
+    };
+    block.calculate_hash();
+
+    Ok(block)
+}
+
+pub fn parse_headers(payload: &[u8]) -> Result<HeadersPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let count = reader.read_compact_size()?;
+
+    let mut headers = Vec::new();
+    for _ in 0..count {
+        let header = parse_block_header(&mut reader)?;
+        // each header is followed by a transaction count, which has to be zero
+        let transaction_count = reader.read_compact_size()?;
+        if transaction_count != 0 {
+            return Err(format!("header {} has a transaction count of {}", header.block_hash, transaction_count).into());
+        }
+        headers.push(header);
+    }
+
+    Ok(HeadersPayload { headers })
+}
+
+pub fn parse_block(payload: &[u8]) -> Result<BlockData, Box<dyn Error>> {
+    // The block header is the first 80 bytes of the payload
+    let mut reader = ByteReader::new(payload);
+    let mut block = parse_block_header(&mut reader)?;
+
+    // The rest of the payload is the transaction count and then the transactions back to back
+    let transaction_count = reader.read_compact_size()?;
+    for _ in 0..transaction_count {
+        block.transactions.push(parse_transaction(&mut reader)?);
+    }
+
+    if !reader.is_empty() {
+        return Err(format!("{} trailing bytes after the last transaction", reader.remaining()).into());
+    }
+
+    Ok(block)
+}
+
+// a tx message is one transaction and nothing after it
+fn parse_tx(payload: &[u8]) -> Result<Transaction, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let transaction = parse_transaction(&mut reader)?;
+
+    if !reader.is_empty() {
+        return Err(format!("{} trailing bytes after the transaction", reader.remaining()).into());
+    }
+
+    Ok(transaction)
+}
+
+pub fn parse_transaction(reader: &mut ByteReader) -> Result<Transaction, Box<dyn Error>> {
+    let transaction_start = reader.position();
+
+    let version = reader.read_u32()?;
+
+    // BIP144: a zero marker where the input count would be, followed by the flag
+    let segwit = reader.peek(2) == Some(&[0x00, 0x01][..]);
+    if segwit {
+        reader.read_slice(2)?;
+    }
+    let inputs_start = reader.position();
+
+    let input_count = reader.read_compact_size()?;
+
+    let mut inputs = Vec::new();
+    for _ in 0..input_count {
+        let prev_tx_hash = reader.read_hash()?;
+        let prev_output_index = reader.read_u32()?;
+        let script_sig = reader.read_var_bytes()?;
+        let sequence = reader.read_u32()?;
+
+        inputs.push(TransactionInput { prev_tx_hash, prev_output_index, script_sig, sequence, witness: vec![] });
+    }
+
+    let output_count = reader.read_compact_size()?;
+
+    let mut outputs = Vec::new();
+    for _ in 0..output_count {
+        let value = reader.read_u64()?;
+        let script_pub_key = reader.read_var_bytes()?;
+
+        outputs.push(TransactionOutput { value, script_pub_key });
+    }
+    let outputs_end = reader.position();
+
+    // one witness stack per input, each a list of var_bytes items
+    if segwit {
+        for input in inputs.iter_mut() {
+            let item_count = reader.read_compact_size()?;
+            for _ in 0..item_count {
+                input.witness.push(reader.read_var_bytes()?);
+            }
+        }
+
+        if inputs.iter().all(|input| input.witness.is_empty()) {
+            return Err("segwit transaction has no witness data".into());
+        }
+    }
+
+    let locktime = reader.read_u32()?;
+    let transaction_end = reader.position();
+
+    // the txid leaves out the marker, flag and witnesses, the wtxid covers everything
+    let data = reader.get_ref();
+    let wtxid = sha256d(&data[transaction_start..transaction_end]);
+    let (txid, stripped_size) = if segwit {
+        let mut stripped = data[transaction_start..transaction_start + 4].to_vec();
+        stripped.extend(&data[inputs_start..outputs_end]);
+        stripped.extend(&data[transaction_end - 4..transaction_end]);
+        (sha256d(&stripped), stripped.len())
+    } else {
+        (wtxid, transaction_end - transaction_start)
+    };
+
+    Ok(Transaction {
+        version,
+        inputs,
+        outputs,
+        locktime,
+        txid,
+        wtxid,
+        size: transaction_end - transaction_start,
+        stripped_size,
+    })
+}
+
+pub fn parse_addr(payload: &[u8]) -> Result<AddrPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let count = reader.read_compact_size()?;
+
+    let mut addresses = Vec::new();
+    for _ in 0..count {
+        let time = reader.read_u32()?;
+        let addr = parse_network_address(&mut reader)?;
+        addresses.push(AddrEntry { time, addr });
+    }
+
+    Ok(AddrPayload { addresses })
+}
+
+pub fn parse_addrv2(payload: &[u8]) -> Result<AddrV2Payload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let count = reader.read_compact_size()?;
+
+    let mut addresses = Vec::new();
+    for _ in 0..count {
+        let time = reader.read_u32()?;
+        let services = reader.read_compact_size()?;
+        let network_id = reader.read_u8()?;
+        let addr = reader.read_var_bytes()?;
+        let port = reader.read_u16_be()?;
+        addresses.push(AddrV2Entry { time, services, network_id, addr, port });
+    }
+
+    Ok(AddrV2Payload { addresses })
+}
+
+fn parse_sendcmpct(payload: &[u8]) -> Result<SendCmpctPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let announce = reader.read_u8()? != 0;
+    let version = reader.read_u64()?;
+
+    Ok(SendCmpctPayload { announce, version })
+}
+
+pub fn parse_reject(payload: &[u8]) -> Result<RejectPayload, Box<dyn Error>> {
+    let mut reader = ByteReader::new(payload);
+    let message = reader.read_var_str()?;
+    let code = reader.read_u8()?;
+    let reason = reader.read_var_str()?;
+    let data = reader.read_remaining();
+
+    Ok(RejectPayload { message, code, reason, data })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::codec::{serialise_message, MessageReader};
+    use crate::serialisers::{
+        build_message, serialise_block, serialise_network_message, serialise_transaction, serialise_version_message,
+    };
+    use crate::utils::write_var_int;
+    use crate::structures::{AddrEntry, AddrV2Entry, FeeFilterPayload, InvType};
+    use crate::utils::{reversed_hex, BITCOIN_MAGIC};
+
+    // version message from the bitcoin developer reference, sent by a 0.9.3 node
+    const VERSION_70002: &str = "721101000100000000000000bc8f5e5400000000\
+        010000000000000000000000000000000000ffffc61b6409208d\
+        010000000000000000000000000000000000ffffcb0071c0208d\
+        128035cbc97953f80f2f5361746f7368693a302e392e332fcf05050001";
+
+    // version message from the protocol wiki, a 0.7.2 node from before the relay flag
+    const VERSION_60002: &str = "62ea0000010000000000000011b2d05000000000\
+        010000000000000000000000000000000000ffff000000000000\
+        010000000000000000000000000000000000ffff000000000000\
+        3b2eb35d8ce617650f2f5361746f7368693a302e372e322fc03e0300";
+
+    // the mainnet genesis block, header and coinbase
+    const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e6776\
+        8f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000\
+        000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f3230\
+        3039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73\
+        ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6\
+        bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    // the signed native P2WPKH example from BIP143, one legacy input and one segwit input
+    pub(crate) const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000049483045022100\
+        8b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c04\
+        89bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b9\
+        0ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac909351\
+        0d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa6\
+        10b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02d\
+        e67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    fn version_70002() -> VersionPayload {
+        VersionPayload {
+            version: 70002,
+            services: 1,
+            timestamp: 1415483324,
+            addr_recv: NetAddr { services: 1, ip_v6_4: "198.27.100.9".to_string(), port: 8333 },
+            addr_from: NetAddr { services: 1, ip_v6_4: "203.0.113.192".to_string(), port: 8333 },
+            nonce: 0xf85379c9cb358012,
+            user_agent: b"/Satoshi:0.9.3/".to_vec(),
+            start_height: 329167,
+            relay: true,
+        }
+    }
+
+    #[test]
+    fn decodes_captured_version() {
+        let payload = hex::decode(VERSION_70002).unwrap();
+        assert_eq!(parse_version(&payload).unwrap(), version_70002());
+    }
+
+    #[test]
+    fn encodes_captured_version() {
+        let payload = hex::decode(VERSION_70002).unwrap();
+        assert_eq!(serialise_version_message(&version_70002()).unwrap(), payload);
+    }
+
+    #[test]
+    fn captured_version_round_trips() {
+        for captured in [VERSION_70002, VERSION_60002] {
+            let payload = hex::decode(captured).unwrap();
+            let version = parse_version(&payload).unwrap();
+            let encoded = serialise_version_message(&version).unwrap();
+            assert_eq!(parse_version(&encoded).unwrap(), version);
+        }
+    }
+
+    #[test]
+    fn missing_relay_flag_means_relay() {
+        let payload = hex::decode(VERSION_60002).unwrap();
+        let version = parse_version(&payload).unwrap();
+
+        assert_eq!(version.version, 60002);
+        assert_eq!(version.user_agent, b"/Satoshi:0.7.2/");
+        assert_eq!(version.start_height, 212672);
+        assert_eq!(version.addr_recv.ip_v6_4, "0.0.0.0");
+        assert!(version.relay);
+
+        // we always write the flag, so the encoding is the capture plus one byte
+        let mut expected = payload.clone();
+        expected.push(1);
+        assert_eq!(serialise_version_message(&version).unwrap(), expected);
+    }
+
+    #[test]
+    fn addresses_are_ipv4_mapped_with_big_endian_ports() {
+        let encoded = serialise_version_message(&version_70002()).unwrap();
+
+        // addr_recv starts after version, services and timestamp
+        let addr_recv = &encoded[20..46];
+        assert_eq!(&addr_recv[..8], &1u64.to_le_bytes());
+        assert_eq!(&addr_recv[8..24], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 198, 27, 100, 9]);
+        assert_eq!(&addr_recv[24..], &[0x20, 0x8d]);
+    }
+
+    #[test]
+    fn ipv6_addresses_round_trip() {
+        let mut version = version_70002();
+        version.addr_recv.ip_v6_4 = "2001:db8::1".to_string();
+        version.addr_recv.port = 18333;
+
+        let encoded = serialise_version_message(&version).unwrap();
+        assert_eq!(parse_version(&encoded).unwrap(), version);
+    }
+
+    #[test]
+    fn long_user_agent_uses_compact_size() {
+        let mut version = version_70002();
+        version.user_agent = vec![b'a'; 300];
+
+        let encoded = serialise_version_message(&version).unwrap();
+        // 0xfd then the length as a little endian u16
+        assert_eq!(&encoded[80..83], &[0xfd, 0x2c, 0x01]);
+        assert_eq!(parse_version(&encoded).unwrap(), version);
+    }
+
+    #[test]
+    fn version_survives_the_wire() {
+        let message = build_message(&NetworkMessage::Version(version_70002()), BITCOIN_MAGIC).unwrap();
+        let bytes = serialise_message(&message).unwrap();
+
+        let mut reader = MessageReader::new(&bytes[..], BITCOIN_MAGIC);
+        let received = reader.read_message().unwrap();
+        assert_eq!(received.command, "version");
+
+        match parse_network_message(&received.command, &received.payload).unwrap() {
+            NetworkMessage::Version(version) => assert_eq!(version, version_70002()),
+            _ => panic!("expected a version message"),
+        }
+    }
+
+    // built, framed, read back and parsed, then written out again to the same payload
+    fn round_trip(message: NetworkMessage) -> NetworkMessage {
+        let sent = build_message(&message, BITCOIN_MAGIC).unwrap();
+        let bytes = serialise_message(&sent).unwrap();
+
+        let received = MessageReader::new(&bytes[..], BITCOIN_MAGIC).read_message().unwrap();
+        assert_eq!(received.command, message.command());
+        let parsed = parse_network_message(&received.command, &received.payload).unwrap();
+        assert_eq!(parsed.command(), message.command());
+        assert_eq!(serialise_network_message(&parsed).unwrap(), sent.payload);
+        parsed
+    }
+
+    #[test]
+    fn version_and_verack_round_trip() {
+        match round_trip(NetworkMessage::Version(version_70002())) {
+            NetworkMessage::Version(version) => assert_eq!(version, version_70002()),
+            _ => panic!("expected a version message"),
+        }
+        for message in [
+            NetworkMessage::Verack,
+            NetworkMessage::SendHeaders,
+            NetworkMessage::WtxidRelay,
+            NetworkMessage::SendAddrV2,
+            NetworkMessage::MemPool,
+        ] {
+            round_trip(message);
+        }
+    }
+
+    #[test]
+    fn ping_and_pong_round_trip() {
+        match round_trip(NetworkMessage::Ping(PingPayload { nonce: 0x0123456789abcdef })) {
+            NetworkMessage::Ping(ping) => assert_eq!(ping.nonce, 0x0123456789abcdef),
+            _ => panic!("expected a ping"),
+        }
+        match round_trip(NetworkMessage::Pong(PingPayload { nonce: 7 })) {
+            NetworkMessage::Pong(pong) => assert_eq!(pong.nonce, 7),
+            _ => panic!("expected a pong"),
+        }
+    }
+
+    #[test]
+    fn locators_round_trip() {
+        let locator = BlockLocatorPayload { version: 70016, locator_hashes: vec![[1; 32], [2; 32]], hash_stop: [0; 32] };
+        match round_trip(NetworkMessage::GetHeaders(locator.clone())) {
+            NetworkMessage::GetHeaders(parsed) => {
+                assert_eq!(parsed.version, 70016);
+                assert_eq!(parsed.locator_hashes, vec![[1; 32], [2; 32]]);
+                assert_eq!(parsed.hash_stop, [0; 32]);
+            }
+            _ => panic!("expected getheaders"),
+        }
+        round_trip(NetworkMessage::GetBlocks(locator));
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let genesis = parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let mut second = genesis.clone();
+        second.prev_block_hash = genesis.block_hash.0;
+        second.calculate_hash();
+
+        let headers = HeadersPayload { headers: vec![genesis.clone(), second.clone()] };
+        match round_trip(NetworkMessage::Headers(headers)) {
+            NetworkMessage::Headers(parsed) => {
+                let hashes: Vec<BlockHash> = parsed.headers.iter().map(|header| header.block_hash).collect();
+                assert_eq!(hashes, vec![genesis.block_hash, second.block_hash]);
+                assert!(parsed.headers.iter().all(|header| header.transactions.is_empty()));
+            }
+            _ => panic!("expected headers"),
+        }
+    }
+
+    #[test]
+    fn inventories_round_trip() {
+        let inventory = InvPayload {
+            inventory: vec![
+                Inventory { inv_type: InvType::Tx, hash: [3; 32] },
+                Inventory { inv_type: InvType::WitnessBlock, hash: [4; 32] },
+                Inventory { inv_type: InvType::Unknown(9), hash: [5; 32] },
+            ],
+        };
+        match round_trip(NetworkMessage::Inv(inventory.clone())) {
+            NetworkMessage::Inv(parsed) => {
+                let types: Vec<InvType> = parsed.inventory.iter().map(|item| item.inv_type).collect();
+                assert!(types == vec![InvType::Tx, InvType::WitnessBlock, InvType::Unknown(9)]);
+                assert_eq!(parsed.inventory[2].hash, [5; 32]);
+            }
+            _ => panic!("expected inv"),
+        }
+        round_trip(NetworkMessage::GetData(inventory.clone()));
+        round_trip(NetworkMessage::NotFound(inventory));
+    }
+
+    #[test]
+    fn block_round_trips() {
+        let genesis = parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        match round_trip(NetworkMessage::Block(genesis.clone())) {
+            NetworkMessage::Block(parsed) => {
+                assert_eq!(parsed.block_hash, genesis.block_hash);
+                assert_eq!(parsed.transactions.len(), 1);
+            }
+            _ => panic!("expected a block"),
+        }
+    }
+
+    #[test]
+    fn tx_round_trips() {
+        let transaction = parse_transaction(&mut ByteReader::new(&hex::decode(SEGWIT_TX).unwrap())).unwrap();
+        match round_trip(NetworkMessage::Tx(transaction.clone())) {
+            NetworkMessage::Tx(parsed) => {
+                assert_eq!(parsed.txid, transaction.txid);
+                assert_eq!(parsed.wtxid, transaction.wtxid);
+            }
+            _ => panic!("expected a tx"),
+        }
+    }
+
+    #[test]
+    fn the_rest_round_trip() {
+        let addr = NetAddr { services: 1033, ip_v6_4: "2001:db8::2".to_string(), port: 8333 };
+        round_trip(NetworkMessage::Addr(AddrPayload { addresses: vec![AddrEntry { time: 1_700_000_000, addr }] }));
+        round_trip(NetworkMessage::AddrV2(AddrV2Payload {
+            addresses: vec![AddrV2Entry { time: 1_700_000_000, services: 9, network_id: 1, addr: vec![10, 0, 0, 1], port: 18444 }],
+        }));
+        round_trip(NetworkMessage::SendCmpct(SendCmpctPayload { announce: true, version: 2 }));
+        round_trip(NetworkMessage::FeeFilter(FeeFilterPayload { feerate: 1000 }));
+        round_trip(NetworkMessage::Reject(RejectPayload {
+            message: "tx".to_string(),
+            code: 0x10,
+            reason: "bad-txns".to_string(),
+            data: vec![6; 32],
+        }));
+        round_trip(NetworkMessage::Unknown { command: "cmpctblock".to_string(), payload: vec![1, 2, 3] });
+    }
+
+    #[test]
+    fn segwit_txid_leaves_out_the_witness() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        let mut reader = ByteReader::new(&bytes);
+        let transaction = parse_transaction(&mut reader).unwrap();
+
+        assert!(reader.is_empty());
+        assert_eq!(reversed_hex(&transaction.txid), "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609");
+        assert_eq!(reversed_hex(&transaction.wtxid), "c36c38370907df2324d9ce9d149d191192f338b37665a82e78e76a12c909b762");
+
+        // the first input is legacy and has an empty stack, the second a signature and key
+        assert!(transaction.inputs[0].witness.is_empty());
+        assert_eq!(transaction.inputs[0].script_sig.len(), 73);
+        let witness: Vec<usize> = transaction.inputs[1].witness.iter().map(Vec::len).collect();
+        assert_eq!(witness, vec![71, 33]);
+        assert_eq!(transaction.outputs.len(), 2);
+        assert_eq!(transaction.locktime, 17);
+    }
+
+    #[test]
+    fn witness_bytes_weigh_a_quarter() {
+        let transaction = parse_transaction(&mut ByteReader::new(&hex::decode(SEGWIT_TX).unwrap())).unwrap();
+        // the marker, flag and 108 bytes of witness stacks come off the stripped size
+        assert_eq!(transaction.size, 343);
+        assert_eq!(transaction.stripped_size, 233);
+        assert_eq!(transaction.weight(), 1042);
+        assert_eq!(transaction.vsize(), 261);
+
+        let genesis = parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let coinbase = &genesis.transactions[0];
+        assert_eq!(coinbase.size, 204);
+        assert_eq!(coinbase.stripped_size, 204);
+        assert_eq!(coinbase.weight(), 816);
+        assert_eq!(coinbase.vsize(), 204);
+    }
+
+    #[test]
+    fn legacy_txid_is_the_wtxid() {
+        let genesis = parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let coinbase = &genesis.transactions[0];
+
+        assert_eq!(reversed_hex(&coinbase.txid), "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        assert_eq!(coinbase.txid, coinbase.wtxid);
+        assert!(coinbase.inputs[0].witness.is_empty());
+    }
+
+    #[test]
+    fn marker_without_witnesses_is_refused() {
+        // the segwit example with every witness stack emptied
+        let mut bytes = hex::decode(SEGWIT_TX).unwrap();
+        let witness_start = bytes.len() - 4 - 1 - (1 + 71) - (1 + 33) - 1;
+        bytes.splice(witness_start..bytes.len() - 4, [0, 0]);
+
+        let error = parse_transaction(&mut ByteReader::new(&bytes)).err().unwrap();
+        assert_eq!(error.to_string(), "segwit transaction has no witness data");
+    }
+
+    // the genesis header over its coinbase, the segwit example and the coinbase again
+    fn three_transaction_block() -> Vec<u8> {
+        let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+        let mut bytes = genesis[..80].to_vec();
+        bytes.push(3);
+        bytes.extend(&genesis[81..]);
+        bytes.extend(hex::decode(SEGWIT_TX).unwrap());
+        bytes.extend(&genesis[81..]);
+        bytes
+    }
+
+    #[test]
+    fn every_transaction_in_a_block_is_parsed() {
+        let bytes = three_transaction_block();
+        let block = parse_block(&bytes).unwrap();
+
+        let txids: Vec<String> = block.transactions.iter().map(|transaction| reversed_hex(&transaction.txid)).collect();
+        assert_eq!(
+            txids,
+            vec![
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609",
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            ]
+        );
+        assert_eq!(serialise_block(&block), bytes);
+    }
+
+    #[test]
+    fn trailing_bytes_after_a_block_are_refused() {
+        let mut bytes = three_transaction_block();
+        bytes.push(0);
+        let error = parse_block(&bytes).err().unwrap();
+        assert_eq!(error.to_string(), "1 trailing bytes after the last transaction");
+
+        // a count one short leaves the last transaction over
+        let mut bytes = three_transaction_block();
+        bytes[80] = 2;
+        assert!(parse_block(&bytes).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes_after_a_tx_are_refused() {
+        let mut bytes = hex::decode(SEGWIT_TX).unwrap();
+        assert!(parse_network_message("tx", &bytes).is_ok());
+
+        bytes.extend([0, 0]);
+        let error = parse_network_message("tx", &bytes).err().unwrap();
+        assert_eq!(error.to_string(), "2 trailing bytes after the transaction");
+    }
+
+    #[test]
+    fn compact_size_boundaries() {
+        let cases: [(u64, &str); 6] = [
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x10000, "fe00000100"),
+            (0xffff_ffff, "feffffffff"),
+            (0x1_0000_0000, "ff0000000001000000"),
+        ];
+        for (value, encoded) in cases {
+            assert_eq!(hex::encode(write_var_int(value)), encoded);
+            let bytes = hex::decode(encoded).unwrap();
+            assert_eq!(ByteReader::new(&bytes).read_compact_size().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn long_counts_and_scripts() {
+        let mut transaction = parse_transaction(&mut ByteReader::new(&hex::decode(SEGWIT_TX).unwrap())).unwrap();
+        let input = transaction.inputs[1].clone();
+        transaction.inputs = vec![input; 300];
+        transaction.inputs[0].script_sig = vec![0x51; 300];
+        transaction.outputs[0].script_pub_key = vec![0x6a; 70_000];
+
+        let bytes = serialise_transaction(&transaction);
+        // marker and flag, then 300 inputs as 0xfd and a u16
+        assert_eq!(&bytes[4..9], &[0x00, 0x01, 0xfd, 0x2c, 0x01]);
+
+        let parsed = parse_transaction(&mut ByteReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.inputs.len(), 300);
+        assert_eq!(parsed.inputs[0].script_sig.len(), 300);
+        assert_eq!(parsed.outputs[0].script_pub_key.len(), 70_000);
+        assert_eq!(parsed.inputs[299].witness, transaction.inputs[299].witness);
+        assert_eq!(serialise_transaction(&parsed), bytes);
+    }
+
+    #[test]
+    fn truncated_transactions_are_errors() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        for length in 0..bytes.len() {
+            assert!(parse_transaction(&mut ByteReader::new(&bytes[..length])).is_err(), "cut at {}", length);
+        }
+    }
+
+    #[test]
+    fn script_length_past_the_end_is_an_error() {
+        // one input whose scriptSig claims 0xfe (a u32 length) of 0x10000000 bytes
+        let mut bytes = hex::decode("0100000001").unwrap();
+        bytes.extend([0; 36]);
+        bytes.extend([0xfe, 0x00, 0x00, 0x00, 0x10]);
+        assert!(parse_transaction(&mut ByteReader::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn headers_carry_no_transactions() {
+        let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+        let mut payload = vec![1];
+        payload.extend(&genesis[..80]);
+        payload.push(0);
+        assert_eq!(parse_headers(&payload).unwrap().headers.len(), 1);
+
+        *payload.last_mut().unwrap() = 1;
+        let error = parse_headers(&payload).err().unwrap();
+        assert!(error.to_string().ends_with("has a transaction count of 1"));
+    }
+}
@@ -0,0 +1,325 @@
+// keeps several outbound peers running at once so one slow or misbehaving node can't stall
+// the block feed. each peer gets its own thread, they share one view of which blocks have
+// already been asked for and handed to the ui.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::chain::ChainIndex;
+use crate::connection::Connection;
+use crate::discovery::discover_candidates;
+use crate::discovery::SystemResolver;
+use crate::network::Network;
+use crate::storage::BlockStore;
+use crate::structures::{NetworkCommand, NetworkEvent, PeerId};
+
+// how long to leave an address alone after it failed or dropped us
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+// how often the dns seeds can be asked again once every candidate has been used up
+const REDISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+// how long the manager waits for a peer to exit before checking for commands from the ui
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how many delivered blocks are remembered. peers only announce blocks near the tip, and the
+// ui merges a block it already lists, so forgetting old ones costs at most a repeat download
+const DELIVERED_WINDOW: usize = 2000;
+
+// Block inventory shared between peers. A block announced by several peers is only
+// fetched from the first one to claim it, and only handed to the ui once.
+#[derive(Default)]
+pub struct InventoryTracker {
+    requested: HashMap<[u8; 32], PeerId>,
+    delivered: HashSet<[u8; 32]>,
+    // delivered hashes oldest first, so the window can drop the oldest
+    delivery_order: VecDeque<[u8; 32]>,
+}
+
+impl InventoryTracker {
+    // true if the peer should go and fetch the block itself
+    pub fn claim(&mut self, hash: [u8; 32], peer: PeerId) -> bool {
+        if self.delivered.contains(&hash) || self.requested.contains_key(&hash) {
+            return false;
+        }
+        self.requested.insert(hash, peer);
+        true
+    }
+
+    // true the first time a block turns up, from whichever peer
+    pub fn deliver(&mut self, hash: [u8; 32]) -> bool {
+        self.requested.remove(&hash);
+        if !self.delivered.insert(hash) {
+            return false;
+        }
+
+        self.delivery_order.push_back(hash);
+        if self.delivery_order.len() > DELIVERED_WINDOW {
+            if let Some(oldest) = self.delivery_order.pop_front() {
+                self.delivered.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    // a peer that went away won't answer its getdata, let the next announcement claim them
+    pub fn release(&mut self, peer: PeerId) {
+        self.requested.retain(|_, owner| *owner != peer);
+    }
+}
+
+// everything a peer thread shares with the manager and the other peers
+pub struct PeerContext {
+    pub id: PeerId,
+    pub events: Sender<NetworkEvent>,
+    pub commands: Receiver<NetworkCommand>,
+    pub inventory: Arc<Mutex<InventoryTracker>>,
+    pub chain: Arc<Mutex<ChainIndex>>,
+    pub store: Arc<Mutex<BlockStore>>,
+}
+
+struct PeerHandle {
+    address: String,
+    commands: Sender<NetworkCommand>,
+}
+
+pub struct PeerManager {
+    network: Network,
+    // a peer picked by the user is the only one we talk to, otherwise candidates come from discovery
+    configured_peer: Option<String>,
+    max_peers: usize,
+    candidates: VecDeque<String>,
+    last_discovery: Option<Instant>,
+    last_attempt: HashMap<String, Instant>,
+    peers: HashMap<PeerId, PeerHandle>,
+    next_id: PeerId,
+    // the peer walking back through history for the ui, there's only ever one walk going
+    backfill_peer: Option<PeerId>,
+    inventory: Arc<Mutex<InventoryTracker>>,
+    chain: Arc<Mutex<ChainIndex>>,
+    store: Arc<Mutex<BlockStore>>,
+    events: Sender<NetworkEvent>,
+    commands: Receiver<NetworkCommand>,
+    exit_sender: Sender<PeerId>,
+    exit_receiver: Receiver<PeerId>,
+}
+
+impl PeerManager {
+    pub fn new(
+        network: Network,
+        configured_peer: Option<String>,
+        max_peers: usize,
+        chain: ChainIndex,
+        store: BlockStore,
+        events: Sender<NetworkEvent>,
+        commands: Receiver<NetworkCommand>,
+    ) -> PeerManager {
+        let (exit_sender, exit_receiver) = mpsc::channel();
+        let max_peers = if configured_peer.is_some() { 1 } else { max_peers };
+
+        PeerManager {
+            network,
+            configured_peer,
+            max_peers,
+            candidates: VecDeque::new(),
+            last_discovery: None,
+            last_attempt: HashMap::new(),
+            peers: HashMap::new(),
+            next_id: 0,
+            backfill_peer: None,
+            inventory: Arc::new(Mutex::new(InventoryTracker::default())),
+            chain: Arc::new(Mutex::new(chain)),
+            store: Arc::new(Mutex::new(store)),
+            events,
+            commands,
+            exit_sender,
+            exit_receiver,
+        }
+    }
+
+    // addresses already found by discovery, saves asking the seeds again straight away
+    pub fn add_candidates(&mut self, candidates: &[SocketAddr]) {
+        self.candidates.extend(candidates.iter().map(|address| address.to_string()));
+        self.last_discovery = Some(Instant::now());
+    }
+
+    // starts a peer that has already connected and finished its handshake
+    pub fn add_peer(&mut self, address: String, connection: Connection) {
+        self.spawn_peer(address, Some(connection));
+    }
+
+    // Tops the pool back up whenever a peer drops out and passes commands from the ui on
+    // to a peer. Never returns, the thread it runs on is torn down with the rest of the
+    // process when the ui exits.
+    pub fn run(mut self) {
+        loop {
+            while self.peers.len() < self.max_peers {
+                match self.next_candidate() {
+                    Some(address) => self.spawn_peer(address, None),
+                    None => break,
+                }
+            }
+
+            while let Ok(command) = self.commands.try_recv() {
+                self.dispatch(command);
+            }
+
+            match self.exit_receiver.recv_timeout(COMMAND_POLL_INTERVAL) {
+                Ok(id) => self.peer_exited(id),
+                Err(RecvTimeoutError::Timeout) => {}
+                // can't happen while we hold exit_sender
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn peer_exited(&mut self, id: PeerId) {
+        if let Some(peer) = self.peers.remove(&id) {
+            self.last_attempt.insert(peer.address, Instant::now());
+        }
+        self.inventory.lock().unwrap().release(id);
+        self.chain.lock().unwrap().release_sync(id);
+        let _ = self.events.send(NetworkEvent::PeerDisconnected(id));
+
+        // the walk dies with the peer, let the ui ask again
+        if self.backfill_peer == Some(id) {
+            self.backfill_peer = None;
+            let _ = self.events.send(NetworkEvent::BackfillFinished);
+        }
+    }
+
+    // commands go to the longest serving peer, it's the one most likely to still be there
+    fn dispatch(&mut self, command: NetworkCommand) {
+        let sent = self
+            .peers
+            .iter()
+            .min_by_key(|(id, _)| **id)
+            .and_then(|(id, peer)| peer.commands.send(command).ok().map(|_| *id));
+
+        match sent {
+            Some(id) => self.backfill_peer = Some(id),
+            None => {
+                let _ = self.events.send(NetworkEvent::BackfillFinished);
+            }
+        }
+    }
+
+    fn next_candidate(&mut self) -> Option<String> {
+        if let Some(peer) = &self.configured_peer {
+            return if self.peers.is_empty() && self.ready_to_try(peer) {
+                Some(peer.clone())
+            } else {
+                None
+            };
+        }
+
+        if self.candidates.is_empty() && self.discovery_due() {
+            let found = discover_candidates(self.network, &SystemResolver, &mut |_| {});
+            self.add_candidates(&found);
+        }
+
+        while let Some(address) = self.candidates.pop_front() {
+            let in_use = self.peers.values().any(|peer| peer.address == address);
+            if !in_use && self.ready_to_try(&address) {
+                return Some(address);
+            }
+        }
+
+        None
+    }
+
+    fn ready_to_try(&self, address: &str) -> bool {
+        match self.last_attempt.get(address) {
+            Some(attempted) => attempted.elapsed() >= RETRY_DELAY,
+            None => true,
+        }
+    }
+
+    fn discovery_due(&self) -> bool {
+        match self.last_discovery {
+            Some(discovered) => discovered.elapsed() >= REDISCOVER_INTERVAL,
+            None => true,
+        }
+    }
+
+    fn spawn_peer(&mut self, address: String, connection: Option<Connection>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (command_sender, command_receiver) = mpsc::channel();
+        self.peers.insert(id, PeerHandle { address: address.clone(), commands: command_sender });
+
+        let network = self.network;
+        let context = PeerContext {
+            id,
+            events: self.events.clone(),
+            commands: command_receiver,
+            inventory: Arc::clone(&self.inventory),
+            chain: Arc::clone(&self.chain),
+            store: Arc::clone(&self.store),
+        };
+        let exit_sender = self.exit_sender.clone();
+
+        thread::spawn(move || {
+            let connection = match connection {
+                Some(connection) => Some(connection),
+                None => {
+                    let mut connection = Connection::new(network);
+                    match connection.connect(&address).and_then(|_| connection.handshake()) {
+                        Ok(()) => Some(connection),
+                        Err(_) => None,
+                    }
+                }
+            };
+
+            if let Some(mut connection) = connection {
+                let _ = connection.handle_stream(context);
+            }
+
+            let _ = exit_sender.send(id);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: usize) -> [u8; 32] {
+        let mut hash = [0; 32];
+        hash[..8].copy_from_slice(&(n as u64).to_le_bytes());
+        hash
+    }
+
+    #[test]
+    fn blocks_are_claimed_and_delivered_once() {
+        let mut tracker = InventoryTracker::default();
+        assert!(tracker.claim(hash(1), 0));
+        assert!(!tracker.claim(hash(1), 1));
+
+        assert!(tracker.deliver(hash(1)));
+        assert!(!tracker.deliver(hash(1)));
+        assert!(!tracker.claim(hash(1), 1));
+    }
+
+    #[test]
+    fn released_claims_can_be_taken_again() {
+        let mut tracker = InventoryTracker::default();
+        assert!(tracker.claim(hash(1), 0));
+        tracker.release(0);
+        assert!(tracker.claim(hash(1), 1));
+    }
+
+    #[test]
+    fn delivered_blocks_are_forgotten_past_the_window() {
+        let mut tracker = InventoryTracker::default();
+        for n in 0..DELIVERED_WINDOW + 10 {
+            assert!(tracker.deliver(hash(n)));
+        }
+
+        assert_eq!(tracker.delivered.len(), DELIVERED_WINDOW);
+        assert_eq!(tracker.delivery_order.len(), DELIVERED_WINDOW);
+        assert!(tracker.claim(hash(0), 0));
+        assert!(!tracker.claim(hash(DELIVERED_WINDOW + 9), 0));
+    }
+}
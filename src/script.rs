@@ -0,0 +1,291 @@
+// bitcoin script, decoded into the opcodes and pushes it's made of
+//
+// every opcode is one byte. 0x01 to 0x4b push that many of the bytes after them, OP_PUSHDATA1,
+// OP_PUSHDATA2 and OP_PUSHDATA4 push as many bytes as the 1, 2 or 4 byte little endian length
+// after them says. everything else works on the stack and carries no data.
+
+use std::fmt;
+
+use crate::utils::ByteReader;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHBYTES_20: u8 = 0x14;
+pub const OP_PUSHBYTES_33: u8 = 0x21;
+pub const OP_PUSHBYTES_65: u8 = 0x41;
+pub const OP_PUSHBYTES_75: u8 = 0x4b;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+// where decoding had to stop, everything before offset decoded fine
+pub struct Malformed {
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.reason, self.offset)
+    }
+}
+
+pub struct Disassembly<'a> {
+    pub instructions: Vec<Instruction<'a>>,
+    pub malformed: Option<Malformed>,
+}
+
+// Scripts are only checked when they're run, so a block can hold ones that don't decode,
+// usually a push longer than what's left. Those decode as far as they go rather than failing.
+pub fn disassemble(script: &[u8]) -> Disassembly<'_> {
+    let mut reader = ByteReader::new(script);
+    let mut instructions = Vec::new();
+
+    while !reader.is_empty() {
+        let offset = reader.position();
+        match read_instruction(&mut reader) {
+            Ok(instruction) => instructions.push(instruction),
+            Err(reason) => {
+                return Disassembly {
+                    instructions,
+                    malformed: Some(Malformed { offset, reason }),
+                }
+            }
+        }
+    }
+
+    Disassembly { instructions, malformed: None }
+}
+
+fn read_instruction<'a>(reader: &mut ByteReader<'a>) -> Result<Instruction<'a>, String> {
+    let opcode = reader.read_u8().map_err(|e| e.to_string())?;
+
+    let length = match opcode {
+        0x01..=OP_PUSHBYTES_75 => Ok(opcode as usize),
+        OP_PUSHDATA1 => reader.read_u8().map(|length| length as usize),
+        OP_PUSHDATA2 => reader.read_u16().map(|length| length as usize),
+        OP_PUSHDATA4 => reader.read_u32().map(|length| length as usize),
+        _ => return Ok(Instruction::Op(opcode)),
+    }
+    .map_err(|_| format!("{} is missing its length", opcode_name(opcode)))?;
+
+    let remaining = reader.remaining();
+    reader
+        .read_slice(length)
+        .map(Instruction::Push)
+        .map_err(|_| format!("push of {} bytes with only {} left", length, remaining))
+}
+
+// Pushed data as hex and opcodes by name, space separated, like bitcoin core's decodescript.
+// A malformed script ends in [error: ...] after whatever decoded before the problem.
+pub fn asm(script: &[u8]) -> String {
+    let disassembly = disassemble(script);
+
+    let mut parts: Vec<String> = disassembly
+        .instructions
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Push(data) => hex::encode(data),
+            Instruction::Op(opcode) => opcode_name(*opcode),
+        })
+        .collect();
+    if let Some(malformed) = disassembly.malformed {
+        parts.push(format!("[error: {}]", malformed));
+    }
+
+    parts.join(" ")
+}
+
+pub fn opcode_name(opcode: u8) -> String {
+    let name = match opcode {
+        OP_0 => "OP_0",
+        0x01..=OP_PUSHBYTES_75 => return format!("OP_PUSHBYTES_{}", opcode),
+        OP_PUSHDATA1 => "OP_PUSHDATA1",
+        OP_PUSHDATA2 => "OP_PUSHDATA2",
+        OP_PUSHDATA4 => "OP_PUSHDATA4",
+        0x4f => "OP_1NEGATE",
+        0x50 => "OP_RESERVED",
+        OP_1..=OP_16 => return format!("OP_{}", opcode - OP_1 + 1),
+
+        // flow control
+        0x61 => "OP_NOP",
+        0x62 => "OP_VER",
+        0x63 => "OP_IF",
+        0x64 => "OP_NOTIF",
+        0x65 => "OP_VERIF",
+        0x66 => "OP_VERNOTIF",
+        0x67 => "OP_ELSE",
+        0x68 => "OP_ENDIF",
+        0x69 => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+
+        // stack
+        0x6b => "OP_TOALTSTACK",
+        0x6c => "OP_FROMALTSTACK",
+        0x6d => "OP_2DROP",
+        0x6e => "OP_2DUP",
+        0x6f => "OP_3DUP",
+        0x70 => "OP_2OVER",
+        0x71 => "OP_2ROT",
+        0x72 => "OP_2SWAP",
+        0x73 => "OP_IFDUP",
+        0x74 => "OP_DEPTH",
+        0x75 => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        0x77 => "OP_NIP",
+        0x78 => "OP_OVER",
+        0x79 => "OP_PICK",
+        0x7a => "OP_ROLL",
+        0x7b => "OP_ROT",
+        0x7c => "OP_SWAP",
+        0x7d => "OP_TUCK",
+
+        // splice and bitwise logic, most of these have been disabled since 2010
+        0x7e => "OP_CAT",
+        0x7f => "OP_SUBSTR",
+        0x80 => "OP_LEFT",
+        0x81 => "OP_RIGHT",
+        0x82 => "OP_SIZE",
+        0x83 => "OP_INVERT",
+        0x84 => "OP_AND",
+        0x85 => "OP_OR",
+        0x86 => "OP_XOR",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        0x89 => "OP_RESERVED1",
+        0x8a => "OP_RESERVED2",
+
+        // arithmetic
+        0x8b => "OP_1ADD",
+        0x8c => "OP_1SUB",
+        0x8d => "OP_2MUL",
+        0x8e => "OP_2DIV",
+        0x8f => "OP_NEGATE",
+        0x90 => "OP_ABS",
+        0x91 => "OP_NOT",
+        0x92 => "OP_0NOTEQUAL",
+        0x93 => "OP_ADD",
+        0x94 => "OP_SUB",
+        0x95 => "OP_MUL",
+        0x96 => "OP_DIV",
+        0x97 => "OP_MOD",
+        0x98 => "OP_LSHIFT",
+        0x99 => "OP_RSHIFT",
+        0x9a => "OP_BOOLAND",
+        0x9b => "OP_BOOLOR",
+        0x9c => "OP_NUMEQUAL",
+        0x9d => "OP_NUMEQUALVERIFY",
+        0x9e => "OP_NUMNOTEQUAL",
+        0x9f => "OP_LESSTHAN",
+        0xa0 => "OP_GREATERTHAN",
+        0xa1 => "OP_LESSTHANOREQUAL",
+        0xa2 => "OP_GREATERTHANOREQUAL",
+        0xa3 => "OP_MIN",
+        0xa4 => "OP_MAX",
+        0xa5 => "OP_WITHIN",
+
+        // crypto
+        0xa6 => "OP_RIPEMD160",
+        0xa7 => "OP_SHA1",
+        0xa8 => "OP_SHA256",
+        OP_HASH160 => "OP_HASH160",
+        0xaa => "OP_HASH256",
+        0xab => "OP_CODESEPARATOR",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        0xad => "OP_CHECKSIGVERIFY",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        0xaf => "OP_CHECKMULTISIGVERIFY",
+
+        // upgradable nops, two of which became timelocks
+        0xb0 => "OP_NOP1",
+        0xb1 => "OP_CHECKLOCKTIMEVERIFY",
+        0xb2 => "OP_CHECKSEQUENCEVERIFY",
+        0xb3..=0xb9 => return format!("OP_NOP{}", opcode - 0xb3 + 4),
+        // tapscript only
+        0xba => "OP_CHECKSIGADD",
+        0xff => "OP_INVALIDOPCODE",
+        _ => return format!("OP_UNKNOWN_{:#04x}", opcode),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pushes(script: &[u8]) -> Vec<Vec<u8>> {
+        let disassembly = disassemble(script);
+        assert!(disassembly.malformed.is_none());
+        disassembly
+            .instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Push(data) => data.to_vec(),
+                Instruction::Op(opcode) => panic!("expected a push, got {}", opcode_name(*opcode)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn direct_push() {
+        assert_eq!(pushes(&[0x03, 1, 2, 3]), vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn pushdata_lengths() {
+        let data = vec![0xcd; 300];
+
+        let mut script = vec![OP_PUSHDATA1, 200];
+        script.extend(&data[..200]);
+        assert_eq!(pushes(&script), vec![data[..200].to_vec()]);
+
+        let mut script = vec![OP_PUSHDATA2, 0x2c, 0x01];
+        script.extend(&data);
+        assert_eq!(pushes(&script), vec![data.clone()]);
+
+        let mut script = vec![OP_PUSHDATA4, 0x2c, 0x01, 0x00, 0x00];
+        script.extend(&data);
+        assert_eq!(pushes(&script), vec![data]);
+    }
+
+    #[test]
+    fn truncated_push_stops_where_it_starts() {
+        let script = [OP_DUP, OP_PUSHDATA1, 10, 1, 2, 3];
+        let disassembly = disassemble(&script);
+
+        assert_eq!(disassembly.instructions.len(), 1);
+        let malformed = disassembly.malformed.unwrap();
+        assert_eq!(malformed.offset, 1);
+        assert_eq!(malformed.reason, "push of 10 bytes with only 3 left");
+        assert_eq!(asm(&script), "OP_DUP [error: push of 10 bytes with only 3 left at byte 1]");
+    }
+
+    #[test]
+    fn missing_length_is_malformed() {
+        let malformed = disassemble(&[OP_PUSHDATA2, 0x01]).malformed.unwrap();
+        assert_eq!(malformed.offset, 0);
+        assert_eq!(malformed.reason, "OP_PUSHDATA2 is missing its length");
+    }
+
+    #[test]
+    fn p2pkh_asm() {
+        let script = hex::decode("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        assert_eq!(
+            asm(&script),
+            "OP_DUP OP_HASH160 62e907b15cbf27d5425399ebf6f0fb50ebb88f18 OP_EQUALVERIFY OP_CHECKSIG"
+        );
+    }
+}
@@ -3,28 +3,75 @@
 use sha2::{Digest, Sha256};
 
 
-use crate::utils::ip_string_to_bytes; // import the function
-use crate::structures::{Command, NetAddr, VersionPayload};
-
+use crate::utils::{ip_string_to_bytes, write_var_int}; // import the function
+use crate::structures::{
+    AddrPayload, AddrV2Payload, BitcoinMessage, BlockData, BlockLocatorPayload, InvPayload,
+    NetAddr, NetworkMessage, RejectPayload, Transaction, VersionPayload,
+};
+
+
+pub fn serialise_network_message(message: &NetworkMessage) -> Result<Vec<u8>, std::net::AddrParseError> {
+    let payload = match message {
+        NetworkMessage::Version(version) => serialise_version_message(version)?,
+        NetworkMessage::Ping(ping) | NetworkMessage::Pong(ping) => ping.nonce.to_le_bytes().to_vec(),
+        NetworkMessage::Inv(inv) | NetworkMessage::GetData(inv) | NetworkMessage::NotFound(inv) => {
+            serialise_inv(inv)
+        }
+        NetworkMessage::GetBlocks(locator) | NetworkMessage::GetHeaders(locator) => {
+            serialise_block_locator(locator)
+        }
+        NetworkMessage::Headers(headers) => {
+            let mut bytes = write_var_int(headers.headers.len() as u64);
+            for header in &headers.headers {
+                bytes.extend(serialise_block_header(header));
+                bytes.push(0);
+            }
+            bytes
+        }
+        NetworkMessage::Block(block) => serialise_block(block),
+        NetworkMessage::Tx(transaction) => serialise_transaction(transaction),
+        NetworkMessage::Addr(addr) => serialise_addr(addr)?,
+        NetworkMessage::AddrV2(addr) => serialise_addrv2(addr),
+        NetworkMessage::SendCmpct(sendcmpct) => {
+            let mut bytes = vec![sendcmpct.announce as u8];
+            bytes.extend(sendcmpct.version.to_le_bytes());
+            bytes
+        }
+        NetworkMessage::FeeFilter(feefilter) => feefilter.feerate.to_le_bytes().to_vec(),
+        NetworkMessage::Reject(reject) => serialise_reject(reject),
+        NetworkMessage::Unknown { payload, .. } => payload.clone(),
+        NetworkMessage::Verack
+        | NetworkMessage::SendHeaders
+        | NetworkMessage::WtxidRelay
+        | NetworkMessage::SendAddrV2
+        | NetworkMessage::MemPool => vec![],
+    };
+
+    Ok(payload)
+}
 
+// wraps a typed message up ready for the MessageWriter
+pub fn build_message(message: &NetworkMessage, magic: [u8; 4]) -> Result<BitcoinMessage, std::net::AddrParseError> {
+    Ok(BitcoinMessage::new(magic, message.command(), serialise_network_message(message)?))
+}
 
 pub fn serialise_version_message(version_message: &VersionPayload) -> Result<Vec<u8>, std::net::AddrParseError> {
     let mut message_bytes = vec![];
-    let net_addr_from = serialise_network_address(&version_message.addr_from)?;
     let net_addr_recv = serialise_network_address(&version_message.addr_recv)?;
+    let net_addr_from = serialise_network_address(&version_message.addr_from)?;
 
-    message_bytes.extend((&version_message.version).to_le_bytes());
-    message_bytes.extend((&version_message.services).to_le_bytes());
-    message_bytes.extend((&version_message.timestamp).to_le_bytes());
-    message_bytes.extend(net_addr_from);
+    message_bytes.extend(version_message.version.to_le_bytes());
+    message_bytes.extend(version_message.services.to_le_bytes());
+    message_bytes.extend(version_message.timestamp.to_le_bytes());
     message_bytes.extend(net_addr_recv);
-    message_bytes.extend((&version_message.nonce).to_le_bytes());
+    message_bytes.extend(net_addr_from);
+    message_bytes.extend(version_message.nonce.to_le_bytes());
 
-    // User agent length (as a u8)
-    message_bytes.push(version_message.user_agent.len() as u8);
+    // User agent is a var_str
+    message_bytes.extend(write_var_int(version_message.user_agent.len() as u64));
     message_bytes.extend_from_slice(&version_message.user_agent);
 
-    message_bytes.extend((&version_message.start_height).to_le_bytes());
+    message_bytes.extend(version_message.start_height.to_le_bytes());
     message_bytes.push(version_message.relay as u8);
 
     Ok(message_bytes)
@@ -37,38 +84,128 @@ fn serialise_network_address(addr: &NetAddr) -> Result<Vec<u8>, std::net::AddrPa
     buf.extend(&addr.services.to_le_bytes());
     let ip_bytes = ip_string_to_bytes(&addr.ip_v6_4)?;
     buf.extend(&ip_bytes);
-    buf.extend(&addr.port.to_le_bytes());
+    buf.extend(&addr.port.to_be_bytes());
 
     Ok(buf)
 }
 
-pub fn bitcoin_checksum(payload: &[u8]) -> [u8; 4] {
-    let first_hash = Sha256::digest(payload);
-    let second_hash = Sha256::digest(&first_hash);
-    let mut checksum = [0u8; 4];
-    checksum.copy_from_slice(&second_hash[..4]);
-    checksum
+fn serialise_inv(inv: &InvPayload) -> Vec<u8> {
+    let mut bytes = write_var_int(inv.inventory.len() as u64);
+    for item in &inv.inventory {
+        bytes.extend(item.inv_type.to_u32().to_le_bytes());
+        bytes.extend(item.hash);
+    }
+    bytes
 }
 
-fn sha256d(payload: &[u8]) -> Vec<u8> {
-    let hash = Sha256::digest(payload);
-    return hash.as_slice().to_vec();
+fn serialise_block_locator(locator: &BlockLocatorPayload) -> Vec<u8> {
+    let mut bytes = locator.version.to_le_bytes().to_vec();
+    bytes.extend(write_var_int(locator.locator_hashes.len() as u64));
+    for hash in &locator.locator_hashes {
+        bytes.extend(hash);
+    }
+    bytes.extend(locator.hash_stop);
+    bytes
+}
 
+pub fn serialise_block_header(block: &BlockData) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(80);
+    bytes.extend(block.version.to_le_bytes());
+    bytes.extend(block.prev_block_hash);
+    bytes.extend(block.merkle_root);
+    bytes.extend(block.timestamp.to_le_bytes());
+    bytes.extend(block.bits.to_le_bytes());
+    bytes.extend(block.nonce.to_le_bytes());
+    bytes
 }
 
-pub fn construct_complete_message(command:Command,  payload: Vec<u8>) -> Vec<u8> {
-    let mut complete_message = vec![];
-    let magic_bytes: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
-    let command : [u8; 12] = command.as_bytes();
-    let payload_len = payload.len() as u32;
-    let checksum = bitcoin_checksum(&payload);
+pub fn serialise_block(block: &BlockData) -> Vec<u8> {
+    let mut bytes = serialise_block_header(block);
+    bytes.extend(write_var_int(block.transactions.len() as u64));
+    for transaction in &block.transactions {
+        bytes.extend(serialise_transaction(transaction));
+    }
+    bytes
+}
 
-    complete_message.extend(&magic_bytes);
-    complete_message.extend(&command);
-    complete_message.extend(&payload_len.to_le_bytes());
-    complete_message.extend(&checksum);
-    complete_message.extend(&payload);
+// uses the BIP144 layout whenever any input carries a witness, like bitcoin core does
+pub fn serialise_transaction(transaction: &Transaction) -> Vec<u8> {
+    let include_witness = transaction.has_witness();
+    let mut bytes = transaction.version.to_le_bytes().to_vec();
+
+    if include_witness {
+        bytes.extend([0x00, 0x01]);
+    }
+
+    bytes.extend(write_var_int(transaction.inputs.len() as u64));
+    for input in &transaction.inputs {
+        bytes.extend(input.prev_tx_hash);
+        bytes.extend(input.prev_output_index.to_le_bytes());
+        bytes.extend(write_var_int(input.script_sig.len() as u64));
+        bytes.extend(&input.script_sig);
+        bytes.extend(input.sequence.to_le_bytes());
+    }
+
+    bytes.extend(write_var_int(transaction.outputs.len() as u64));
+    for output in &transaction.outputs {
+        bytes.extend(output.value.to_le_bytes());
+        bytes.extend(write_var_int(output.script_pub_key.len() as u64));
+        bytes.extend(&output.script_pub_key);
+    }
+
+    if include_witness {
+        for input in &transaction.inputs {
+            bytes.extend(write_var_int(input.witness.len() as u64));
+            for item in &input.witness {
+                bytes.extend(write_var_int(item.len() as u64));
+                bytes.extend(item);
+            }
+        }
+    }
+
+    bytes.extend(transaction.locktime.to_le_bytes());
+    bytes
+}
+
+fn serialise_addr(addr: &AddrPayload) -> Result<Vec<u8>, std::net::AddrParseError> {
+    let mut bytes = write_var_int(addr.addresses.len() as u64);
+    for entry in &addr.addresses {
+        bytes.extend(entry.time.to_le_bytes());
+        bytes.extend(serialise_network_address(&entry.addr)?);
+    }
+    Ok(bytes)
+}
+
+fn serialise_addrv2(addr: &AddrV2Payload) -> Vec<u8> {
+    let mut bytes = write_var_int(addr.addresses.len() as u64);
+    for entry in &addr.addresses {
+        bytes.extend(entry.time.to_le_bytes());
+        bytes.extend(write_var_int(entry.services));
+        bytes.push(entry.network_id);
+        bytes.extend(write_var_int(entry.addr.len() as u64));
+        bytes.extend(&entry.addr);
+        bytes.extend(entry.port.to_be_bytes());
+    }
+    bytes
+}
+
+fn serialise_reject(reject: &RejectPayload) -> Vec<u8> {
+    let mut bytes = write_var_int(reject.message.len() as u64);
+    bytes.extend(reject.message.as_bytes());
+    bytes.push(reject.code);
+    bytes.extend(write_var_int(reject.reason.len() as u64));
+    bytes.extend(reject.reason.as_bytes());
+    bytes.extend(&reject.data);
+    bytes
+}
 
-    return complete_message;
+pub fn bitcoin_checksum(payload: &[u8]) -> [u8; 4] {
+    let mut checksum = [0u8; 4];
+    checksum.copy_from_slice(&sha256d(payload)[..4]);
+    checksum
+}
 
+pub fn sha256d(payload: &[u8]) -> [u8; 32] {
+    let first_hash = Sha256::digest(payload);
+    Sha256::digest(first_hash).into()
 }
@@ -0,0 +1,352 @@
+// sizes, fees and adoption figures for a whole block
+//
+// a transaction's fee is what its inputs bring in minus what its outputs pay, but an input
+// only names the output it spends, not its value. that has to come from the transaction
+// that created the output, so fees are only known for transactions whose inputs all spend
+// outputs the explorer has seen.
+
+use std::collections::HashMap;
+
+use crate::coinbase::decode_coinbase;
+use crate::network::Network;
+use crate::structures::{BlockData, Transaction};
+use crate::utils::write_var_int;
+
+// looks up the value of the output a transaction input spends
+pub trait PrevoutSource {
+    fn prevout_value(&self, txid: &[u8; 32], index: u32) -> Option<u64>;
+}
+
+// output values from every block loaded so far, there's no utxo set to ask instead
+#[derive(Default)]
+pub struct LoadedOutputs {
+    values: HashMap<[u8; 32], Vec<u64>>,
+}
+
+impl LoadedOutputs {
+    pub fn add_block(&mut self, block: &BlockData) {
+        for transaction in &block.transactions {
+            let values = transaction.outputs.iter().map(|output| output.value).collect();
+            self.values.insert(transaction.txid, values);
+        }
+    }
+
+    pub fn remove_block(&mut self, block: &BlockData) {
+        for transaction in &block.transactions {
+            self.values.remove(&transaction.txid);
+        }
+    }
+}
+
+impl PrevoutSource for LoadedOutputs {
+    fn prevout_value(&self, txid: &[u8; 32], index: u32) -> Option<u64> {
+        self.values.get(txid)?.get(index as usize).copied()
+    }
+}
+
+// where the total fee figure came from
+pub enum FeeSource {
+    // every transaction's inputs could be valued
+    Transactions,
+    // what the coinbase paid out over the subsidy
+    Coinbase,
+}
+
+pub struct BlockStats {
+    pub transactions: usize,
+    pub size: usize,
+    pub stripped_size: usize,
+    pub weight: usize,
+    // paid out by everything but the coinbase
+    pub output_value: u64,
+    pub fees: Option<(u64, FeeSource)>,
+    // sat/vB of each transaction whose fee is known, lowest first
+    pub feerates: Vec<f64>,
+    pub segwit_share: f64,
+    pub taproot_share: f64,
+}
+
+impl BlockStats {
+    pub fn min_feerate(&self) -> Option<f64> {
+        self.feerates.first().copied()
+    }
+
+    pub fn max_feerate(&self) -> Option<f64> {
+        self.feerates.last().copied()
+    }
+
+    pub fn median_feerate(&self) -> Option<f64> {
+        let count = self.feerates.len();
+        match count {
+            0 => None,
+            _ if count % 2 == 1 => Some(self.feerates[count / 2]),
+            _ => Some((self.feerates[count / 2 - 1] + self.feerates[count / 2]) / 2.0),
+        }
+    }
+}
+
+// None for a block that's only a header. shares are of the transactions after the coinbase,
+// which has no choice in the matter
+pub fn block_stats(block: &BlockData, height: Option<u32>, network: Network, prevouts: &impl PrevoutSource) -> Option<BlockStats> {
+    if block.transactions.is_empty() {
+        return None;
+    }
+    let spending: Vec<&Transaction> = block.transactions.iter().filter(|transaction| !transaction.is_coinbase()).collect();
+
+    let header_size = 80 + write_var_int(block.transactions.len() as u64).len();
+    let stripped_size = header_size + block.transactions.iter().map(|transaction| transaction.stripped_size).sum::<usize>();
+    let size = header_size + block.transactions.iter().map(|transaction| transaction.size).sum::<usize>();
+
+    let mut fees = Vec::new();
+    let mut feerates = Vec::new();
+    for transaction in &spending {
+        if let Some(fee) = transaction_fee(transaction, prevouts) {
+            fees.push(fee);
+            feerates.push(fee as f64 / transaction.vsize() as f64);
+        }
+    }
+    feerates.sort_by(|a, b| a.total_cmp(b));
+
+    let total_fees = if fees.len() == spending.len() {
+        fees.iter().try_fold(0u64, |total, &fee| total.checked_add(fee)).map(|fees| (fees, FeeSource::Transactions))
+    } else {
+        decode_coinbase(block, height, network)
+            .and_then(|coinbase| coinbase.fees())
+            .map(|fees| (fees, FeeSource::Coinbase))
+    };
+
+    let share = |count: usize| {
+        if spending.is_empty() {
+            0.0
+        } else {
+            count as f64 * 100.0 / spending.len() as f64
+        }
+    };
+
+    Some(BlockStats {
+        transactions: block.transactions.len(),
+        size,
+        stripped_size,
+        weight: stripped_size * 3 + size,
+        output_value: spending.iter().flat_map(|transaction| &transaction.outputs).map(|output| output.value).sum(),
+        fees: total_fees,
+        feerates,
+        segwit_share: share(spending.iter().filter(|transaction| transaction.has_witness()).count()),
+        taproot_share: share(spending.iter().filter(|transaction| spends_taproot(transaction)).count()),
+    })
+}
+
+// None unless every input's value is known
+fn transaction_fee(transaction: &Transaction, prevouts: &impl PrevoutSource) -> Option<u64> {
+    let mut inputs = 0u64;
+    for input in &transaction.inputs {
+        // the values come from whatever blocks were loaded, a bogus one mustn't overflow
+        inputs = inputs.checked_add(prevouts.prevout_value(&input.prev_tx_hash, input.prev_output_index)?)?;
+    }
+    let outputs = transaction.outputs.iter().try_fold(0u64, |total, output| total.checked_add(output.value))?;
+    inputs.checked_sub(outputs)
+}
+
+// Without the scripts being spent, taproot inputs are recognised by their witness (BIP341).
+// After dropping an annex, a key path spend is a lone 64 or 65 byte signature and a script
+// path spend ends in a control block: a leaf version byte of 0xc0 or 0xc1 then 32 bytes
+// for the internal key and 32 for each step of the merkle path.
+fn spends_taproot(transaction: &Transaction) -> bool {
+    transaction.inputs.iter().any(|input| {
+        let mut witness = input.witness.as_slice();
+        if witness.len() >= 2 && witness[witness.len() - 1].first() == Some(&0x50) {
+            witness = &witness[..witness.len() - 1];
+        }
+        match witness {
+            [signature] => signature.len() == 64 || signature.len() == 65,
+            [.., control] => {
+                control.len() >= 33 && (control.len() - 33) % 32 == 0 && control[0] & 0xfe == 0xc0
+            }
+            [] => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinbase::block_subsidy;
+    use crate::parsers::parse_transaction;
+    use crate::serialisers::{serialise_block, serialise_transaction};
+    use crate::structures::{TransactionInput, TransactionOutput};
+    use crate::utils::ByteReader;
+
+    // every output it's asked about is worth the same
+    struct Worth(u64);
+
+    impl PrevoutSource for Worth {
+        fn prevout_value(&self, _: &[u8; 32], _: u32) -> Option<u64> {
+            Some(self.0)
+        }
+    }
+
+    fn spending(inputs: u32, outputs: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: (0..inputs)
+                .map(|index| TransactionInput {
+                    prev_tx_hash: [1; 32],
+                    prev_output_index: index,
+                    script_sig: Vec::new(),
+                    sequence: u32::MAX,
+                    witness: Vec::new(),
+                })
+                .collect(),
+            outputs: outputs.iter().map(|&value| TransactionOutput { value, script_pub_key: vec![0x51] }).collect(),
+            locktime: 0,
+            txid: [2; 32],
+            wtxid: [2; 32],
+            size: 0,
+            stripped_size: 0,
+        }
+    }
+
+    // none of the outputs being spent were ever loaded
+    struct Unknown;
+
+    impl PrevoutSource for Unknown {
+        fn prevout_value(&self, _: &[u8; 32], _: u32) -> Option<u64> {
+            None
+        }
+    }
+
+    // regtest halves every 150 blocks, so this is on the second subsidy
+    const HEIGHT: u32 = 200;
+
+    // claims the subsidy and reward on top, with the height pushed as BIP34 has it. 200 needs a
+    // second byte, 0xc8 on its own would read as negative
+    fn coinbase(reward: u64) -> Transaction {
+        let mut coinbase = spending(1, &[block_subsidy(HEIGHT, Network::Regtest) + reward]);
+        coinbase.inputs[0].prev_tx_hash = [0; 32];
+        coinbase.inputs[0].prev_output_index = u32::MAX;
+        coinbase.inputs[0].script_sig = vec![0x02, HEIGHT as u8, 0x00];
+        coinbase
+    }
+
+    fn witnessed(mut transaction: Transaction, witness: &[usize]) -> Transaction {
+        transaction.inputs[0].witness = witness.iter().map(|&length| vec![0x30; length]).collect();
+        transaction
+    }
+
+    fn block(transactions: Vec<Transaction>) -> BlockData {
+        let mut block = Network::Regtest.genesis_header();
+        block.prev_block_hash = [1; 32];
+        // through the wire format and back, for the sizes and hashes parsing works out
+        block.transactions = transactions
+            .iter()
+            .map(|transaction| parse_transaction(&mut ByteReader::new(&serialise_transaction(transaction))).unwrap())
+            .collect();
+        block
+    }
+
+    fn stats(block: &BlockData, prevouts: &impl PrevoutSource) -> BlockStats {
+        block_stats(block, Some(HEIGHT), Network::Regtest, prevouts).unwrap()
+    }
+
+    fn with_feerates(feerates: &[f64]) -> BlockStats {
+        BlockStats {
+            transactions: 0,
+            size: 0,
+            stripped_size: 0,
+            weight: 0,
+            output_value: 0,
+            fees: None,
+            feerates: feerates.to_vec(),
+            segwit_share: 0.0,
+            taproot_share: 0.0,
+        }
+    }
+
+    #[test]
+    fn header_only_blocks_have_no_stats() {
+        assert!(block_stats(&block(Vec::new()), Some(HEIGHT), Network::Regtest, &Worth(1)).is_none());
+    }
+
+    #[test]
+    fn sizes_count_witnesses_once() {
+        // a 64 byte coinbase, a 61 byte legacy spend and the same spend with a 109 byte witness
+        let block = block(vec![coinbase(0), spending(1, &[900]), witnessed(spending(1, &[900]), &[71, 33])]);
+        let stats = stats(&block, &Worth(1000));
+
+        assert_eq!(stats.transactions, 3);
+        assert_eq!(stats.size, 80 + 1 + 64 + 61 + 61 + 109);
+        assert_eq!(stats.size, serialise_block(&block).len());
+        assert_eq!(stats.stripped_size, 80 + 1 + 64 + 61 + 61);
+        assert_eq!(stats.weight, stats.stripped_size * 4 + 109);
+        assert_eq!(stats.output_value, 1800);
+    }
+
+    #[test]
+    fn feerates_are_per_virtual_byte_lowest_first() {
+        // vsizes of 61 and 89, (61 * 3 + 170) / 4 rounded up
+        let block = block(vec![coinbase(0), spending(1, &[39]), witnessed(spending(1, &[0]), &[71, 33])]);
+        let stats = stats(&block, &Worth(1000));
+
+        assert_eq!(stats.feerates, [1000.0 / 89.0, 961.0 / 61.0]);
+        assert!(matches!(stats.fees, Some((1961, FeeSource::Transactions))));
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(with_feerates(&[]).median_feerate(), None);
+        assert_eq!(with_feerates(&[4.0]).median_feerate(), Some(4.0));
+        assert_eq!(with_feerates(&[1.0, 2.0, 9.0]).median_feerate(), Some(2.0));
+        assert_eq!(with_feerates(&[1.0, 2.0, 3.0, 9.0]).median_feerate(), Some(2.5));
+
+        let stats = with_feerates(&[1.0, 2.0, 3.0, 9.0]);
+        assert_eq!(stats.min_feerate(), Some(1.0));
+        assert_eq!(stats.max_feerate(), Some(9.0));
+    }
+
+    #[test]
+    fn fees_fall_back_to_the_coinbase() {
+        let block = block(vec![coinbase(2500), spending(1, &[900])]);
+
+        let stats = stats(&block, &Unknown);
+        assert!(matches!(stats.fees, Some((2500, FeeSource::Coinbase))));
+        assert!(stats.feerates.is_empty());
+
+        // without a height, the one in the coinbase gives the subsidy
+        let stats = block_stats(&block, None, Network::Regtest, &Unknown).unwrap();
+        assert!(matches!(stats.fees, Some((2500, FeeSource::Coinbase))));
+    }
+
+    #[test]
+    fn shares_leave_out_the_coinbase() {
+        let block = block(vec![
+            witnessed(coinbase(0), &[32]),
+            spending(1, &[900]),
+            witnessed(spending(1, &[900]), &[71, 33]),
+            // a key path spend, then a script path one with a control block one step deep
+            witnessed(spending(1, &[900]), &[64]),
+            witnessed(spending(1, &[900]), &[64, 34, 65]),
+        ]);
+        let mut with_control_block = block.clone();
+        with_control_block.transactions[4].inputs[0].witness[2][0] = 0xc0;
+
+        let stats = stats(&with_control_block, &Worth(1000));
+        assert_eq!(stats.segwit_share, 75.0);
+        assert_eq!(stats.taproot_share, 50.0);
+
+        // the same witness without a leaf version isn't taproot
+        let stats = block_stats(&block, Some(HEIGHT), Network::Regtest, &Worth(1000)).unwrap();
+        assert_eq!(stats.taproot_share, 25.0);
+    }
+
+    #[test]
+    fn fee_is_inputs_less_outputs() {
+        assert_eq!(transaction_fee(&spending(2, &[1500, 400]), &Worth(1000)), Some(100));
+        assert_eq!(transaction_fee(&spending(1, &[1500]), &Worth(1000)), None);
+    }
+
+    #[test]
+    fn overflowing_values_have_no_fee() {
+        assert_eq!(transaction_fee(&spending(2, &[1]), &Worth(u64::MAX)), None);
+        assert_eq!(transaction_fee(&spending(1, &[u64::MAX, 1]), &Worth(5)), None);
+    }
+}
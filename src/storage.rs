@@ -0,0 +1,560 @@
+// blocks and headers kept on disk between runs, so a restart picks up where the last one
+// left off instead of downloading everything again
+//
+// everything lives under <data dir>/<network>/:
+//   headers.dat        every header in the order it was indexed: header (80) | checksum (4)
+//   blocks.idx         one entry per stored block:
+//                      hash (32) | file (4) | offset (8) | length (4) | checksum (4) | entry checksum (4)
+//   heights.idx        the active chain as it changed: height (4) | hash (32) | checksum (4)
+//   blk00000.dat ...   raw blocks back to back: magic (4) | length (4, le) | checksum (4) | block
+//
+// a heights.idx record puts its hash at that height and drops everything above it, so a reorg
+// is written as the new branch from the fork point up and the last record is always the tip.
+// checksums are the first four bytes of the double sha256, the same as the wire format uses.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::network::Network;
+use crate::parsers::{parse_block, parse_block_header};
+use crate::serialisers::{bitcoin_checksum, serialise_block, serialise_block_header};
+use crate::structures::{BlockData, BlockHash, ChainPosition};
+use crate::utils::ByteReader;
+
+const HEADERS_FILE: &str = "headers.dat";
+const INDEX_FILE: &str = "blocks.idx";
+const HEIGHTS_FILE: &str = "heights.idx";
+
+const HEADER_RECORD_SIZE: usize = 84;
+const INDEX_ENTRY_SIZE: usize = 56;
+const HEIGHT_RECORD_SIZE: usize = 40;
+const BLOCK_RECORD_HEADER_SIZE: usize = 12;
+
+// bitcoin core moves on to a new blk file at the same size
+const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+// part of a file that didn't check out and was dropped
+pub struct Corruption {
+    pub file: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.reason)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BlockLocation {
+    file: u32,
+    offset: u64,
+    length: u32,
+    checksum: [u8; 4],
+}
+
+pub struct BlockStore {
+    dir: PathBuf,
+    magic: [u8; 4],
+    headers: File,
+    index: File,
+    heights_file: File,
+    locations: HashMap<BlockHash, BlockLocation>,
+    // the active chain as of the last update, by height
+    heights: BTreeMap<u32, BlockHash>,
+    // the blk file new blocks are appended to
+    current_file: u32,
+}
+
+impl BlockStore {
+    // Opens the network's store, creating it if needed, and hands back the stored headers so
+    // they can be replayed into the chain index. Anything that fails its checksum, along with
+    // everything after it in the same file, is reported through on_corruption and cut off so
+    // new records carry on from the last good one.
+    pub fn open(
+        data_dir: &Path,
+        network: Network,
+        on_corruption: &mut dyn FnMut(&Corruption),
+    ) -> Result<(BlockStore, Vec<BlockData>), Box<dyn Error>> {
+        let dir = data_dir.join(network.name());
+        fs::create_dir_all(&dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+
+        let headers = load_records(&dir.join(HEADERS_FILE), HEADER_RECORD_SIZE, on_corruption, |record| {
+            let (header, checksum) = record.split_at(80);
+            if bitcoin_checksum(header) != checksum {
+                return Err("header checksum mismatch".into());
+            }
+            parse_block_header(&mut ByteReader::new(header))
+        })?;
+
+        let mut locations = HashMap::new();
+        let entries = load_records(&dir.join(INDEX_FILE), INDEX_ENTRY_SIZE, on_corruption, parse_index_entry)?;
+        for (hash, location) in entries {
+            // a block stored again after its first copy went bad, the newest copy wins
+            locations.insert(hash, location);
+        }
+
+        let mut heights = BTreeMap::new();
+        for (height, hash) in load_records(&dir.join(HEIGHTS_FILE), HEIGHT_RECORD_SIZE, on_corruption, parse_height_record)? {
+            set_height(&mut heights, height, hash);
+        }
+
+        let store = BlockStore {
+            headers: open_for_append(&dir.join(HEADERS_FILE))?,
+            index: open_for_append(&dir.join(INDEX_FILE))?,
+            heights_file: open_for_append(&dir.join(HEIGHTS_FILE))?,
+            current_file: last_block_file(&dir)?,
+            dir,
+            magic: network.magic(),
+            locations,
+            heights,
+        };
+        Ok((store, headers))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        self.locations.contains_key(hash)
+    }
+
+    // the block at height on the active chain as it was last saved
+    pub fn block_hash_at(&self, height: u32) -> Option<BlockHash> {
+        self.heights.get(&height).copied()
+    }
+
+    // Records headers that joined the active chain, oldest first. Each one replaces whatever
+    // was at its height and above, which is how a reorg's new branch takes over.
+    pub fn save_heights(&mut self, connected: &[(ChainPosition, BlockData)]) -> Result<(), Box<dyn Error>> {
+        let mut bytes = Vec::with_capacity(connected.len() * HEIGHT_RECORD_SIZE);
+        for (position, header) in connected {
+            bytes.extend(serialise_height_record(position.height, &header.block_hash));
+        }
+        append(&mut self.heights_file, &bytes)?;
+
+        for (position, header) in connected {
+            set_height(&mut self.heights, position.height, header.block_hash);
+        }
+        Ok(())
+    }
+
+    // starts the height index over from the whole active chain, genesis first
+    pub fn replace_heights(&mut self, active: &[(ChainPosition, BlockData)]) -> Result<(), Box<dyn Error>> {
+        self.heights_file.set_len(0)?;
+        self.heights.clear();
+        self.save_heights(active)
+    }
+
+    pub fn save_headers(&mut self, headers: &[BlockData]) -> Result<(), Box<dyn Error>> {
+        let mut bytes = Vec::with_capacity(headers.len() * HEADER_RECORD_SIZE);
+        for header in headers {
+            let header = serialise_block_header(header);
+            bytes.extend(&header);
+            bytes.extend(bitcoin_checksum(&header));
+        }
+        append(&mut self.headers, &bytes)?;
+        Ok(())
+    }
+
+    // the block goes to disk before its index entry, so a crash in between only leaves some
+    // unreferenced bytes at the end of a blk file
+    pub fn save_block(&mut self, block: &BlockData) -> Result<(), Box<dyn Error>> {
+        if self.has_block(&block.block_hash) {
+            return Ok(());
+        }
+
+        let bytes = serialise_block(block);
+        let record_size = (BLOCK_RECORD_HEADER_SIZE + bytes.len()) as u64;
+        let mut file = open_for_append(&self.dir.join(block_file_name(self.current_file)))?;
+        let size = file.metadata()?.len();
+        if size > 0 && size + record_size > MAX_BLOCK_FILE_SIZE {
+            self.current_file += 1;
+            file = open_for_append(&self.dir.join(block_file_name(self.current_file)))?;
+        }
+
+        let mut record = Vec::with_capacity(record_size as usize);
+        record.extend(self.magic);
+        record.extend((bytes.len() as u32).to_le_bytes());
+        record.extend(bitcoin_checksum(&bytes));
+        record.extend(&bytes);
+        let location = BlockLocation {
+            file: self.current_file,
+            offset: append(&mut file, &record)?,
+            length: bytes.len() as u32,
+            checksum: bitcoin_checksum(&bytes),
+        };
+
+        append(&mut self.index, &serialise_index_entry(&block.block_hash, &location))?;
+        self.locations.insert(block.block_hash, location);
+        Ok(())
+    }
+
+    // None if the block was never stored. A stored copy that fails its checks is forgotten,
+    // so the next time the block is downloaded it gets written out again.
+    pub fn read_block(&mut self, hash: &BlockHash) -> Result<Option<BlockData>, Box<dyn Error>> {
+        let location = match self.locations.get(hash) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+
+        match self.read_record(hash, &location) {
+            Ok(block) => Ok(Some(block)),
+            Err(e) => {
+                self.locations.remove(hash);
+                Err(format!("Stored block {} is corrupt: {}", hash, e).into())
+            }
+        }
+    }
+
+    fn read_record(&self, hash: &BlockHash, location: &BlockLocation) -> Result<BlockData, Box<dyn Error>> {
+        let mut file = File::open(self.dir.join(block_file_name(location.file)))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+
+        let mut record = vec![0; BLOCK_RECORD_HEADER_SIZE + location.length as usize];
+        file.read_exact(&mut record)?;
+
+        let mut reader = ByteReader::new(&record);
+        if reader.read_array::<4>()? != self.magic {
+            return Err("wrong magic".into());
+        }
+        if reader.read_u32()? != location.length {
+            return Err("length doesn't match the index".into());
+        }
+        let checksum: [u8; 4] = reader.read_array()?;
+        let bytes = reader.read_remaining();
+        if checksum != location.checksum || bitcoin_checksum(&bytes) != checksum {
+            return Err("checksum mismatch".into());
+        }
+
+        let block = parse_block(&bytes)?;
+        if block.block_hash != *hash {
+            return Err(format!("file holds block {} instead", block.block_hash).into());
+        }
+        Ok(block)
+    }
+}
+
+fn block_file_name(number: u32) -> String {
+    format!("blk{:05}.dat", number)
+}
+
+// the highest numbered blk file there is, or 0 for an empty store
+fn last_block_file(dir: &Path) -> Result<u32, Box<dyn Error>> {
+    let mut last = 0;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("blk"))
+            .and_then(|name| name.strip_suffix(".dat"))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            last = u32::max(last, number);
+        }
+    }
+    Ok(last)
+}
+
+fn open_for_append(path: &Path) -> Result<File, Box<dyn Error>> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Could not open {}: {}", path.display(), e).into())
+}
+
+// Writes bytes to the end of a file opened for appending and returns the offset they start
+// at. A write that fails part way is cut back off, so the next one starts where this one
+// should have and nothing is left half written in between.
+fn append(file: &mut File, bytes: &[u8]) -> Result<u64, Box<dyn Error>> {
+    let offset = file.metadata()?.len();
+    if let Err(e) = file.write_all(bytes) {
+        let _ = file.set_len(offset);
+        return Err(e.into());
+    }
+    Ok(offset)
+}
+
+// Reads a file of fixed size records, stopping at the first one parse rejects or a partial
+// one at the end. The file is truncated there so later appends follow the last good record.
+fn load_records<T>(
+    path: &Path,
+    record_size: usize,
+    on_corruption: &mut dyn FnMut(&Corruption),
+    mut parse: impl FnMut(&[u8]) -> Result<T, Box<dyn Error>>,
+) -> Result<Vec<T>, Box<dyn Error>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut records = Vec::new();
+    let mut problem = None;
+    for (number, record) in bytes.chunks(record_size).enumerate() {
+        let parsed = if record.len() < record_size {
+            Err("record cut short".into())
+        } else {
+            parse(record)
+        };
+        match parsed {
+            Ok(parsed) => records.push(parsed),
+            Err(e) => {
+                problem = Some(format!("record {} is unreadable ({}), dropping it and everything after it", number, e));
+                break;
+            }
+        }
+    }
+
+    if let Some(reason) = problem {
+        on_corruption(&Corruption {
+            file: path.display().to_string(),
+            reason,
+        });
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len((records.len() * record_size) as u64)?;
+    }
+
+    Ok(records)
+}
+
+fn serialise_index_entry(hash: &BlockHash, location: &BlockLocation) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(INDEX_ENTRY_SIZE);
+    entry.extend(hash.0);
+    entry.extend(location.file.to_le_bytes());
+    entry.extend(location.offset.to_le_bytes());
+    entry.extend(location.length.to_le_bytes());
+    entry.extend(location.checksum);
+    entry.extend(bitcoin_checksum(&entry));
+    entry
+}
+
+fn parse_index_entry(entry: &[u8]) -> Result<(BlockHash, BlockLocation), Box<dyn Error>> {
+    let (body, checksum) = entry.split_at(INDEX_ENTRY_SIZE - 4);
+    if bitcoin_checksum(body) != checksum {
+        return Err("index entry checksum mismatch".into());
+    }
+
+    let mut reader = ByteReader::new(body);
+    let hash = BlockHash(reader.read_hash()?);
+    let location = BlockLocation {
+        file: reader.read_u32()?,
+        offset: reader.read_u64()?,
+        length: reader.read_u32()?,
+        checksum: reader.read_array()?,
+    };
+    Ok((hash, location))
+}
+
+fn set_height(heights: &mut BTreeMap<u32, BlockHash>, height: u32, hash: BlockHash) {
+    heights.split_off(&height);
+    heights.insert(height, hash);
+}
+
+fn serialise_height_record(height: u32, hash: &BlockHash) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEIGHT_RECORD_SIZE);
+    record.extend(height.to_le_bytes());
+    record.extend(hash.0);
+    record.extend(bitcoin_checksum(&record));
+    record
+}
+
+fn parse_height_record(record: &[u8]) -> Result<(u32, BlockHash), Box<dyn Error>> {
+    let (body, checksum) = record.split_at(HEIGHT_RECORD_SIZE - 4);
+    if bitcoin_checksum(body) != checksum {
+        return Err("height record checksum mismatch".into());
+    }
+
+    let mut reader = ByteReader::new(body);
+    Ok((reader.read_u32()?, BlockHash(reader.read_hash()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blkfiles::tests::TempDir;
+
+    // one of the store's files, which live under the network's name
+    fn file(dir: &TempDir, name: &str) -> PathBuf {
+        dir.0.join(Network::Regtest.name()).join(name)
+    }
+
+    // a block holding one coinbase paying n satoshis, so each n gives a different block
+    fn block(n: u32) -> BlockData {
+        let mut header = Network::Regtest.genesis_header();
+        header.nonce = n;
+
+        let mut bytes = serialise_block_header(&header);
+        bytes.push(1);
+        bytes.extend(hex::decode("0100000001").unwrap());
+        bytes.extend([0; 32]);
+        bytes.extend(hex::decode("ffffffff00ffffffff01").unwrap());
+        bytes.extend((n as u64).to_le_bytes());
+        bytes.extend(hex::decode("0000000000").unwrap());
+        parse_block(&bytes).unwrap()
+    }
+
+    // opens the store, returning what it found and how many corruptions it reported
+    fn open(dir: &TempDir) -> (BlockStore, Vec<BlockData>, usize) {
+        let mut corruptions = 0;
+        let (store, headers) = BlockStore::open(&dir.0, Network::Regtest, &mut |_| corruptions += 1).unwrap();
+        (store, headers, corruptions)
+    }
+
+    fn flip_byte(path: &Path, offset: usize) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn headers_and_blocks_survive_a_restart() {
+        let dir = TempDir::new("restart");
+        let (mut store, headers, _) = open(&dir);
+        assert!(headers.is_empty());
+        store.save_headers(&[block(1), block(2)]).unwrap();
+        store.save_block(&block(1)).unwrap();
+        drop(store);
+
+        let (mut store, headers, corruptions) = open(&dir);
+        assert_eq!(corruptions, 0);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].block_hash, block(2).block_hash);
+        let stored = store.read_block(&block(1).block_hash).unwrap().unwrap();
+        assert_eq!(stored.transactions[0].outputs[0].value, 1);
+        assert!(store.read_block(&block(2).block_hash).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_header_record_is_dropped() {
+        let dir = TempDir::new("truncated-header");
+        let (mut store, _, _) = open(&dir);
+        store.save_headers(&[block(1), block(2)]).unwrap();
+        drop(store);
+
+        let path = file(&dir, HEADERS_FILE);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len((2 * HEADER_RECORD_SIZE - 10) as u64).unwrap();
+        drop(file);
+
+        let (mut store, headers, corruptions) = open(&dir);
+        assert_eq!(corruptions, 1);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].block_hash, block(1).block_hash);
+        // cut back to the last whole record, so the next one lines up again
+        assert_eq!(fs::metadata(&path).unwrap().len(), HEADER_RECORD_SIZE as u64);
+
+        store.save_headers(&[block(3)]).unwrap();
+        drop(store);
+        let (_, headers, corruptions) = open(&dir);
+        assert_eq!(corruptions, 0);
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn index_entry_with_a_bad_checksum_is_ignored() {
+        let dir = TempDir::new("bad-index");
+        let (mut store, _, _) = open(&dir);
+        store.save_block(&block(1)).unwrap();
+        store.save_block(&block(2)).unwrap();
+        drop(store);
+
+        // inside the second entry's offset field
+        flip_byte(&file(&dir, INDEX_FILE), INDEX_ENTRY_SIZE + 40);
+
+        let (mut store, _, corruptions) = open(&dir);
+        assert_eq!(corruptions, 1);
+        assert_eq!(store.block_count(), 1);
+        assert!(store.read_block(&block(1).block_hash).unwrap().is_some());
+        assert!(store.read_block(&block(2).block_hash).unwrap().is_none());
+    }
+
+    #[test]
+    fn flipped_block_byte_fails_the_read() {
+        let dir = TempDir::new("flipped-block");
+        let (mut store, _, _) = open(&dir);
+        store.save_block(&block(1)).unwrap();
+        drop(store);
+
+        // inside the coinbase, past the record header and the block header
+        flip_byte(&file(&dir, &block_file_name(0)), BLOCK_RECORD_HEADER_SIZE + 100);
+
+        let (mut store, _, corruptions) = open(&dir);
+        assert_eq!(corruptions, 0);
+        assert!(store.read_block(&block(1).block_hash).is_err());
+        // the bad copy is forgotten, so the block counts as missing and gets stored again
+        assert!(store.read_block(&block(1).block_hash).unwrap().is_none());
+        store.save_block(&block(1)).unwrap();
+        assert!(store.read_block(&block(1).block_hash).unwrap().is_some());
+    }
+
+    fn at(height: u32, header: BlockData) -> (ChainPosition, BlockData) {
+        (ChainPosition { height, chainwork: height as u128 }, header)
+    }
+
+    #[test]
+    fn heights_survive_a_restart() {
+        let dir = TempDir::new("heights");
+        let (mut store, _, _) = open(&dir);
+        store.save_heights(&[at(0, block(0)), at(1, block(1))]).unwrap();
+        store.save_heights(&[at(2, block(2))]).unwrap();
+        drop(store);
+
+        let (store, _, corruptions) = open(&dir);
+        assert_eq!(corruptions, 0);
+        assert_eq!(store.block_hash_at(0), Some(block(0).block_hash));
+        assert_eq!(store.block_hash_at(2), Some(block(2).block_hash));
+        assert_eq!(store.block_hash_at(3), None);
+    }
+
+    #[test]
+    fn reorg_replaces_the_heights_above_the_fork() {
+        let dir = TempDir::new("heights-reorg");
+        let (mut store, _, _) = open(&dir);
+        store.save_heights(&[at(0, block(0)), at(1, block(1)), at(2, block(2)), at(3, block(3))]).unwrap();
+        // a branch from height 1 with more work but fewer blocks
+        store.save_heights(&[at(2, block(12))]).unwrap();
+        assert_eq!(store.block_hash_at(2), Some(block(12).block_hash));
+        assert_eq!(store.block_hash_at(3), None);
+        drop(store);
+
+        let (mut store, _, _) = open(&dir);
+        assert_eq!(store.block_hash_at(1), Some(block(1).block_hash));
+        assert_eq!(store.block_hash_at(2), Some(block(12).block_hash));
+        assert_eq!(store.block_hash_at(3), None);
+
+        store.replace_heights(&[at(0, block(0)), at(1, block(21))]).unwrap();
+        drop(store);
+        let (store, _, _) = open(&dir);
+        assert_eq!(store.block_hash_at(1), Some(block(21).block_hash));
+        assert_eq!(store.block_hash_at(2), None);
+        assert_eq!(fs::metadata(file(&dir, HEIGHTS_FILE)).unwrap().len(), 2 * HEIGHT_RECORD_SIZE as u64);
+    }
+
+    #[test]
+    fn blocks_go_after_whatever_is_in_the_file() {
+        let dir = TempDir::new("stray-bytes");
+        let (mut store, _, _) = open(&dir);
+        store.save_block(&block(1)).unwrap();
+
+        // the tail of a write that failed part way, or one from a run that crashed
+        let mut file = open_for_append(&file(&dir, &block_file_name(0))).unwrap();
+        file.write_all(&[0xab; 37]).unwrap();
+        drop(file);
+
+        store.save_block(&block(2)).unwrap();
+        assert!(store.read_block(&block(1).block_hash).unwrap().is_some());
+        assert!(store.read_block(&block(2).block_hash).unwrap().is_some());
+    }
+}
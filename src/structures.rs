@@ -1,16 +1,30 @@
 // version payload
 
-use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::Duration;
+
+use crate::serialisers::{serialise_block_header, sha256d};
+use crate::utils::reversed_hex;
 
 
 #[derive(Debug)]
 pub struct BitcoinMessage {
     pub magic: u32,
     pub command: String,
-    pub length: u32,
     pub payload: Vec<u8>
 }
 
+impl BitcoinMessage {
+    pub fn new(magic: [u8; 4], command: &str, payload: Vec<u8>) -> BitcoinMessage {
+        BitcoinMessage {
+            magic: u32::from_le_bytes(magic),
+            command: command.to_string(),
+            payload,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct VersionPayload {
     pub version: i32,
     pub services: u64,
@@ -23,13 +37,125 @@ pub struct VersionPayload {
     pub relay: bool,
 }
 
+// peers are numbered in the order the peer manager starts them
+pub type PeerId = usize;
+
+// what the network threads tell the ui about
+pub enum NetworkEvent {
+    Block(BlockData),
+    Chain(ChainUpdate),
+    PeerConnected(PeerId, PeerInfo),
+    PeerUpdated(PeerId, PeerStats),
+    PeerDisconnected(PeerId),
+    // a block from before the ones already listed, fetched because the ui asked for it
+    BackfilledBlock(BlockData),
+    // the backfill reached its depth, hit genesis or lost its peer
+    BackfillFinished,
+    // the peer sent headers that failed validation, it's disconnected straight after
+    HeadersRejected(PeerId, String),
+    // a block or header couldn't be written to the store, it'll be downloaded again next run
+    StorageFailed(String),
+}
+
+// what the ui asks of the network threads
+#[derive(Clone, Copy)]
+pub enum NetworkCommand {
+    // fetch start and then keep following prev_block_hash until depth blocks have come in
+    Backfill { start: BlockHash, depth: u32 },
+}
+
+// where a header sits in the chain index
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChainPosition {
+    pub height: u32,
+    // total expected hashes to build the chain up to and including this block
+    pub chainwork: u128,
+}
+
+// the active chain switched to a branch with more work
+#[derive(Clone, Debug)]
+pub struct Reorg {
+    // height of the last block both branches share
+    pub fork_height: u32,
+    pub old_tip: BlockHash,
+    pub new_tip: BlockHash,
+    pub new_height: u32,
+    // blocks that dropped off the active chain, lowest first
+    pub disconnected: Vec<BlockHash>,
+}
+
+// what adding a batch of headers did to the active chain
+#[derive(Clone, Default)]
+pub struct ChainUpdate {
+    // headers that joined the active chain, oldest first
+    pub connected: Vec<(ChainPosition, BlockData)>,
+    pub reorgs: Vec<Reorg>,
+    // branches we know of that have less work than the active chain
+    pub stale_branches: usize,
+}
+
+impl ChainUpdate {
+    // folds in an update that happened after this one, as if both were a single batch
+    pub fn append(&mut self, later: ChainUpdate) {
+        for reorg in &later.reorgs {
+            self.connected.retain(|(_, header)| !reorg.disconnected.contains(&header.block_hash));
+        }
+        self.connected.extend(later.connected);
+        self.reorgs.extend(later.reorgs);
+        self.stale_branches = later.stale_branches;
+    }
+}
+
+// traffic counters and round trip time for one peer, sent to the ui as they change
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct PeerStats {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // None until the peer answers one of our pings
+    pub ping: Option<Duration>,
+}
+
+// what we learnt about a peer from its version message and the rest of the handshake
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub address: String,
+    pub version: i32,
+    // the lower of our version and theirs, what both sides will actually speak
+    pub negotiated_version: i32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+    // set when the peer sent wtxidrelay / sendaddrv2 before its verack
+    pub wtxid_relay: bool,
+    pub addr_v2: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct NetAddr {
     // pub time: u32,
     pub services: u64,
+    // written as text, "a.b.c.d" for ipv4 peers and the usual colon form for ipv6 ones
     pub ip_v6_4: String,
     pub port: u16
 }
 
+// double sha256 of the block header, kept in internal byte order and displayed reversed
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct BlockHash(pub [u8; 32]);
+
+impl From<[u8; 32]> for BlockHash {
+    fn from(bytes: [u8; 32]) -> Self {
+        BlockHash(bytes)
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", reversed_hex(&self.0))
+    }
+}
+
 #[derive(Clone)]
 pub struct BlockData {
     pub version: u32,
@@ -38,54 +164,201 @@ pub struct BlockData {
     pub timestamp: u32,
     pub bits: u32,
     pub nonce: u32,
-    pub block_hash: Vec<u8>,
+    pub block_hash: BlockHash,
     pub transactions: Vec<Transaction>,
 }
 
 
 impl BlockData {
-    fn calculate_hash(&mut self) {
-        let mut headers = Vec::new();
-        headers.extend(&self.version.to_le_bytes());
-        headers.extend(&self.prev_block_hash);
-        headers.extend(&self.merkle_root);
-        headers.extend(&self.timestamp.to_le_bytes());
-        headers.extend(&self.bits.to_le_bytes());
-        headers.extend(&self.nonce.to_le_bytes());
-
-        let first_hash = Sha256::digest(&headers);
-        let second_hash = Sha256::digest(&first_hash);
-        self.block_hash = second_hash.to_vec();
+    pub fn calculate_hash(&mut self) {
+        self.block_hash = BlockHash(sha256d(&serialise_block_header(self)));
     }
 
     pub fn convert_date(&self) -> String {
-        let date = chrono::NaiveDateTime::from_timestamp(self.timestamp as i64, 0);
+        // any u32 of seconds is in range, the default is never used
+        let date = chrono::DateTime::from_timestamp(self.timestamp as i64, 0).unwrap_or_default();
         date.format("%A %e %B %Y at %H:%M").to_string()
     }
 }
 
 
-pub enum Command {
-    Version,
+// every message we know how to read or write, keyed by its command string
+#[derive(Clone)]
+pub enum NetworkMessage {
+    Version(VersionPayload),
     Verack,
-    Pong,
-    GetData
+    Ping(PingPayload),
+    Pong(PingPayload),
+    Inv(InvPayload),
+    GetData(InvPayload),
+    NotFound(InvPayload),
+    GetBlocks(BlockLocatorPayload),
+    GetHeaders(BlockLocatorPayload),
+    Headers(HeadersPayload),
+    Block(BlockData),
+    Tx(Transaction),
+    Addr(AddrPayload),
+    AddrV2(AddrV2Payload),
+    SendHeaders,
+    SendCmpct(SendCmpctPayload),
+    FeeFilter(FeeFilterPayload),
+    WtxidRelay,
+    SendAddrV2,
+    MemPool,
+    Reject(RejectPayload),
+    Unknown { command: String, payload: Vec<u8> },
 }
 
+impl NetworkMessage {
+    pub fn command(&self) -> &str {
+        match self {
+            NetworkMessage::Version(_) => "version",
+            NetworkMessage::Verack => "verack",
+            NetworkMessage::Ping(_) => "ping",
+            NetworkMessage::Pong(_) => "pong",
+            NetworkMessage::Inv(_) => "inv",
+            NetworkMessage::GetData(_) => "getdata",
+            NetworkMessage::NotFound(_) => "notfound",
+            NetworkMessage::GetBlocks(_) => "getblocks",
+            NetworkMessage::GetHeaders(_) => "getheaders",
+            NetworkMessage::Headers(_) => "headers",
+            NetworkMessage::Block(_) => "block",
+            NetworkMessage::Tx(_) => "tx",
+            NetworkMessage::Addr(_) => "addr",
+            NetworkMessage::AddrV2(_) => "addrv2",
+            NetworkMessage::SendHeaders => "sendheaders",
+            NetworkMessage::SendCmpct(_) => "sendcmpct",
+            NetworkMessage::FeeFilter(_) => "feefilter",
+            NetworkMessage::WtxidRelay => "wtxidrelay",
+            NetworkMessage::SendAddrV2 => "sendaddrv2",
+            NetworkMessage::MemPool => "mempool",
+            NetworkMessage::Reject(_) => "reject",
+            NetworkMessage::Unknown { command, .. } => command,
+        }
+    }
+}
 
-impl Command {
-    pub fn as_bytes(&self) -> [u8; 12] {
+// used by both ping and pong
+#[derive(Clone)]
+pub struct PingPayload {
+    pub nonce: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InvType {
+    Error,
+    Tx,
+    Block,
+    FilteredBlock,
+    CompactBlock,
+    WitnessTx,
+    WitnessBlock,
+    WitnessFilteredBlock,
+    Unknown(u32),
+}
+
+impl InvType {
+    pub fn from_u32(value: u32) -> InvType {
+        match value {
+            0 => InvType::Error,
+            1 => InvType::Tx,
+            2 => InvType::Block,
+            3 => InvType::FilteredBlock,
+            4 => InvType::CompactBlock,
+            0x40000001 => InvType::WitnessTx,
+            0x40000002 => InvType::WitnessBlock,
+            0x40000003 => InvType::WitnessFilteredBlock,
+            other => InvType::Unknown(other),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
         match self {
-            Command::Version => *b"version\0\0\0\0\0",
-            Command::Verack => *b"verack\0\0\0\0\0\0",
-            Command::Pong => *b"pong\0\0\0\0\0\0\0\0",
-            Command::GetData => *b"getdata\0\0\0\0\0",
+            InvType::Error => 0,
+            InvType::Tx => 1,
+            InvType::Block => 2,
+            InvType::FilteredBlock => 3,
+            InvType::CompactBlock => 4,
+            InvType::WitnessTx => 0x40000001,
+            InvType::WitnessBlock => 0x40000002,
+            InvType::WitnessFilteredBlock => 0x40000003,
+            InvType::Unknown(value) => value,
         }
     }
 }
 
+#[derive(Clone)]
+pub struct Inventory {
+    pub inv_type: InvType,
+    pub hash: [u8; 32],
+}
+
+// shared by inv, getdata and notfound
+#[derive(Clone)]
+pub struct InvPayload {
+    pub inventory: Vec<Inventory>,
+}
+
+// shared by getblocks and getheaders
+#[derive(Clone)]
+pub struct BlockLocatorPayload {
+    pub version: u32,
+    pub locator_hashes: Vec<[u8; 32]>,
+    pub hash_stop: [u8; 32],
+}
+
+// headers come through as blocks with no transactions
+#[derive(Clone)]
+pub struct HeadersPayload {
+    pub headers: Vec<BlockData>,
+}
+
+#[derive(Clone)]
+pub struct AddrEntry {
+    pub time: u32,
+    pub addr: NetAddr,
+}
 
+#[derive(Clone)]
+pub struct AddrPayload {
+    pub addresses: Vec<AddrEntry>,
+}
 
+// BIP155 address, the length of addr depends on network_id
+#[derive(Clone)]
+pub struct AddrV2Entry {
+    pub time: u32,
+    pub services: u64,
+    pub network_id: u8,
+    pub addr: Vec<u8>,
+    pub port: u16,
+}
+
+#[derive(Clone)]
+pub struct AddrV2Payload {
+    pub addresses: Vec<AddrV2Entry>,
+}
+
+#[derive(Clone)]
+pub struct SendCmpctPayload {
+    pub announce: bool,
+    pub version: u64,
+}
+
+#[derive(Clone)]
+pub struct FeeFilterPayload {
+    // sat per 1000 bytes
+    pub feerate: u64,
+}
+
+#[derive(Clone)]
+pub struct RejectPayload {
+    pub message: String,
+    pub code: u8,
+    pub reason: String,
+    // usually the hash of the rejected tx or block, empty otherwise
+    pub data: Vec<u8>,
+}
 
 #[derive(Clone)]
 pub struct Transaction {
@@ -93,6 +366,38 @@ pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
     pub locktime: u32,
+    // both in internal byte order, computed when the transaction is parsed
+    pub txid: [u8; 32],
+    pub wtxid: [u8; 32],
+    // bytes on the wire with the witnesses and without them, also from parsing
+    pub size: usize,
+    pub stripped_size: usize,
+}
+
+impl Transaction {
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    // BIP141: witness bytes count once, everything else four times
+    pub fn weight(&self) -> usize {
+        self.stripped_size * 3 + self.size
+    }
+
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].is_coinbase()
+    }
+}
+
+impl TransactionInput {
+    // a coinbase input spends nothing, its outpoint is all zeros and index 0xffffffff
+    pub fn is_coinbase(&self) -> bool {
+        self.prev_tx_hash == [0; 32] && self.prev_output_index == 0xffffffff
+    }
 }
 
 #[derive(Clone)]
@@ -101,10 +406,65 @@ pub struct TransactionInput {
     pub prev_output_index: u32,
     pub script_sig: Vec<u8>,
     pub sequence: u32,
+    // empty for legacy inputs
+    pub witness: Vec<Vec<u8>>,
 }
 
 #[derive(Clone)]
 pub struct TransactionOutput {
     pub value: u64,
     pub script_pub_key: Vec<u8>,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+
+    fn header(n: u32) -> BlockData {
+        let mut header = Network::Regtest.genesis_header();
+        header.nonce = n;
+        header.calculate_hash();
+        header
+    }
+
+    fn at(height: u32) -> (ChainPosition, BlockData) {
+        (ChainPosition { height, chainwork: height as u128 }, header(height))
+    }
+
+    #[test]
+    fn block_hash_displays_reversed() {
+        let mut genesis = Network::Mainnet.genesis_header();
+        genesis.calculate_hash();
+        assert_eq!(
+            genesis.block_hash.to_string(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(genesis.block_hash.0[31], 0x00);
+        assert_eq!(genesis.block_hash.0[0], 0x6f);
+    }
+
+    #[test]
+    fn appended_update_drops_what_its_reorg_disconnected() {
+        let mut update = ChainUpdate { connected: vec![at(1), at(2), at(3)], reorgs: Vec::new(), stale_branches: 0 };
+        let reorg = Reorg {
+            fork_height: 1,
+            old_tip: header(3).block_hash,
+            new_tip: header(14).block_hash,
+            new_height: 4,
+            disconnected: vec![header(2).block_hash, header(3).block_hash],
+        };
+        update.append(ChainUpdate { connected: vec![at(12), at(13), at(14)], reorgs: vec![reorg], stale_branches: 1 });
+
+        let connected: Vec<BlockHash> = update.connected.iter().map(|(_, header)| header.block_hash).collect();
+        let expected: Vec<BlockHash> = [1, 12, 13, 14].iter().map(|&n| header(n).block_hash).collect();
+        assert_eq!(connected, expected);
+        assert_eq!(update.reorgs.len(), 1);
+        assert_eq!(update.stale_branches, 1);
+    }
+
+    #[test]
+    fn dates_are_utc() {
+        assert_eq!(Network::Mainnet.genesis_header().convert_date(), "Saturday  3 January 2009 at 18:15");
+    }
+}
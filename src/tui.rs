@@ -1,29 +1,37 @@
-use std::io::{self, stdout, Stdout};
+use ratatui::widgets::TableState;
 
-use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
-    execute,
-    terminal::*,
-};
-use ratatui::widgets::{Table, TableState};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::structures::{BlockData, Transaction};
+use crate::address::pays_to;
+use crate::coinbase::Coinbase;
+use crate::config::DEFAULT_BACKFILL_DEPTH;
+use crate::merkle::{block_txids, merkle_proof, MerkleProof};
+use crate::network::Network;
+use crate::stats::{block_stats, BlockStats, FeeSource, LoadedOutputs};
+use crate::structures::{BlockData, BlockHash, ChainPosition, ChainUpdate, NetworkCommand, PeerId, PeerInfo, PeerStats, Reorg, Transaction};
+use crate::validation::block_work;
+use crate::widgets::block_details;
+
+// the block list keeps the newest blocks, older ones fall off the bottom
+pub const MAX_LISTED_BLOCKS: usize = 1000;
+
+// pages of the block info panel, shift+tab moves between them
+pub const BLOCK_INFO_PAGES: [&str; 3] = ["Header", "Coinbase", "Stats"];
+pub const COINBASE_PAGE: usize = 1;
+pub const STATS_PAGE: usize = 2;
 
 
 
 pub enum CurrentScreen {
     Home,
+    // everything in the transaction picked in the transaction table
+    TransactionDetail,
+    // typing an address and picking from the outputs that pay it
+    AddressSearch,
     Exiting,
     Exit
 }
 
-pub struct CurrentBlock {
-    pub time: u8,
-    pub nonce: u8,
-    pub data: u8,
-
-}
-
 pub enum Direction {
     Up,
     Down
@@ -34,12 +42,68 @@ pub enum TableType {
     Transaction,
 }
 
+// an output in one of the listed blocks that pays the searched address
+pub struct AddressMatch {
+    pub block_hash: BlockHash,
+    pub txid: [u8; 32],
+    pub transaction: usize,
+    pub output: usize,
+    pub value: u64,
+}
+
+// what the info panel and transaction table show of a block, worked out once when it's first
+// shown rather than on every frame
+pub struct BlockDetails {
+    // the height the coinbase was decoded at, it's done again if that changes
+    pub height: Option<u32>,
+    // None for a block that's only a header
+    pub coinbase: Option<Coinbase>,
+    pub merkle_valid: Option<bool>,
+    // output types and who's paid, one pair per transaction
+    pub transaction_labels: Vec<(String, String)>,
+}
+
+// the merkle proof of the highlighted transaction, when the transaction table is showing the
+// highlighted block
+pub struct SelectedProof {
+    pub block_hash: BlockHash,
+    pub proof: MerkleProof,
+    // whether the branch leads back to the header's merkle root
+    pub proven: bool,
+}
+
+// what the Chain Events panel lists, oldest first
+pub enum ChainEvent {
+    Reorg(Reorg),
+    // headers a peer sent that didn't check out, the peer is dropped for it
+    RejectedHeaders { peer: String, reason: String },
+    // a write to the block store that didn't go through
+    StorageFailed(String),
+}
+
 pub enum Action {
     SetScreen(CurrentScreen),
     SelectBlock(usize),
+    OpenTransaction,
+    ScrollDetail(Direction, u16),
+    StartSearch,
+    SearchInput(char),
+    SearchBackspace,
+    // searches, or opens the highlighted result once the results are for what's typed
+    SubmitSearch,
+    NavigateResults(Direction),
     SetPanel(usize),
     AddBlock(BlockData),
-    SetExit(bool),
+    AddHistoricalBlock(BlockData),
+    LoadMore(NetworkCommand),
+    BackfillFinished,
+    UpdateChain(ChainUpdate),
+    RejectHeaders(PeerId, String),
+    StorageFailed(String),
+    AddPeer(PeerId, PeerInfo),
+    UpdatePeer(PeerId, PeerStats),
+    RemovePeer(PeerId),
+    NextPeer,
     NavigateTable(Direction, TableType),
     SwitchTable,
     DoNothing,
@@ -49,70 +113,355 @@ pub enum Action {
 pub struct AppState {
     pub current_screen: CurrentScreen,
     pub current_block: Option<BlockData>,
+    // newest first
     pub block_list: Vec<BlockData>,
-    pub test_list: [&'static str; 3],
+    // grows as older blocks are loaded so they don't get trimmed straight away
+    pub list_limit: usize,
+    pub backfill_depth: u32,
+    // the first block of the backfill in flight
+    pub backfill_pending: Option<BlockHash>,
+    // where the listed blocks sit on the active chain, if they're on it
+    pub chain_positions: HashMap<BlockHash, ChainPosition>,
+    // listed blocks a reorg knocked off the active chain
+    pub stale_blocks: HashSet<BlockHash>,
+    pub chain_height: Option<u32>,
+    pub stale_branches: usize,
+    pub chain_events: Vec<ChainEvent>,
     pub selected_panel: usize,
-    pub selected_block: usize,  
     pub transaction_list: Vec<Transaction>, // Assuming you have a transaction_list in your state
-    pub counter: u8,
-    pub exit: bool,
     pub block_table_state: TableState,
     pub transaction_table_state: TableState,
-    pub active_table: TableType // Add this line
+    pub active_table: TableType, // Add this line
+    pub peers: BTreeMap<PeerId, (PeerInfo, PeerStats)>,
+    // index into peers of the one the peer panel is showing
+    pub selected_peer: usize,
+    // the blocks directory being read when there's no network
+    pub offline_source: Option<String>,
+    // for turning output scripts into addresses
+    pub network: Network,
+    // lines scrolled past at the top of the transaction detail screen
+    pub detail_scroll: u16,
+    pub search_query: String,
+    // None until the first search, then the query the results are for
+    pub searched_for: Option<String>,
+    pub search_results: Vec<AddressMatch>,
+    pub search_table_state: TableState,
+    // what the outputs of the listed blocks are worth, for working out fees
+    pub loaded_outputs: LoadedOutputs,
+    // stats of blocks the stats page has shown, None for ones that are only a header
+    pub block_stats: HashMap<BlockHash, Option<BlockStats>>,
+    // details of the blocks the panels have shown
+    pub block_details: HashMap<BlockHash, BlockDetails>,
+    pub merkle_proof: Option<SelectedProof>,
 }
 
 impl AppState {
     pub fn new() -> AppState {
         AppState {
-            counter: 0,
-            exit: false,
             current_screen: CurrentScreen::Home,
             current_block: None,
-            test_list: ["one", "two", "three"],
             block_list: Vec::new(),
-            selected_block: 0,
+            list_limit: MAX_LISTED_BLOCKS,
+            backfill_depth: DEFAULT_BACKFILL_DEPTH,
+            backfill_pending: None,
+            chain_positions: HashMap::new(),
+            stale_blocks: HashSet::new(),
+            chain_height: None,
+            stale_branches: 0,
+            chain_events: Vec::new(),
             selected_panel: 0,
             transaction_list: Vec::new(),
             block_table_state: TableState::default(), 
             transaction_table_state: TableState::default(),
-            active_table: TableType::Block// Add this line
+            active_table: TableType::Block, // Add this line
+            peers: BTreeMap::new(),
+            selected_peer: 0,
+            offline_source: None,
+            network: Network::Mainnet,
+            detail_scroll: 0,
+            search_query: String::new(),
+            searched_for: None,
+            search_results: Vec::new(),
+            search_table_state: TableState::default(),
+            loaded_outputs: LoadedOutputs::default(),
+            block_stats: HashMap::new(),
+            block_details: HashMap::new(),
+            merkle_proof: None,
         }
     }
-}
 
+    pub fn selected_peer(&self) -> Option<&(PeerInfo, PeerStats)> {
+        self.peers.values().nth(self.selected_peer)
+    }
+
+    // the block highlighted in the block table, which is what the info panel describes
+    pub fn selected_block(&self) -> Option<&BlockData> {
+        self.block_table_state.selected().and_then(|index| self.block_list.get(index))
+    }
+
+    // the transaction highlighted in the transaction table
+    pub fn selected_transaction(&self) -> Option<&Transaction> {
+        self.transaction_table_state.selected().and_then(|index| self.transaction_list.get(index))
+    }
+
+    // works the selected block's stats out once rather than on every frame
+    fn cache_selected_stats(&mut self) {
+        let block = match self.selected_block() {
+            Some(block) if !self.block_stats.contains_key(&block.block_hash) => block,
+            _ => return,
+        };
+        let height = self.chain_positions.get(&block.block_hash).map(|position| position.height);
+        let stats = block_stats(block, height, self.network, &self.loaded_outputs);
+        self.block_stats.insert(block.block_hash, stats);
+    }
+
+    // for the highlighted block and the one the transaction table is showing
+    fn cache_shown_details(&mut self) {
+        let mut computed: Vec<(BlockHash, BlockDetails)> = Vec::new();
+        for block in [self.selected_block(), self.current_block.as_ref()].into_iter().flatten() {
+            let height = self.chain_positions.get(&block.block_hash).map(|position| position.height);
+            let cached = self.block_details.get(&block.block_hash).is_some_and(|details| details.height == height);
+            if !cached && !computed.iter().any(|(hash, _)| *hash == block.block_hash) {
+                computed.push((block.block_hash, block_details(block, height, self.network)));
+            }
+        }
+        self.block_details.extend(computed);
+    }
+
+    fn cache_selected_proof(&mut self) {
+        let block = match (self.selected_block(), self.current_block.as_ref()) {
+            (Some(selected), Some(current)) if selected.block_hash == current.block_hash => selected,
+            _ => {
+                self.merkle_proof = None;
+                return;
+            }
+        };
+        let index = match self.transaction_table_state.selected() {
+            Some(index) if index < block.transactions.len() => index,
+            _ => {
+                self.merkle_proof = None;
+                return;
+            }
+        };
+        if self.merkle_proof.as_ref().is_some_and(|shown| shown.block_hash == block.block_hash && shown.proof.index == index) {
+            return;
+        }
+
+        let txids = block_txids(block);
+        self.merkle_proof = merkle_proof(&txids, index).map(|proof| SelectedProof {
+            block_hash: block.block_hash,
+            proven: proof.root(txids[proof.index]) == block.merkle_root,
+            proof,
+        });
+    }
+
+    // true when the block table is highlighting its last row, where scrolling on loads more
+    pub fn at_bottom_of_blocks(&self) -> bool {
+        matches!(self.active_table, TableType::Block)
+            && !self.block_list.is_empty()
+            && self.block_table_state.selected() == Some(self.block_list.len() - 1)
+    }
+
+    // None while a backfill is already running or once the list goes back to genesis
+    pub fn backfill_command(&self) -> Option<NetworkCommand> {
+        if self.backfill_pending.is_some() {
+            return None;
+        }
+        let oldest = self.block_list.last()?;
+        if oldest.prev_block_hash == [0; 32] {
+            return None;
+        }
+
+        Some(NetworkCommand::Backfill {
+            start: BlockHash(oldest.prev_block_hash),
+            depth: self.backfill_depth,
+        })
+    }
+}
 
 pub fn reduce(state: &mut AppState, action: Action) {
+    apply(state, action);
+
+    // whatever the action did to the selection or the blocks, what's on show is ready for
+    // the next frame without any work left for the draw
+    if state.selected_panel == STATS_PAGE {
+        state.cache_selected_stats();
+    }
+    state.cache_shown_details();
+    state.cache_selected_proof();
+}
+
+fn apply(state: &mut AppState, action: Action) {
     match action {
         Action::SetScreen(screen) => state.current_screen = screen,
         Action::AddBlock(block) => {
-        state.block_list.push(block);
-        // Update the block_table_state
-        state.block_table_state.select(Some(state.block_list.len() - 1));
+        state.loaded_outputs.add_block(&block);
+        forget_partial_stats(state);
+        if fill_in_listed_block(state, &block) {
+            return;
+        }
+        let selected = selected_row(state);
+        state.block_list.insert(0, block);
+        trim_block_list(state);
+        reselect_block(state, selected);
     }
+        Action::AddHistoricalBlock(block) => {
+            state.loaded_outputs.add_block(&block);
+            forget_partial_stats(state);
+            if fill_in_listed_block(state, &block) {
+                return;
+            }
+
+            // off the end of the synced chain the position comes from the block after it
+            if let Some(child) = state.block_list.last() {
+                let child_position = state.chain_positions.get(&child.block_hash).copied();
+                if let (Some(position), true) = (child_position, child.prev_block_hash == block.block_hash.0) {
+                    state.chain_positions.entry(block.block_hash).or_insert(ChainPosition {
+                        height: position.height.saturating_sub(1),
+                        chainwork: position.chainwork.saturating_sub(block_work(child.bits)),
+                    });
+                }
+            }
+
+            // move onto the first loaded block if the user is still sat at the bottom
+            let follow = state.backfill_pending == Some(block.block_hash) && state.at_bottom_of_blocks();
+            state.block_list.push(block);
+            state.list_limit = state.list_limit.max(state.block_list.len());
+            if follow {
+                state.block_table_state.select(Some(state.block_list.len() - 1));
+            }
+        }
+        Action::LoadMore(command) => {
+            let NetworkCommand::Backfill { start, .. } = command;
+            state.backfill_pending = Some(start);
+        }
+        Action::BackfillFinished => state.backfill_pending = None,
+        Action::UpdateChain(update) => {
+            let selected = selected_row(state);
+            for reorg in &update.reorgs {
+                for hash in &reorg.disconnected {
+                    state.chain_positions.remove(hash);
+                    state.stale_blocks.insert(*hash);
+                }
+            }
+            state.chain_events.extend(update.reorgs.into_iter().map(ChainEvent::Reorg));
+            state.stale_branches = update.stale_branches;
+            forget_partial_stats(state);
+
+            for (position, header) in update.connected {
+                state.chain_height = Some(position.height);
+                state.stale_blocks.remove(&header.block_hash);
+                state.chain_positions.insert(header.block_hash, position);
+                if !state.block_list.iter().any(|listed| listed.block_hash == header.block_hash) {
+                    state.block_list.insert(0, header);
+                }
+            }
+            trim_block_list(state);
+            reselect_block(state, selected);
+        }
+        Action::RejectHeaders(id, reason) => {
+            // the peer is still listed, its disconnect comes after this
+            let peer = state.peers.get(&id).map_or_else(|| format!("peer {}", id), |(info, _)| info.address.clone());
+            state.chain_events.push(ChainEvent::RejectedHeaders { peer, reason });
+        }
+        Action::StorageFailed(reason) => state.chain_events.push(ChainEvent::StorageFailed(reason)),
+        Action::AddPeer(id, info) => {
+            state.peers.insert(id, (info, PeerStats::default()));
+        }
+        Action::UpdatePeer(id, stats) => {
+            if let Some(peer) = state.peers.get_mut(&id) {
+                peer.1 = stats;
+            }
+        }
+        Action::RemovePeer(id) => {
+            state.peers.remove(&id);
+            if state.selected_peer >= state.peers.len() {
+                state.selected_peer = 0;
+            }
+        }
+        Action::NextPeer => {
+            if !state.peers.is_empty() {
+                state.selected_peer = (state.selected_peer + 1) % state.peers.len();
+            }
+        }
         Action::SetPanel(index) => {
             state.selected_panel = index;
         }
-        Action::SelectBlock(_) => {
-            if let Some(selected) = state.block_table_state.selected() {
-                if selected < state.block_list.len() {
-                    state.current_block = Some(state.block_list[selected].clone());
-                    state.transaction_list = state.current_block.as_ref().unwrap().transactions.clone();
-                    state.transaction_table_state.select(Some(0)); // Reset the selection in the transaction table
+        Action::SelectBlock(index) => {
+            if let Some(block) = state.block_list.get(index) {
+                state.current_block = Some(block.clone());
+                state.transaction_list = block.transactions.clone();
+                state.transaction_table_state.select(Some(0)); // Reset the selection in the transaction table
+                // straight onto the transactions, tab goes back to the blocks
+                if !state.transaction_list.is_empty() {
+                    state.active_table = TableType::Transaction;
                 }
             }
         },
+        Action::OpenTransaction => {
+            if state.selected_transaction().is_some() {
+                state.detail_scroll = 0;
+                state.current_screen = CurrentScreen::TransactionDetail;
+            }
+        }
+        Action::StartSearch => state.current_screen = CurrentScreen::AddressSearch,
+        Action::SearchInput(character) => state.search_query.push(character),
+        Action::SearchBackspace => {
+            state.search_query.pop();
+        }
+        Action::SubmitSearch => {
+            let query = state.search_query.trim().to_string();
+            if state.searched_for.as_deref() == Some(query.as_str()) {
+                open_search_result(state);
+            } else {
+                state.search_results = search_address(&state.block_list, &query, state.network);
+                state.search_table_state.select(if state.search_results.is_empty() { None } else { Some(0) });
+                state.searched_for = Some(query);
+            }
+        }
+        Action::NavigateResults(direction) => {
+            let len = state.search_results.len();
+            if len == 0 {
+                return;
+            }
+            let current = state.search_table_state.selected().unwrap_or(0);
+            let next = match direction {
+                Direction::Up => (current + len - 1) % len,
+                Direction::Down => (current + 1) % len,
+            };
+            state.search_table_state.select(Some(next));
+        }
+        Action::ScrollDetail(direction, lines) => {
+            // the ui stops it going past the last line, it knows how many there are
+            state.detail_scroll = match direction {
+                Direction::Up => state.detail_scroll.saturating_sub(lines),
+                Direction::Down => state.detail_scroll.saturating_add(lines),
+            };
+        }
         Action::NavigateTable(direction, table_type) => {
             let (len, current_selection) = match table_type {
                 TableType::Block => (state.block_list.len(), state.block_table_state.selected().unwrap_or(0)),
                 TableType::Transaction => (state.transaction_list.len(), state.transaction_table_state.selected().unwrap_or(0)), // Assuming you have a transaction_list in your state
             };
 
+            // nothing to move through yet
+            if len == 0 {
+                return;
+            }
+
             let new_selection = match direction {
                 Direction::Up => {
                     if current_selection > 0 { current_selection - 1 } else { len - 1 }
                 }
                 Direction::Down => {
-                    if current_selection < len - 1 { current_selection + 1 } else { 0 }
+                    if current_selection < len - 1 {
+                        current_selection + 1
+                    } else if matches!(table_type, TableType::Block) && state.backfill_pending.is_some() {
+                        // stay put while older blocks are on their way in below
+                        current_selection
+                    } else {
+                        0
+                    }
                 }
             };
 
@@ -127,11 +476,325 @@ pub fn reduce(state: &mut AppState, action: Action) {
                 TableType::Transaction => TableType::Block,
             };
         }
-        Action::SetExit(value) => {
-            state.exit = value;
-        }
         Action::DoNothing => {}
 
         // other actions...
     }
-}
\ No newline at end of file
+}
+
+// only outputs, what an input spends isn't known without the transaction that created it
+fn search_address(blocks: &[BlockData], query: &str, network: Network) -> Vec<AddressMatch> {
+    let mut matches = Vec::new();
+    if query.is_empty() {
+        return matches;
+    }
+
+    for block in blocks {
+        for (transaction_index, transaction) in block.transactions.iter().enumerate() {
+            for (output_index, output) in transaction.outputs.iter().enumerate() {
+                if pays_to(&output.script_pub_key, query, network) {
+                    matches.push(AddressMatch {
+                        block_hash: block.block_hash,
+                        txid: transaction.txid,
+                        transaction: transaction_index,
+                        output: output_index,
+                        value: output.value,
+                    });
+                }
+            }
+        }
+    }
+    matches
+}
+
+// shows the transaction behind the highlighted result, with its block selected underneath
+fn open_search_result(state: &mut AppState) {
+    let found = match state.search_table_state.selected().and_then(|index| state.search_results.get(index)) {
+        Some(found) => found,
+        None => return,
+    };
+    // the block may have been trimmed off the list since
+    let block_index = match state.block_list.iter().position(|block| block.block_hash == found.block_hash) {
+        Some(index) => index,
+        None => return,
+    };
+    let transaction = found.transaction;
+
+    state.block_table_state.select(Some(block_index));
+    reduce(state, Action::SelectBlock(block_index));
+    state.transaction_table_state.select(Some(transaction));
+    reduce(state, Action::OpenTransaction);
+}
+
+// A block we already list from its header gets its transactions filled in, and so does the
+// transaction table when it's showing that block. false if the block isn't listed.
+fn fill_in_listed_block(state: &mut AppState, block: &BlockData) -> bool {
+    let listed = match state.block_list.iter_mut().find(|listed| listed.block_hash == block.block_hash) {
+        Some(listed) => listed,
+        None => return false,
+    };
+    *listed = block.clone();
+    // worked out from the header alone, the transactions change all of it
+    state.block_details.remove(&block.block_hash);
+    if state.merkle_proof.as_ref().is_some_and(|shown| shown.block_hash == block.block_hash) {
+        state.merkle_proof = None;
+    }
+
+    if state.current_block.as_ref().is_some_and(|current| current.block_hash == block.block_hash) {
+        state.current_block = Some(block.clone());
+        state.transaction_list = block.transactions.clone();
+    }
+    true
+}
+
+// the highlighted row and the block on it, so the block can be found again once the list changes
+fn selected_row(state: &AppState) -> Option<(usize, BlockHash)> {
+    let row = state.block_table_state.selected()?;
+    Some((row, state.block_list.get(row)?.block_hash))
+}
+
+// Keeps the highlight on the block it was on before new blocks went in above it. With no
+// highlight, or one on the newest block, it follows the tip instead. A block trimmed off the
+// end leaves the highlight on the last row.
+fn reselect_block(state: &mut AppState, previous: Option<(usize, BlockHash)>) {
+    if state.block_list.is_empty() {
+        return;
+    }
+
+    let row = match previous {
+        Some((row, hash)) if row != 0 => state
+            .block_list
+            .iter()
+            .position(|block| block.block_hash == hash)
+            .unwrap_or(state.block_list.len() - 1),
+        _ => 0,
+    };
+    state.block_table_state.select(Some(row));
+}
+
+// Fees worked out from every transaction's inputs stay right. The rest may change with a
+// newly loaded block, whose outputs can value more inputs or which fills in a header, or
+// with a new height, which changes the subsidy the coinbase's fees are taken from.
+fn forget_partial_stats(state: &mut AppState) {
+    state
+        .block_stats
+        .retain(|_, stats| matches!(stats, Some(BlockStats { fees: Some((_, FeeSource::Transactions)), .. })));
+}
+
+fn trim_block_list(state: &mut AppState) {
+    if state.block_list.len() <= state.list_limit {
+        return;
+    }
+
+    for block in state.block_list.drain(state.list_limit..) {
+        state.loaded_outputs.remove_block(&block);
+        state.chain_positions.remove(&block.block_hash);
+        state.stale_blocks.remove(&block.block_hash);
+        state.block_stats.remove(&block.block_hash);
+        state.block_details.remove(&block.block_hash);
+    }
+    if let Some(selected) = state.block_table_state.selected() {
+        state.block_table_state.select(Some(selected.min(state.list_limit - 1)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{parse_transaction, tests::SEGWIT_TX};
+    use crate::utils::ByteReader;
+
+    // headers that only need telling apart, they're never validated here
+    fn header(n: u32) -> BlockData {
+        let mut header = Network::Regtest.genesis_header();
+        header.nonce = n;
+        header.calculate_hash();
+        header
+    }
+
+    fn connect(state: &mut AppState, heights: &[u32]) {
+        let connected = heights
+            .iter()
+            .map(|&height| (ChainPosition { height, chainwork: height as u128 }, header(height)))
+            .collect();
+        reduce(state, Action::UpdateChain(ChainUpdate { connected, reorgs: Vec::new(), stale_branches: 0 }));
+    }
+
+    fn selected_hash(state: &AppState) -> Option<BlockHash> {
+        state.selected_block().map(|block| block.block_hash)
+    }
+
+    #[test]
+    fn chain_update_selects_the_tip_when_nothing_is_selected() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        assert_eq!(state.block_table_state.selected(), Some(0));
+        assert_eq!(selected_hash(&state), Some(header(2).block_hash));
+    }
+
+    #[test]
+    fn chain_update_follows_the_tip_from_the_first_row() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        connect(&mut state, &[3]);
+        assert_eq!(selected_hash(&state), Some(header(3).block_hash));
+    }
+
+    #[test]
+    fn chain_update_keeps_an_older_block_selected() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2, 3]);
+        state.block_table_state.select(Some(2));
+
+        connect(&mut state, &[4, 5]);
+        assert_eq!(state.block_table_state.selected(), Some(4));
+        assert_eq!(selected_hash(&state), Some(header(1).block_hash));
+    }
+
+    #[test]
+    fn new_block_follows_the_tip_from_the_first_row() {
+        let mut state = AppState::new();
+        reduce(&mut state, Action::AddBlock(header(1)));
+        reduce(&mut state, Action::AddBlock(header(2)));
+        assert_eq!(selected_hash(&state), Some(header(2).block_hash));
+    }
+
+    #[test]
+    fn new_block_keeps_an_older_block_selected() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        state.block_table_state.select(Some(1));
+
+        reduce(&mut state, Action::AddBlock(header(3)));
+        assert_eq!(state.block_table_state.selected(), Some(2));
+        assert_eq!(selected_hash(&state), Some(header(1).block_hash));
+        assert!(state.at_bottom_of_blocks());
+    }
+
+    // the header with a transaction in it, the block doesn't have to add up
+    fn full_block(n: u32) -> BlockData {
+        let mut block = header(n);
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        block.transactions = vec![parse_transaction(&mut ByteReader::new(&bytes)).unwrap()];
+        block
+    }
+
+    #[test]
+    fn full_block_fills_in_the_selected_header() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        reduce(&mut state, Action::SelectBlock(1));
+        assert!(state.transaction_list.is_empty());
+
+        reduce(&mut state, Action::AddBlock(full_block(1)));
+
+        assert_eq!(state.block_list.len(), 2);
+        assert_eq!(state.current_block.as_ref().unwrap().transactions.len(), 1);
+        assert_eq!(state.transaction_list.len(), 1);
+        assert!(state.selected_transaction().is_some());
+    }
+
+    #[test]
+    fn full_block_leaves_another_selection_alone() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        reduce(&mut state, Action::SelectBlock(0));
+
+        reduce(&mut state, Action::AddBlock(full_block(1)));
+
+        assert_eq!(state.current_block.as_ref().unwrap().block_hash, header(2).block_hash);
+        assert!(state.transaction_list.is_empty());
+        assert_eq!(state.block_list[1].transactions.len(), 1);
+    }
+
+    #[test]
+    fn details_and_proof_follow_the_selection() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        assert!(state.block_details[&header(2).block_hash].coinbase.is_none());
+
+        reduce(&mut state, Action::AddBlock(full_block(2)));
+        let details = &state.block_details[&header(2).block_hash];
+        assert_eq!(details.height, Some(2));
+        assert_eq!(details.transaction_labels.len(), 1);
+        assert_eq!(details.merkle_valid, Some(false));
+        assert!(state.merkle_proof.is_none());
+
+        // a proof once the transaction table shows the highlighted block
+        reduce(&mut state, Action::SelectBlock(0));
+        let shown = state.merkle_proof.as_ref().unwrap();
+        assert_eq!(shown.block_hash, header(2).block_hash);
+        assert_eq!(shown.proof.index, 0);
+        assert!(!shown.proven);
+
+        reduce(&mut state, Action::NavigateTable(Direction::Down, TableType::Block));
+        assert!(state.merkle_proof.is_none());
+        assert!(state.block_details.contains_key(&header(1).block_hash));
+    }
+
+    #[test]
+    fn chain_update_keeps_the_bottom_row_while_loading_more() {
+        let mut state = AppState::new();
+        connect(&mut state, &[2, 3]);
+        state.block_table_state.select(Some(1));
+        assert!(state.at_bottom_of_blocks());
+
+        connect(&mut state, &[4]);
+        assert!(state.at_bottom_of_blocks());
+        assert_eq!(selected_hash(&state), Some(header(2).block_hash));
+    }
+
+    // stats whose fees came from every transaction's inputs, which nothing can change
+    fn complete_stats() -> Option<BlockStats> {
+        Some(BlockStats {
+            transactions: 1,
+            size: 0,
+            stripped_size: 0,
+            weight: 0,
+            output_value: 0,
+            fees: Some((0, FeeSource::Transactions)),
+            feerates: Vec::new(),
+            segwit_share: 0.0,
+            taproot_share: 0.0,
+        })
+    }
+
+    #[test]
+    fn stats_are_worked_out_for_the_selected_block() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        assert!(state.block_stats.is_empty());
+
+        // once the stats page is showing, and again for each block the selection moves to
+        reduce(&mut state, Action::SetPanel(STATS_PAGE));
+        assert_eq!(state.block_stats.len(), 1);
+        assert!(matches!(state.block_stats.get(&header(2).block_hash), Some(None)));
+
+        reduce(&mut state, Action::NavigateTable(Direction::Down, TableType::Block));
+        assert_eq!(state.block_stats.len(), 2);
+        assert!(state.block_stats.contains_key(&header(1).block_hash));
+    }
+
+    #[test]
+    fn new_blocks_only_forget_stats_that_could_change() {
+        let mut state = AppState::new();
+        connect(&mut state, &[1, 2]);
+        state.cache_selected_stats();
+        state.block_stats.insert(header(1).block_hash, complete_stats());
+
+        reduce(&mut state, Action::AddBlock(header(3)));
+        assert!(!state.block_stats.contains_key(&header(2).block_hash));
+        assert!(state.block_stats.contains_key(&header(1).block_hash));
+    }
+
+    #[test]
+    fn trimmed_blocks_take_their_stats_with_them() {
+        let mut state = AppState::new();
+        state.list_limit = 2;
+        connect(&mut state, &[1, 2]);
+        state.block_stats.insert(header(1).block_hash, complete_stats());
+
+        connect(&mut state, &[3]);
+        assert!(state.block_stats.is_empty());
+    }
+}
@@ -1,13 +1,10 @@
-use crate::tui::{reduce, Action, AppState, CurrentScreen};
-use crate::widgets::{block_table, container_layout, nested_layout, transaction_table};
-use crossterm::event::{self, KeyCode};
+use crate::tui::{AppState, CurrentScreen};
+use crate::widgets::{block_info, block_table, chain_events, container_layout, nested_layout, offline_info, peer_info, search_input, search_results, top_layout, transaction_detail, transaction_table};
 use ratatui::{
     layout::{Constraint, Direction, Layout},
     widgets::{Block, Borders, Paragraph},
     Frame,
 };
-use ratatui::{prelude::*, widgets::*};
-use std::time::Duration;
 
 pub fn ui(frame: &mut Frame, app_state: &mut AppState) {
     // variable for focusing on the different screens
@@ -19,24 +16,19 @@ pub fn ui(frame: &mut Frame, app_state: &mut AppState) {
             // define layout
 
             let container_layout = container_layout(frame.size());
+            let top_layout = top_layout(container_layout[0]);
             let nested_layout = nested_layout(container_layout[1]);
 
             // then render to the frame
 
-            let block_list_component = block_table(&app_state.block_list);
+            let block_list_component = block_table(&app_state.block_list, app_state);
    
             let transaction_table_component = if let Some(current_block) = app_state.current_block.as_ref() {
-                transaction_table(&current_block.transactions)
+                transaction_table(&current_block.transactions, app_state.block_details.get(&current_block.block_hash))
             } else {
-                transaction_table(&empty_transactions)
+                transaction_table(&empty_transactions, None)
             };
 
-            let widths = [
-                Constraint::Length(20),
-                Constraint::Length(20),
-                Constraint::Length(20),
-            ];
-
             // // transaction list
             // let transaction_table = Table::new(
             //     vec![
@@ -60,27 +52,62 @@ pub fn ui(frame: &mut Frame, app_state: &mut AppState) {
 
             // block info
 
-            frame.render_widget(
-                Paragraph::new("outer 0").block(Block::new().borders(Borders::ALL)),
-                container_layout[0],
-            );
+            frame.render_widget(block_info(app_state.selected_block(), app_state), top_layout[0]);
+            let source_panel = match &app_state.offline_source {
+                Some(dir) => offline_info(dir),
+                None => peer_info(app_state.selected_peer(), app_state.selected_peer, app_state.peers.len()),
+            };
+            frame.render_widget(source_panel, top_layout[1]);
             frame.render_stateful_widget(block_list_component, nested_layout[0], &mut app_state.block_table_state);
             frame.render_stateful_widget(transaction_table_component, nested_layout[1], &mut app_state.transaction_table_state);
 
-            frame.render_widget(
-                Paragraph::new("outer 1").block(Block::new().borders(Borders::ALL)),
-                container_layout[2],
-            );
+            frame.render_widget(chain_events(&app_state.chain_events), container_layout[2]);
 
            
         },
 
+        CurrentScreen::TransactionDetail => {
+            let area = frame.size();
+            // inside the borders
+            let width = area.width.saturating_sub(2) as usize;
+            let height = area.height.saturating_sub(2) as usize;
+
+            let lines = match app_state.selected_transaction() {
+                Some(transaction) => transaction_detail(transaction, app_state.network, width),
+                None => Vec::new(),
+            };
+
+            // keep the last page full rather than scrolling off into nothing
+            let last_scroll = lines.len().saturating_sub(height) as u16;
+            app_state.detail_scroll = app_state.detail_scroll.min(last_scroll);
+
+            let detail = Paragraph::new(lines)
+                .block(Block::default().title("Transaction (Esc to go back)").borders(Borders::ALL))
+                .scroll((app_state.detail_scroll, 0));
+            frame.render_widget(detail, area);
+        }
+
+        CurrentScreen::AddressSearch => {
+            let layout = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Min(0)])
+                .split(frame.size());
+
+            frame.render_widget(search_input(&app_state.search_query), layout[0]);
+            let results = search_results(app_state);
+            frame.render_stateful_widget(results, layout[1], &mut app_state.search_table_state);
+        }
+
         CurrentScreen::Exiting => {
             let block = Block::default().title("Exiting").borders(Borders::ALL);
             frame.render_widget(block, frame.size());
 
         }
 
-        CurrentScreen::Exit => {}
+        CurrentScreen::Exit => {
+            let prompt = Paragraph::new("Do you want to exit? (y/n)")
+                .block(Block::default().title("Exit").borders(Borders::ALL));
+            frame.render_widget(prompt, frame.size());
+        }
     }
 }
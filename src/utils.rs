@@ -1,49 +1,162 @@
-use std::{error::Error, net::IpAddr};
+use std::io;
+use std::net::IpAddr;
 
-pub fn ip_string_to_bytes(ip_string: &str) -> Result<Vec<u8>, std::net::AddrParseError> {
+// the protocol only has room for ipv6 addresses, ipv4 ones go in as ::ffff:a.b.c.d
+pub fn ip_string_to_bytes(ip_string: &str) -> Result<[u8; 16], std::net::AddrParseError> {
     let ip_addr: IpAddr = ip_string.parse()?;
     let ip_bytes = match ip_addr {
-        IpAddr::V4(ipv4_addr) => ipv4_addr.octets().to_vec(),
-        IpAddr::V6(ipv6_addr) => ipv6_addr.octets().to_vec(),
+        IpAddr::V4(ipv4_addr) => ipv4_addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(ipv6_addr) => ipv6_addr.octets(),
     };
     Ok(ip_bytes)
 }
 
 
-pub fn read_var_int (payload:Vec<u8>) -> Result<(u64, usize), Box<dyn Error>>{
-    let first_byte = payload[0];
-    let (count, offset) = match first_byte {
-        value if value < 0xFD => (value as u64, 1),
-        0xFD => (u16::from_le_bytes(payload[1..3].try_into().unwrap()) as u64, 3),
-        0xFE => (u32::from_le_bytes(payload[1..5].try_into().unwrap()) as u64, 5),
-        0xFF => (u64::from_le_bytes(payload[1..9].try_into().unwrap()), 9),
-        _ => return Err("Invalid count".into()),
-    };
-
-    Ok((count, offset))
+// Cursor style reader over a byte slice. Every read is bounds checked and fails with
+// UnexpectedEof instead of panicking, so short or malicious payloads can't take down
+// the thread parsing them.
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
 }
 
-pub fn read_bytes(data: &[u8], start: &mut usize, length: usize) -> Result<Vec<u8>, std::io::Error> {
-    if *start + length > data.len() {
-        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Not enough data"));
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> ByteReader<'a> {
+        ByteReader { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    // the whole underlying slice, for hashing ranges that have already been read
+    pub fn get_ref(&self) -> &'a [u8] {
+        self.data
     }
-    let result = data[*start..*start + length].to_vec();
-    *start += length;
-    Ok(result)
-}
 
-pub fn read_u32(data: &[u8], start: &mut usize) -> Result<u32, Box<dyn Error>> {
-    Ok(u32::from_le_bytes(read_bytes(data, start, 4)?.try_into().unwrap()))
+    pub fn peek(&self, length: usize) -> Option<&'a [u8]> {
+        self.data.get(self.position..self.position.checked_add(length)?)
+    }
+
+    pub fn read_slice(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        let slice = self.peek(length).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("wanted {} bytes at offset {} but only {} remain", length, self.position, self.remaining()),
+            )
+        })?;
+        self.position += length;
+        Ok(slice)
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        Ok(self.read_slice(length)?.to_vec())
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.read_slice(N)?.try_into().unwrap())
+    }
+
+    pub fn read_remaining(&mut self) -> Vec<u8> {
+        let rest = self.data[self.position..].to_vec();
+        self.position = self.data.len();
+        rest
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    // ports are the one big endian field in the protocol
+    pub fn read_u16_be(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_hash(&mut self) -> io::Result<[u8; 32]> {
+        self.read_array()
+    }
+
+    // CompactSize: one byte below 0xFD, otherwise a marker byte followed by a u16, u32 or u64
+    pub fn read_compact_size(&mut self) -> io::Result<u64> {
+        match self.read_u8()? {
+            0xFD => Ok(self.read_u16()? as u64),
+            0xFE => Ok(self.read_u32()? as u64),
+            0xFF => self.read_u64(),
+            value => Ok(value as u64),
+        }
+    }
+
+    pub fn read_var_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.read_compact_size()?;
+        // check against what is left first so a bogus length can't overflow usize
+        if length > self.remaining() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("var_bytes length {} runs past the end of the data", length),
+            ));
+        }
+        self.read_bytes(length as usize)
+    }
+
+    pub fn read_var_str(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(&self.read_var_bytes()?).to_string())
+    }
 }
 
-pub fn read_u64(data: &[u8], start: &mut usize) -> Result<u64, Box<dyn Error>> {
-    Ok(u64::from_le_bytes(read_bytes(data, start, 8)?.try_into().unwrap()))
+pub fn write_var_int(value: u64) -> Vec<u8> {
+    match value {
+        value if value < 0xFD => vec![value as u8],
+        value if value <= 0xFFFF => {
+            let mut bytes = vec![0xFD];
+            bytes.extend((value as u16).to_le_bytes());
+            bytes
+        }
+        value if value <= 0xFFFF_FFFF => {
+            let mut bytes = vec![0xFE];
+            bytes.extend((value as u32).to_le_bytes());
+            bytes
+        }
+        value => {
+            let mut bytes = vec![0xFF];
+            bytes.extend(value.to_le_bytes());
+            bytes
+        }
+    }
 }
 
-pub fn read_var_bytes(data: &[u8], start: &mut usize) -> Result<Vec<u8>, Box<dyn Error>> {
-    let (length, offset) = read_var_int(data[*start..].to_vec())?;
-    *start += offset;
-    Ok(read_bytes(data, start, length as usize)?)
+// hashes are stored in internal byte order but always shown reversed
+pub fn reversed_hex(bytes: &[u8]) -> String {
+    let mut reversed = bytes.to_vec();
+    reversed.reverse();
+    hex::encode(reversed)
 }
 
-pub const BITCOIN_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
\ No newline at end of file
+pub const BITCOIN_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
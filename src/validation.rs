@@ -0,0 +1,381 @@
+// proof of work checks for block headers
+
+use crate::network::Network;
+use crate::structures::BlockData;
+
+pub struct ProofOfWork {
+    pub valid: bool,
+    // false when the bits don't decode or decode to a target above the network's limit
+    pub target_in_range: bool,
+    pub difficulty: f64,
+}
+
+// Expands the compact "bits" encoding into a 256 bit big endian target. The top byte
+// is a base-256 exponent and the low 23 bits the mantissa, so target = mantissa *
+// 256^(exponent - 3). Returns None for negative, zero or overflowing targets, which
+// bitcoin core treats as invalid.
+pub fn bits_to_target(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    let negative = bits & 0x0080_0000 != 0;
+
+    if mantissa == 0 || negative {
+        return None;
+    }
+
+    let mut target = [0u8; 32];
+    let mantissa_bytes = &mantissa.to_be_bytes()[1..];
+
+    if exponent <= 3 {
+        let shifted = mantissa >> (8 * (3 - exponent));
+        if shifted == 0 {
+            return None;
+        }
+        target[28..].copy_from_slice(&shifted.to_be_bytes());
+        return Some(target);
+    }
+
+    // the mantissa's three bytes end up exponent bytes from the right hand side
+    for (i, byte) in mantissa_bytes.iter().enumerate() {
+        let position_from_right = exponent - 1 - i;
+        if position_from_right >= 32 {
+            if *byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[31 - position_from_right] = *byte;
+    }
+
+    Some(target)
+}
+
+// blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: u32 = 2016;
+
+// Whether a block at height can have new_bits when its parent has old_bits, on a network that
+// retargets normally. The bits stay put between adjustments and at one the target can move
+// at most a factor of four either way, capped at the network's limit. Like bitcoin core's
+// PermittedDifficultyTransition, both bounds are rounded to what bits can hold first.
+pub fn permitted_difficulty_transition(network: Network, height: u32, old_bits: u32, new_bits: u32) -> bool {
+    if !height.is_multiple_of(RETARGET_INTERVAL) {
+        return new_bits == old_bits;
+    }
+
+    let (old_target, new_target) = match (bits_to_target(old_bits), bits_to_target(new_bits)) {
+        (Some(old_target), Some(new_target)) => (u256_from_be_bytes(&old_target), u256_from_be_bytes(&new_target)),
+        _ => return false,
+    };
+    let pow_limit = u256_from_be_bytes(&network.pow_limit());
+
+    // four times the target only overflows for targets no network allows anyway
+    let largest = if u256_bits(&old_target) > 254 {
+        pow_limit
+    } else {
+        u256_shl(&old_target, 2).min(pow_limit)
+    };
+    let smallest = u256_shr(&old_target, 2).min(pow_limit);
+
+    (round_to_bits(&smallest)..=round_to_bits(&largest)).contains(&new_target)
+}
+
+// The target rounded down to what the compact encoding can hold, like bitcoin core's
+// GetCompact followed by SetCompact.
+fn round_to_bits(target: &U256) -> U256 {
+    let mut size = u256_bits(target).div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        (target[3] << (8 * (3 - size))) as u32
+    } else {
+        u256_shr(target, 8 * (size - 3))[3] as u32
+    };
+    // the top mantissa bit is the sign, move up a byte rather than set it
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    match bits_to_target(mantissa | (size << 24)) {
+        Some(rounded) => u256_from_be_bytes(&rounded),
+        None => [0; 4],
+    }
+}
+
+// the same calculation bitcoin core's GetDifficulty does, relative to the 0x1d00ffff target
+pub fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mantissa = bits & 0x00ff_ffff;
+    if mantissa == 0 {
+        return 0.0;
+    }
+
+    let mut difficulty = 0x0000ffff as f64 / mantissa as f64;
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+
+    difficulty
+}
+
+// Like bitcoin core's CheckProofOfWork, a target easier than the network allows fails
+// however low the hash is, so a header can't pick its own difficulty.
+pub fn check_proof_of_work(block: &BlockData, network: Network) -> ProofOfWork {
+    let target = bits_to_target(block.bits).filter(|target| *target <= network.pow_limit());
+    let valid = match target {
+        Some(target) => {
+            // the hash is a little endian number, flip it to compare against the target
+            let mut hash = block.block_hash.0;
+            hash.reverse();
+            hash <= target
+        }
+        None => false,
+    };
+
+    ProofOfWork {
+        valid,
+        target_in_range: target.is_some(),
+        difficulty: difficulty(block.bits),
+    }
+}
+
+// 256 bit unsigned numbers as four u64 limbs, most significant first. only the handful of
+// operations the work calculation needs. with that limb order the derived array ordering
+// is also numeric ordering.
+type U256 = [u64; 4];
+
+fn u256_from_be_bytes(bytes: &[u8; 32]) -> U256 {
+    let mut limbs = [0u64; 4];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(8)) {
+        *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+    }
+    limbs
+}
+
+fn u256_bits(value: &U256) -> u32 {
+    for (i, limb) in value.iter().enumerate() {
+        if *limb != 0 {
+            return (4 - i as u32) * 64 - limb.leading_zeros();
+        }
+    }
+    0
+}
+
+fn u256_add_one(value: &U256) -> U256 {
+    let mut result = *value;
+    for limb in result.iter_mut().rev() {
+        let (sum, carry) = limb.overflowing_add(1);
+        *limb = sum;
+        if !carry {
+            break;
+        }
+    }
+    result
+}
+
+fn u256_sub(a: &U256, b: &U256) -> U256 {
+    let mut result = [0u64; 4];
+    let mut borrow = false;
+    for i in (0..4).rev() {
+        let (difference, borrow_a) = a[i].overflowing_sub(b[i]);
+        let (difference, borrow_b) = difference.overflowing_sub(borrow as u64);
+        result[i] = difference;
+        borrow = borrow_a || borrow_b;
+    }
+    result
+}
+
+fn u256_shl(value: &U256, shift: u32) -> U256 {
+    let mut result = [0u64; 4];
+    let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+    for (i, limb) in result.iter_mut().enumerate() {
+        let source = i + limb_shift;
+        if source >= 4 {
+            break;
+        }
+        *limb = value[source] << bit_shift;
+        if bit_shift > 0 && source + 1 < 4 {
+            *limb |= value[source + 1] >> (64 - bit_shift);
+        }
+    }
+    result
+}
+
+fn u256_shr1(value: &U256) -> U256 {
+    let mut result = [0u64; 4];
+    for i in 0..4 {
+        result[i] = value[i] >> 1;
+        if i > 0 {
+            result[i] |= value[i - 1] << 63;
+        }
+    }
+    result
+}
+
+fn u256_shr(value: &U256, shift: u32) -> U256 {
+    let mut result = [0u64; 4];
+    let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+    for (i, limb) in result.iter_mut().enumerate().skip(limb_shift) {
+        let source = i - limb_shift;
+        *limb = value[source] >> bit_shift;
+        if bit_shift > 0 && source > 0 {
+            *limb |= value[source - 1] << (64 - bit_shift);
+        }
+    }
+    result
+}
+
+// plain shift and subtract long division
+fn u256_div(numerator: &U256, divisor: &U256) -> U256 {
+    let mut quotient = [0u64; 4];
+    if divisor == &[0; 4] || numerator < divisor {
+        return quotient;
+    }
+
+    let shift = u256_bits(numerator) - u256_bits(divisor);
+    let mut remainder = *numerator;
+    let mut divisor = u256_shl(divisor, shift);
+
+    for bit in (0..=shift).rev() {
+        if remainder >= divisor {
+            remainder = u256_sub(&remainder, &divisor);
+            quotient[3 - (bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        divisor = u256_shr1(&divisor);
+    }
+    quotient
+}
+
+// Expected number of hashes to find a block at this target, 2^256 / (target + 1). Worked
+// out as (~target / (target + 1)) + 1 like bitcoin core does, since 2^256 itself doesn't
+// fit. Any real block's work fits comfortably in a u128, anything bigger saturates.
+pub fn block_work(bits: u32) -> u128 {
+    let target = match bits_to_target(bits) {
+        Some(target) => u256_from_be_bytes(&target),
+        None => return 0,
+    };
+
+    let inverted = target.map(|limb| !limb);
+    let work = u256_add_one(&u256_div(&inverted, &u256_add_one(&target)));
+
+    if work[0] != 0 || work[1] != 0 {
+        u128::MAX
+    } else {
+        ((work[2] as u128) << 64) | work[3] as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(hex: &str) -> [u8; 32] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn genesis_bits_expand_to_the_mainnet_target() {
+        assert_eq!(
+            bits_to_target(0x1d00ffff),
+            Some(target("00000000ffff0000000000000000000000000000000000000000000000000000"))
+        );
+        assert_eq!(difficulty(0x1d00ffff), 1.0);
+    }
+
+    #[test]
+    fn small_exponents_shift_the_mantissa_down() {
+        assert_eq!(bits_to_target(0x03123456).map(|target| target[29..].to_vec()), Some(vec![0x12, 0x34, 0x56]));
+        assert_eq!(bits_to_target(0x01123456).map(|target| target[31]), Some(0x12));
+        assert_eq!(bits_to_target(0x01003456), None);
+    }
+
+    #[test]
+    fn negative_and_overflowing_bits_have_no_target() {
+        // sign bit set in the mantissa
+        assert_eq!(bits_to_target(0x04923456), None);
+        assert_eq!(bits_to_target(0x01fedcba), None);
+        // more than 256 bits
+        assert_eq!(bits_to_target(0xff123456), None);
+        assert_eq!(bits_to_target(0x21010000), None);
+        // the mantissa's top byte is zero, so this one still fits
+        assert!(bits_to_target(0x2100ffff).is_some());
+        assert_eq!(bits_to_target(0x1d000000), None);
+    }
+
+    #[test]
+    fn difficulty_matches_bitcoin_core() {
+        // block 100000
+        assert!((difficulty(0x1b04864c) - 14484.1623612254).abs() < 1e-6);
+        assert!((difficulty(0x207fffff) - 4.656542373906925e-10).abs() < 1e-20);
+    }
+
+    #[test]
+    fn bits_only_move_at_retargets() {
+        // the first difficulty increase, at block 32256
+        assert!(permitted_difficulty_transition(Network::Mainnet, 32256, 0x1d00ffff, 0x1d00d86a));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 32257, 0x1d00ffff, 0x1d00d86a));
+        assert!(permitted_difficulty_transition(Network::Mainnet, 32257, 0x1d00d86a, 0x1d00d86a));
+    }
+
+    #[test]
+    fn retargets_move_at_most_four_times() {
+        // a quarter of the target, exactly, and one step past it
+        assert!(permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864c, 0x1b012193));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864c, 0x1b012192));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864c, 0x1a04864c));
+
+        // four times the target, exactly, and one step past it
+        assert!(permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864c, 0x1b121930));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864c, 0x1b121931));
+    }
+
+    #[test]
+    fn retarget_bounds_are_rounded_to_bits() {
+        // a quarter of 0x1b04864d is 0x1219340 shifted, which bits round down to 0x1b012193
+        assert!(permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864d, 0x1b012193));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1b04864d, 0x1b012192));
+        // a quarter of the mainnet limit
+        assert!(permitted_difficulty_transition(Network::Mainnet, 2016, 0x1d00ffff, 0x1c3fffc0));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1d00ffff, 0x1c3fffbf));
+    }
+
+    #[test]
+    fn retargets_stop_at_the_pow_limit() {
+        assert!(permitted_difficulty_transition(Network::Mainnet, 2016, 0x1d00ffff, 0x1d00ffff));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1d00ffff, 0x1d03fffc));
+        assert!(permitted_difficulty_transition(Network::Mainnet, 2016, 0x1c7fffff, 0x1d00ffff));
+        assert!(!permitted_difficulty_transition(Network::Mainnet, 2016, 0x1c7fffff, 0x1d010000));
+    }
+
+    #[test]
+    fn genesis_meets_its_target() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Signet, Network::Regtest] {
+            let proof_of_work = check_proof_of_work(&network.genesis_header(), network);
+            assert!(proof_of_work.valid, "{} genesis", network.name());
+            assert!(proof_of_work.target_in_range);
+        }
+    }
+
+    #[test]
+    fn easy_bits_fail_on_mainnet() {
+        // regtest's genesis hash meets 0x207fffff, but mainnet doesn't allow that target
+        let header = Network::Regtest.genesis_header();
+        let proof_of_work = check_proof_of_work(&header, Network::Mainnet);
+        assert!(!proof_of_work.valid);
+        assert!(!proof_of_work.target_in_range);
+        assert!(check_proof_of_work(&header, Network::Regtest).valid);
+    }
+
+    #[test]
+    fn hash_above_target_fails() {
+        let mut header = Network::Mainnet.genesis_header();
+        header.nonce += 1;
+        header.calculate_hash();
+        let proof_of_work = check_proof_of_work(&header, Network::Mainnet);
+        assert!(!proof_of_work.valid);
+        assert!(proof_of_work.target_in_range);
+    }
+}
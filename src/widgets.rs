@@ -3,9 +3,18 @@
 
 use std::rc::Rc;
 
-use ratatui::{layout::{Constraint, Direction, Layout, Rect}, style::{Color, Modifier, Style}, widgets::{Block, Borders, List, Row, Table}};
+use ratatui::{layout::{Constraint, Direction, Layout, Rect}, style::{Color, Modifier, Style}, text::{Line, Span}, widgets::{Block, Borders, Cell, Paragraph, Row, Table, Wrap}};
 
-use crate::structures::{BlockData, Transaction};
+use crate::structures::{BlockData, BlockHash, ChainPosition, PeerInfo, PeerStats, Transaction};
+use crate::tui::{AppState, BlockDetails, ChainEvent, BLOCK_INFO_PAGES, COINBASE_PAGE, STATS_PAGE};
+use crate::address::{address, classify};
+use crate::coinbase::decode_coinbase;
+use crate::stats::FeeSource;
+use crate::network::{service_names, Network};
+use crate::script::asm;
+use crate::merkle::verify_merkle_root;
+use crate::utils::reversed_hex;
+use crate::validation::check_proof_of_work;
 
 
 pub fn container_layout(frame_size: Rect) -> Rc<[Rect]> {
@@ -20,6 +29,15 @@ pub fn container_layout(frame_size: Rect) -> Rc<[Rect]> {
 }
 
 
+// block info on the left, the peer we're talking to on the right
+pub fn top_layout(container_layout: Rect) -> Rc<[Rect]> {
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(65), Constraint::Percentage(35)])
+        .split(container_layout)
+}
+
+
 pub fn nested_layout(container_layout: Rect) -> Rc<[Rect]> {
     Layout::default()
         .direction(Direction::Horizontal)
@@ -28,58 +46,585 @@ pub fn nested_layout(container_layout: Rect) -> Rc<[Rect]> {
         .split(container_layout)
 }
 
-pub fn block_table(block_data: &Vec<BlockData>) -> Table {
+pub fn block_table<'a>(block_data: &'a [BlockData], app_state: &AppState) -> Table<'a> {
     let widths = [
+        Constraint::Length(3),  // for the proof of work badge
+        Constraint::Length(8),  // for height
         Constraint::Length(30), // for timestamp
-        Constraint::Length(70), // for block_hash
+        Constraint::Length(64), // for block_hash
     ];
 
-    let rows: Vec<Row> = block_data.into_iter().map(|block| {
-        // Convert each BlockData into a Vec<String>
+    let rows: Vec<Row> = block_data.iter().map(|block| {
+        // Convert each BlockData into a row of cells
         let columns = vec![
-            block.convert_date(),
-            "hassss".to_string()
+            pow_badge(check_proof_of_work(block, app_state.network).valid),
+            Cell::from(height_label(app_state, &block.block_hash)),
+            Cell::from(block.convert_date()),
+            Cell::from(block.block_hash.to_string())
         ];
         Row::new(columns)
     }).collect();
 
-    let headers = Row::new(vec!["Timestamp", "Block Hash"]);
+    let headers = Row::new(vec!["PoW", "Height", "Timestamp", "Block Hash"]);
+    let title = match (app_state.chain_height, app_state.stale_branches) {
+        (Some(height), 0) => format!("BLOCKS (tip {})", height),
+        (Some(height), stale) => format!("BLOCKS (tip {}, {} stale branches)", height, stale),
+        (None, _) => "BLOCKS".to_string(),
+    };
 
     Table::new(rows, widths)
-        .block(Block::default().title("BLOCKS").borders(Borders::ALL))
+        .block(Block::default().title(title).borders(Borders::ALL))
         .style(Style::default().fg(Color::White))
         .highlight_style(Style::default().add_modifier(Modifier::ITALIC).bg(Color::Blue))
         .highlight_symbol(">>").header(headers)
 }
 
-// TODO: Make it work for transactions instead
-pub fn transaction_table(row_data: &Vec<Transaction>) -> Table {
+// blocks that don't connect to our header chain yet have no height
+fn height_label(app_state: &AppState, hash: &BlockHash) -> String {
+    match app_state.chain_positions.get(hash) {
+        Some(position) => position.height.to_string(),
+        None if app_state.stale_blocks.contains(hash) => "stale".to_string(),
+        None => "?".to_string(),
+    }
+}
+
+fn chainwork_label(position: Option<&ChainPosition>) -> String {
+    match position {
+        Some(position) => format!("{:#x}", position.chainwork),
+        None => "unknown".to_string(),
+    }
+}
+
+fn pow_badge(valid: bool) -> Cell<'static> {
+    if valid {
+        Cell::from("✔").style(Style::default().fg(Color::Green))
+    } else {
+        Cell::from("✘").style(Style::default().fg(Color::Red))
+    }
+}
+
+pub fn block_info<'a>(block: Option<&'a BlockData>, app_state: &AppState) -> Paragraph<'a> {
+    let page = app_state.selected_panel;
+    let title = format!(
+        "Block Info: {} (shift+tab for {})",
+        BLOCK_INFO_PAGES[page],
+        BLOCK_INFO_PAGES[(page + 1) % BLOCK_INFO_PAGES.len()]
+    );
+    let block_widget = Block::default().title(title).borders(Borders::ALL);
+
+    let block = match block {
+        Some(block) => block,
+        None => return Paragraph::new("Waiting for blocks...").block(block_widget),
+    };
+    let details = match app_state.block_details.get(&block.block_hash) {
+        Some(details) => details,
+        None => return Paragraph::new("Working out details...").block(block_widget),
+    };
+    if page == COINBASE_PAGE {
+        return Paragraph::new(coinbase_lines(details)).block(block_widget);
+    }
+    if page == STATS_PAGE {
+        return Paragraph::new(stats_lines(block, app_state)).block(block_widget);
+    }
+
+    let proof_of_work = check_proof_of_work(block, app_state.network);
+    let pow_status = if proof_of_work.valid {
+        Span::styled("verified", Style::default().fg(Color::Green))
+    } else if !proof_of_work.target_in_range {
+        Span::styled("bits are outside the network's target range", Style::default().fg(Color::Red))
+    } else {
+        Span::styled("hash does not meet target", Style::default().fg(Color::Red))
+    };
+
+    let merkle_status = match details.merkle_valid {
+        Some(true) => Span::styled("matches transactions", Style::default().fg(Color::Green)),
+        Some(false) => Span::styled("does not match transactions", Style::default().fg(Color::Red)),
+        None => Span::raw("not checked, no transactions"),
+    };
+
+    let mut lines = vec![
+        Line::from(format!("Block Hash:     {}", block.block_hash)),
+        Line::from(format!("Height:         {}", height_label(app_state, &block.block_hash))),
+        Line::from(format!("Chainwork:      {}", chainwork_label(app_state.chain_positions.get(&block.block_hash)))),
+        Line::from(vec![Span::raw("Proof of Work:  "), pow_status]),
+        Line::from(format!("Difficulty:     {:.2}", proof_of_work.difficulty)),
+        Line::from(format!("Previous Block: {}", reversed_hex(&block.prev_block_hash))),
+        Line::from(format!("Merkle Root:    {}", reversed_hex(&block.merkle_root))),
+        Line::from(vec![Span::raw("Merkle Check:   "), merkle_status]),
+        Line::from(format!("Timestamp:      {}", block.convert_date())),
+        Line::from(format!("Version:        {:#010x}", block.version)),
+        Line::from(format!("Bits:           {:#010x}", block.bits)),
+        Line::from(format!("Nonce:          {}", block.nonce)),
+        Line::from(format!("Transactions:   {}", block.transactions.len())),
+    ];
+
+    if let Some(shown) = app_state.merkle_proof.as_ref().filter(|shown| shown.block_hash == block.block_hash) {
+        lines.push(Line::from(format!(
+            "Merkle Proof:   tx #{}, {} steps, {}",
+            shown.proof.index,
+            shown.proof.branch.len(),
+            if shown.proven { "leads to the merkle root" } else { "does not lead to the merkle root" }
+        )));
+        for sibling in &shown.proof.branch {
+            lines.push(Line::from(format!("                {}", reversed_hex(sibling))));
+        }
+    }
+
+    Paragraph::new(lines).block(block_widget)
+}
+
+// the coinbase, merkle check and transaction labels of a block about to be shown
+pub fn block_details(block: &BlockData, height: Option<u32>, network: Network) -> BlockDetails {
+    BlockDetails {
+        height,
+        coinbase: decode_coinbase(block, height, network),
+        merkle_valid: verify_merkle_root(block),
+        transaction_labels: block
+            .transactions
+            .iter()
+            .map(|transaction| (output_types(transaction), pays_to_label(transaction, network)))
+            .collect(),
+    }
+}
+
+fn coinbase_lines(details: &BlockDetails) -> Vec<Line<'static>> {
+    let height = details.height;
+    let coinbase = match &details.coinbase {
+        Some(coinbase) => coinbase,
+        None => return vec![Line::from("Only the header has been downloaded, the coinbase is in the full block")],
+    };
+
+    let bip34_height = match coinbase.bip34_height {
+        Some(encoded) if height.is_some_and(|height| height != encoded) => {
+            Span::styled(format!("{}, not the block's height", encoded), Style::default().fg(Color::Red))
+        }
+        Some(encoded) => Span::raw(encoded.to_string()),
+        None => Span::raw("not encoded"),
+    };
+    let unknown = || "unknown, the block's height isn't known".to_string();
+
+    let mut lines = vec![
+        Line::from(format!("Pool:           {}", coinbase.pool.unwrap_or("unknown"))),
+        Line::from(format!("Tag:            {}", if coinbase.tag.is_empty() { "none" } else { &coinbase.tag })),
+        Line::from(vec![Span::raw("BIP34 Height:   "), bip34_height]),
+        Line::from(format!("Reward:         {}", format_btc(coinbase.reward))),
+        Line::from(format!("  Subsidy:      {}", coinbase.subsidy.map_or_else(unknown, format_btc))),
+        Line::from(format!("  Fees:         {}", coinbase.fees().map_or_else(unknown, format_btc))),
+    ];
+
+    match &coinbase.witness_commitment {
+        Some(witness) => {
+            let status = match witness.valid {
+                Some(true) => Span::styled("matches witness data", Style::default().fg(Color::Green)),
+                Some(false) => Span::styled("does not match witness data", Style::default().fg(Color::Red)),
+                None => Span::raw("not checked, no reserved value"),
+            };
+            lines.push(Line::from(format!("Witness Commit: {}", hex::encode(witness.commitment))));
+            lines.push(Line::from(vec![Span::raw("                "), status]));
+        }
+        None => lines.push(Line::from("Witness Commit: none")),
+    }
+
+    lines
+}
+
+fn stats_lines(block: &BlockData, app_state: &AppState) -> Vec<Line<'static>> {
+    let stats = match app_state.block_stats.get(&block.block_hash) {
+        Some(Some(stats)) => stats,
+        Some(None) => return vec![Line::from("Only the header has been downloaded, stats need the full block")],
+        None => return vec![Line::from("Working out stats...")],
+    };
+
+    let fees = match &stats.fees {
+        Some((fees, FeeSource::Transactions)) => format!("{} (from every transaction's inputs)", format_btc(*fees)),
+        Some((fees, FeeSource::Coinbase)) => format!("{} (claimed by the coinbase)", format_btc(*fees)),
+        None => "unknown".to_string(),
+    };
+    let spending = stats.transactions - 1;
+    let feerates = match (stats.min_feerate(), stats.median_feerate(), stats.max_feerate()) {
+        (Some(min), Some(median), Some(max)) => format!(
+            "min {:.1}, median {:.1}, max {:.1} sat/vB ({} of {} transactions)",
+            min,
+            median,
+            max,
+            stats.feerates.len(),
+            spending
+        ),
+        _ if spending == 0 => "none, the coinbase is the only transaction".to_string(),
+        _ => "unknown, the outputs spent aren't in any loaded block".to_string(),
+    };
+
+    vec![
+        Line::from(format!("Transactions:   {}", stats.transactions)),
+        Line::from(format!("Size:           {} bytes, {} stripped", stats.size, stats.stripped_size)),
+        Line::from(format!("Weight:         {} weight units, {} vbytes", stats.weight, stats.weight.div_ceil(4))),
+        Line::from(format!("Total Out:      {} (excluding the coinbase)", format_btc(stats.output_value))),
+        Line::from(format!("Fees:           {}", fees)),
+        Line::from(format!("Feerates:       {}", feerates)),
+        Line::from(format!("Segwit:         {:.1}% of transactions", stats.segwit_share)),
+        Line::from(format!("Taproot:        {:.1}% of transactions spend taproot inputs", stats.taproot_share)),
+    ]
+}
+
+// takes the peer panel's place when blocks are coming from core's files instead
+pub fn offline_info(dir: &str) -> Paragraph<'_> {
+    let lines = vec![
+        Line::from("Offline, no peers connected"),
+        Line::from(format!("Reading blocks from {}", dir)),
+    ];
+    Paragraph::new(lines).block(Block::default().title("Source").borders(Borders::ALL))
+}
+
+// peer is the one being shown, position and count say where it sits among the connected peers
+pub fn peer_info(peer: Option<&(PeerInfo, PeerStats)>, position: usize, count: usize) -> Paragraph<'_> {
+    let (info, stats) = match peer {
+        Some(peer) => peer,
+        None => {
+            return Paragraph::new("Connecting...")
+                .block(Block::default().title("Peer").borders(Borders::ALL))
+        }
+    };
+
+    let title = format!("Peer {} of {} (p for next)", position + 1, count);
+    let services = service_names(info.services);
+    let ping = match stats.ping {
+        Some(ping) => format!("{} ms", ping.as_millis()),
+        None => "waiting for pong".to_string(),
+    };
+
+    let lines = vec![
+        Line::from(format!("Address:      {}", info.address)),
+        Line::from(format!("User Agent:   {}", info.user_agent)),
+        Line::from(format!("Protocol:     {} (using {})", info.version, info.negotiated_version)),
+        Line::from(format!("Services:     {:#x}", info.services)),
+        Line::from(format!("              {}", if services.is_empty() { "none".to_string() } else { services.join(", ") })),
+        Line::from(format!("Start Height: {}", info.start_height)),
+        Line::from(format!("Relay:        {}", if info.relay { "yes" } else { "no" })),
+        Line::from(format!("Ping:         {}", ping)),
+        Line::from(format!("Bytes In:     {}", format_bytes(stats.bytes_in))),
+        Line::from(format!("Bytes Out:    {}", format_bytes(stats.bytes_out))),
+    ];
+
+    Paragraph::new(lines)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .wrap(Wrap { trim: false })
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+// the newest reorgs, rejected headers and storage failures, as many as fit
+pub fn chain_events(events: &[ChainEvent]) -> Paragraph<'_> {
+    let block = Block::default().title("Chain Events").borders(Borders::ALL);
+    if events.is_empty() {
+        return Paragraph::new("No reorgs or rejected headers seen").block(block);
+    }
+
+    let lines: Vec<Line> = events
+        .iter()
+        .rev()
+        .map(|event| match event {
+            ChainEvent::Reorg(reorg) => Line::from(format!(
+                "Reorg at height {}: {} block(s) replaced, tip {} is now at height {} (was {})",
+                reorg.fork_height,
+                reorg.disconnected.len(),
+                reorg.new_tip,
+                reorg.new_height,
+                reorg.old_tip
+            )),
+            ChainEvent::RejectedHeaders { peer, reason } => Line::from(Span::styled(
+                format!("Rejected headers from {}, disconnected: {}", peer, reason),
+                Style::default().fg(Color::Red),
+            )),
+            ChainEvent::StorageFailed(reason) => Line::from(Span::styled(
+                format!("Could not save to the block store: {}", reason),
+                Style::default().fg(Color::Red),
+            )),
+        })
+        .collect();
+
+    Paragraph::new(lines).block(block)
+}
+
+// a row per transaction, with what its outputs are and who they pay
+pub fn transaction_table<'a>(row_data: &'a [Transaction], details: Option<&BlockDetails>) -> Table<'a> {
     let widths = [
-        Constraint::Length(20),
-        Constraint::Length(20),
-        Constraint::Length(20),
-        Constraint::Length(20),
-        Constraint::Length(20),
+        Constraint::Length(64),
+        Constraint::Length(6),
+        Constraint::Length(7),
+        Constraint::Length(18),
+        Constraint::Length(24),
+        Constraint::Length(74),
     ];
 
-    let rows: Vec<Row> = row_data.iter().map(|transaction| {
+    let rows: Vec<Row> = row_data.iter().enumerate().map(|(index, transaction)| {
         let total_value: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        let (output_types, pays_to) = details
+            .and_then(|details| details.transaction_labels.get(index))
+            .cloned()
+            .unwrap_or_default();
         Row::new(vec![
-            transaction.version.to_string(),
+            reversed_hex(&transaction.txid),
             transaction.inputs.len().to_string(),
             transaction.outputs.len().to_string(),
-            total_value.to_string(),
-            transaction.locktime.to_string(),
+            format_btc(total_value),
+            output_types,
+            pays_to,
         ])
     }).collect();
 
     Table::new(rows, widths)
         .header(
-            Row::new(vec!["Version", "Inputs", "Outputs", "Total Value", "Locktime"])
+            Row::new(vec!["Txid", "Ins", "Outs", "Value", "Output Types", "Pays To"])
                 .style(Style::new().add_modifier(Modifier::BOLD))
                 .bottom_margin(1),
         )
-        .block(Block::default().title("Transactions"))
+        .block(Block::default().title("Transactions (Enter for details, / to search addresses)"))
         .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
         .highlight_symbol(">>")
-}
\ No newline at end of file
+}
+
+// each kind of output once, in the order they first appear
+fn output_types(transaction: &Transaction) -> String {
+    let mut names: Vec<String> = Vec::new();
+    for output in &transaction.outputs {
+        let name = classify(&output.script_pub_key).name();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.join(", ")
+}
+
+// the first address paid, and how many more there are
+fn pays_to_label(transaction: &Transaction, network: Network) -> String {
+    let mut addresses = transaction.outputs.iter().filter_map(|output| address(&output.script_pub_key, network));
+    match addresses.next() {
+        Some(first) => match addresses.count() {
+            0 => first,
+            more => format!("{} (+{} more)", first, more),
+        },
+        None => "no address".to_string(),
+    }
+}
+
+pub fn search_input(query: &str) -> Paragraph<'_> {
+    Paragraph::new(format!("{}_", query))
+        .block(Block::default().title("Address (Enter to search, Esc to go back)").borders(Borders::ALL))
+}
+
+// the outputs found by the last search, Enter opens the transaction
+pub fn search_results(app_state: &AppState) -> Table<'static> {
+    let widths = [
+        Constraint::Length(8),
+        Constraint::Length(64),
+        Constraint::Length(6),
+        Constraint::Length(18),
+    ];
+
+    let rows: Vec<Row> = app_state.search_results.iter().map(|found| {
+        Row::new(vec![
+            height_label(app_state, &found.block_hash),
+            reversed_hex(&found.txid),
+            found.output.to_string(),
+            format_btc(found.value),
+        ])
+    }).collect();
+
+    // only blocks that have been downloaded in full can be searched
+    let searchable = app_state.block_list.iter().filter(|block| !block.transactions.is_empty()).count();
+    let title = match &app_state.searched_for {
+        None => format!("Searches the outputs of the {} loaded blocks", searchable),
+        Some(query) if app_state.search_results.is_empty() => {
+            format!("No outputs in the {} loaded blocks pay {}", searchable, query)
+        }
+        Some(query) => format!("{} outputs pay {}", app_state.search_results.len(), query),
+    };
+
+    Table::new(rows, widths)
+        .header(
+            Row::new(vec!["Height", "Txid", "Output", "Value"])
+                .style(Style::new().add_modifier(Modifier::BOLD))
+                .bottom_margin(1),
+        )
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .highlight_symbol(">>")
+}
+
+// Every field of one transaction, a line per Line. Scripts and witness items are cut into
+// rows that fit width, rather than left to the paragraph to wrap, so the line count is the
+// number of rows on screen and scrolling can stop at the last one.
+pub fn transaction_detail(transaction: &Transaction, network: Network, width: usize) -> Vec<Line<'static>> {
+    let mut lines = vec![
+        Line::from(format!("Txid:     {}", reversed_hex(&transaction.txid))),
+        Line::from(format!("Wtxid:    {}", reversed_hex(&transaction.wtxid))),
+        Line::from(format!("Version:  {}", transaction.version)),
+        Line::from(format!("Locktime: {}", transaction.locktime)),
+        Line::from(format!(
+            "Size:     {} bytes, {} vbytes, {} weight units",
+            transaction.size,
+            transaction.vsize(),
+            transaction.weight()
+        )),
+        Line::from(format!(
+            "Value:    {} out",
+            format_btc(transaction.outputs.iter().map(|output| output.value).sum())
+        )),
+        Line::from(""),
+        section_title(format!("Inputs ({})", transaction.inputs.len())),
+    ];
+
+    for (index, input) in transaction.inputs.iter().enumerate() {
+        let spends = if input.is_coinbase() {
+            "coinbase".to_string()
+        } else {
+            format!("{}:{}", reversed_hex(&input.prev_tx_hash), input.prev_output_index)
+        };
+        lines.push(Line::from(format!("#{:<4}{}", index, spends)));
+        lines.push(Line::from(format!("{}{:#010x}", field("Sequence"), input.sequence)));
+        lines.extend(asm_rows(&field("scriptSig"), &input.script_sig, width));
+        if !input.script_sig.is_empty() {
+            lines.extend(hex_rows(&field("scriptSig hex"), &input.script_sig, width));
+        }
+        for (item_index, item) in input.witness.iter().enumerate() {
+            lines.extend(hex_rows(&field(&format!("Witness {}", item_index)), item, width));
+        }
+    }
+
+    lines.push(Line::from(""));
+    lines.push(section_title(format!("Outputs ({})", transaction.outputs.len())));
+    for (index, output) in transaction.outputs.iter().enumerate() {
+        lines.push(Line::from(format!("#{:<4}{}", index, format_btc(output.value))));
+        lines.push(Line::from(format!("{}{}", field("Type"), classify(&output.script_pub_key).name())));
+        if let Some(address) = address(&output.script_pub_key, network) {
+            lines.push(Line::from(format!("{}{}", field("Address"), address)));
+        }
+        lines.extend(asm_rows(&field("scriptPubKey"), &output.script_pub_key, width));
+        lines.extend(hex_rows(&field("scriptPubKey hex"), &output.script_pub_key, width));
+    }
+
+    lines
+}
+
+fn section_title(title: String) -> Line<'static> {
+    Line::from(Span::styled(title, Style::default().add_modifier(Modifier::BOLD)))
+}
+
+// an input or output's field name, indented under its number and padded so the values line up
+fn field(name: &str) -> String {
+    format!("     {:<18}", format!("{}:", name))
+}
+
+// bytes as hex after label, carrying on under it when there's more than fits on a row
+fn hex_rows(label: &str, bytes: &[u8], width: usize) -> Vec<Line<'static>> {
+    if bytes.is_empty() {
+        return vec![Line::from(format!("{}(empty)", label))];
+    }
+
+    // whole bytes per row, and at least one whatever the width
+    let per_row = (width.saturating_sub(label.len()) / 2).max(1) * 2;
+    let hex = hex::encode(bytes);
+    let rows = hex.as_bytes().chunks(per_row).map(|chunk| String::from_utf8_lossy(chunk).into_owned());
+    labelled_rows(label, rows)
+}
+
+// the script's asm after label, wrapped between opcodes and pushes. pushes too long for a
+// row of their own are cut up
+fn asm_rows(label: &str, script: &[u8], width: usize) -> Vec<Line<'static>> {
+    if script.is_empty() {
+        return vec![Line::from(format!("{}(empty)", label))];
+    }
+
+    let room = width.saturating_sub(label.len()).max(1);
+    let asm = asm(script);
+    let mut rows = vec![String::new()];
+    for word in asm.split(' ') {
+        for piece in word.as_bytes().chunks(room) {
+            let piece = String::from_utf8_lossy(piece);
+            let row = rows.last_mut().unwrap();
+            if row.is_empty() {
+                row.push_str(&piece);
+            } else if row.len() + 1 + piece.len() <= room {
+                row.push(' ');
+                row.push_str(&piece);
+            } else {
+                rows.push(piece.into_owned());
+            }
+        }
+    }
+    labelled_rows(label, rows.into_iter())
+}
+
+fn labelled_rows(label: &str, rows: impl Iterator<Item = String>) -> Vec<Line<'static>> {
+    let indent = " ".repeat(label.len());
+    rows.enumerate()
+        .map(|(row, text)| {
+            let prefix = if row == 0 { label } else { &indent };
+            Line::from(format!("{}{}", prefix, text))
+        })
+        .collect()
+}
+
+pub fn format_btc(satoshis: u64) -> String {
+    format!("{}.{:08} BTC", satoshis / 100_000_000, satoshis % 100_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{parse_transaction, tests::SEGWIT_TX};
+    use crate::utils::ByteReader;
+
+    fn detail(width: usize) -> Vec<String> {
+        let transaction = parse_transaction(&mut ByteReader::new(&hex::decode(SEGWIT_TX).unwrap())).unwrap();
+        transaction_detail(&transaction, Network::Mainnet, width)
+            .iter()
+            .map(|line| line.spans.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn detail_opens_with_the_summary() {
+        let lines = detail(200);
+        assert_eq!(lines[0], "Txid:     e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609");
+        assert_eq!(lines[1], "Wtxid:    c36c38370907df2324d9ce9d149d191192f338b37665a82e78e76a12c909b762");
+        assert_eq!(lines[2], "Version:  1");
+        assert_eq!(lines[3], "Locktime: 17");
+        assert_eq!(lines[4], "Size:     343 bytes, 261 vbytes, 1042 weight units");
+        assert_eq!(lines[5], "Value:    3.35790000 BTC out");
+        assert_eq!(lines[7], "Inputs (2)");
+    }
+
+    #[test]
+    fn detail_lists_witnesses_and_addresses() {
+        let lines = detail(200);
+        let line = |text: &str| lines.iter().position(|line| line.trim_end() == text);
+
+        // the legacy input has no witness lines, the segwit one a signature and a key
+        let first = line("#0   9f96ade4b41d5433f4eda31e1738ec2b36f6e7d1420d94a6af99801a88f7f7ff:0").unwrap();
+        let second = line("#1   8ac60eb9575db5b2d987e29f301b5b819ea83a5c6579d282d189cc04b8e151ef:1").unwrap();
+        assert!(!lines[first..second].iter().any(|line| line.contains("Witness")));
+        assert!(lines[second..].iter().any(|line| line.starts_with("     Witness 0:        304402")));
+        assert!(lines[second..].iter().any(|line| line.starts_with("     Witness 1:        025476c2")));
+
+        assert!(line("Outputs (2)").is_some());
+        assert!(line("#0   1.12340000 BTC").is_some());
+        assert!(line("     Address:          1Cu32FVupVCgHkMMRJdYJugxwo2Aprgk7H").is_some());
+        assert!(line("#1   2.23450000 BTC").is_some());
+        assert!(line("     Address:          16TZ8J6Q5iZKBWizWzFAYnrsaox5Z5aBRV").is_some());
+    }
+
+    #[test]
+    fn long_hex_wraps_under_its_label() {
+        let wide = detail(200);
+        let narrow = detail(60);
+        assert!(narrow.len() > wide.len());
+        assert!(narrow.iter().all(|line| line.len() <= 80));
+    }
+}